@@ -0,0 +1,155 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use usiagent::event::GameEndState;
+use usiagent::rule::*;
+
+use csaparser::CsaParser;
+use csaparser::CsaFileStream;
+use csaparser::CsaData;
+
+use error::ApplicationError;
+use learning::{csa_moves, is_learning_target};
+use records::{RecordFormat, RecordWriter, TeacherPosition};
+
+/// Converts the csa files of the teacher games into the fixed size records of learning_batch.
+///
+/// The csa files in the training and tests directories of kifudir are written with the same names into those of the output directory,
+/// so the output directory can be passed to --kifudir as it is.
+/// The games are filtered in the same way as learning_from_csa, and every position of a game is labelled with the result of the game
+/// from its side to move. The records have no evaluation, and the best move is the move played in the game,
+/// so learn_lambda must be 0 when training on them, or the targets are pulled toward the evaluation 0.
+pub struct CsaConverter {
+	lowerrate:f64,
+	format:RecordFormat,
+}
+impl CsaConverter {
+	pub fn new(lowerrate:f64,format:RecordFormat) -> CsaConverter {
+		CsaConverter {
+			lowerrate:lowerrate,
+			format:format,
+		}
+	}
+
+	pub fn run(&self,kifudir:&str,outdir:&str) -> Result<(),ApplicationError> {
+		let mut dirs = Vec::new();
+
+		for dir in ["training","tests"].iter() {
+			let src = Path::new(kifudir).join(dir);
+
+			if !src.exists() {
+				continue;
+			}
+
+			let dst = Path::new(outdir).join(dir);
+			let files = self.files(&src,&dst)?;
+
+			dirs.push((dst,files));
+		}
+
+		// Every output file is checked before writing anything, so that a failed run leaves no partial output.
+		for (_,files) in dirs.iter() {
+			for (_,out) in files.iter() {
+				if out.exists() {
+					return Err(ApplicationError::StartupError(format!(
+						"出力先のファイル{}は既に存在します。",out.display()
+					)));
+				}
+			}
+		}
+
+		let mut total = (0,0,0);
+
+		for (dst,files) in dirs {
+			fs::create_dir_all(&dst)?;
+
+			let (games,converted,positions) = self.convert_files(files)?;
+
+			total = (total.0 + games,total.1 + converted,total.2 + positions);
+		}
+
+		print!("{}局中{}局の棋譜から{}局面を書き出しました。\n",total.0,total.1,total.2);
+		print!("書き出した局面は評価値を持たないため、learn_lambdaを0にして学習してください。\n");
+
+		Ok(())
+	}
+
+	/// Pairs of the csa files in src and the output files in dst, in the order of the names.
+	fn files(&self,src:&Path,dst:&Path) -> Result<Vec<(PathBuf,PathBuf)>,ApplicationError> {
+		let mut paths = fs::read_dir(src)?.map(|e| e.map(|e| e.path())).collect::<Result<Vec<_>,_>>()?;
+
+		paths.sort();
+
+		Ok(paths.into_iter().filter(|path| {
+			path.extension().map(|e| e == "csa").unwrap_or(false)
+		}).map(|path| {
+			let out = dst.join(path.file_stem().unwrap_or_default()).with_extension(self.format.ext());
+
+			(path,out)
+		}).collect())
+	}
+
+	fn convert_files(&self,files:Vec<(PathBuf,PathBuf)>) -> Result<(usize,usize,usize),ApplicationError> {
+		let mut total = (0,0,0);
+
+		for (path,out) in files {
+			print!("{} -> {}\n",path.display(),out.display());
+
+			let parsed:Vec<CsaData> = CsaParser::new(CsaFileStream::new(&path)?).parse()?;
+
+			let mut writer = RecordWriter::new(&out,self.format)?;
+
+			for p in parsed.iter() {
+				total.0 += 1;
+
+				if !is_learning_target(p,self.lowerrate) {
+					continue;
+				}
+
+				total.1 += 1;
+
+				for position in CsaConverter::positions(p) {
+					writer.write(&position)?;
+				}
+			}
+
+			writer.flush()?;
+
+			total.2 += writer.count();
+		}
+
+		Ok(total)
+	}
+
+	/// Positions of the game including the last one, where the side to move is the loser of the game.
+	fn positions(p:&CsaData) -> Vec<TeacherPosition> {
+		let (last_teban,_,_,positions) = Rule::apply_moves_with_callback(State::new(p.initial_position.clone()),
+																		 p.teban_at_start,
+																		 p.initial_mochigoma.clone(),
+																		 &csa_moves(p),
+																		 Vec::new(),
+																		 |teban,banmen,mc,m,_,positions| {
+			let mut positions = positions;
+
+			positions.push(TeacherPosition {
+				teban:teban,
+				banmen:banmen.clone(),
+				mc:mc.clone(),
+				eval:0,
+				best_move:m.map(|m| m.to_move()),
+				ply:positions.len() as u16 + 1,
+				result:GameEndState::Lose,
+			});
+
+			positions
+		});
+
+		positions.into_iter().map(|mut position| {
+			if position.teban != last_teban {
+				position.result = GameEndState::Win;
+			}
+
+			position
+		}).collect()
+	}
+}
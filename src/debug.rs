@@ -0,0 +1,285 @@
+use std::io;
+use std::sync::{Arc, Mutex, Condvar};
+use std::time::Duration;
+
+use usiagent::shogi::*;
+use usiagent::rule::*;
+use usiagent::protocol::*;
+use usiagent::input::USIInputReader;
+use usiagent::output::{USIOutputWriter, USIStdErrorWriter};
+
+
+use error::{ApplicationError, CommonError};
+use nn::{Intelligence, Network, win_rate};
+use perft::Perft;
+
+const POSITION_WAIT_TIMEOUT:u64 = 1000;
+const EVALUTOR_WAIT_TIMEOUT:u64 = 10000;
+
+const KOMA_NAMES:[&'static str; 14] = [
+	"歩","香","桂","銀","金","角","飛","玉","と","杏","圭","全","馬","龍"
+];
+
+struct DebugState<NN>
+	where NN: Network {
+	kyokumen:Option<Kyokumen>,
+	mhash:u64,
+	shash:u64,
+	evalutor:Option<Arc<Intelligence<NN>>>,
+	position_count:u64,
+}
+pub struct DebugInfo<NN>
+	where NN: Network {
+	state:Mutex<DebugState<NN>>,
+	updated:Condvar,
+}
+impl<NN> DebugInfo<NN>
+	where NN: Network {
+	pub fn new() -> DebugInfo<NN> {
+		DebugInfo {
+			state:Mutex::new(DebugState {
+				kyokumen:None,
+				mhash:0,
+				shash:0,
+				evalutor:None,
+				position_count:0,
+			}),
+			updated:Condvar::new(),
+		}
+	}
+
+	pub fn set_evalutor(&self,evalutor:Arc<Intelligence<NN>>) {
+		if let Ok(mut state) = self.state.lock() {
+			state.evalutor = Some(evalutor);
+		}
+
+		self.updated.notify_all();
+	}
+
+	pub fn set_position(&self,kyokumen:Kyokumen,mhash:u64,shash:u64) {
+		if let Ok(mut state) = self.state.lock() {
+			state.kyokumen = Some(kyokumen);
+			state.mhash = mhash;
+			state.shash = shash;
+			state.position_count += 1;
+		}
+
+		self.updated.notify_all();
+	}
+
+	pub fn clear_position(&self) {
+		if let Ok(mut state) = self.state.lock() {
+			state.kyokumen = None;
+		}
+	}
+
+	fn snapshot(&self,position_count:u64,wait_evalutor:bool)
+		-> Result<(Option<Kyokumen>,u64,u64,Option<Arc<Intelligence<NN>>>),ApplicationError> {
+		let state = self.state.lock().map_err(|_| ApplicationError::LogicError(String::from(
+			"デバッグ情報の排他ロックの獲得に失敗しました。"
+		)))?;
+
+		// The commands are handled on the player thread,
+		// so wait a little for the ones sent just before to be applied.
+		let (state,_) = self.updated.wait_timeout_while(state,
+														Duration::from_millis(POSITION_WAIT_TIMEOUT),
+														|state| state.position_count < position_count)
+			.map_err(|_| ApplicationError::LogicError(String::from(
+				"デバッグ情報の排他ロックの獲得に失敗しました。"
+			)))?;
+
+		let (state,_) = self.updated.wait_timeout_while(state,
+														Duration::from_millis(EVALUTOR_WAIT_TIMEOUT),
+														|state| wait_evalutor && state.evalutor.is_none())
+			.map_err(|_| ApplicationError::LogicError(String::from(
+				"デバッグ情報の排他ロックの獲得に失敗しました。"
+			)))?;
+
+		Ok((state.kyokumen.clone(),state.mhash,state.shash,state.evalutor.clone()))
+	}
+}
+pub struct DebugInputReader<R,W,NN>
+	where R: USIInputReader, W: USIOutputWriter,
+		  NN: Network {
+	reader:R,
+	writer:W,
+	debug_info:Arc<DebugInfo<NN>>,
+	position_parser:PositionParser,
+	position_count:u64,
+	ready_requested:bool,
+}
+impl<R,W,NN> DebugInputReader<R,W,NN>
+	where R: USIInputReader, W: USIOutputWriter,
+		  NN: Network {
+	pub fn new(reader:R,writer:W,debug_info:Arc<DebugInfo<NN>>) -> DebugInputReader<R,W,NN> {
+		DebugInputReader {
+			reader:reader,
+			writer:writer,
+			debug_info:debug_info,
+			position_parser:PositionParser::new(),
+			position_count:0,
+			ready_requested:false,
+		}
+	}
+
+	fn display(&self) -> Result<Vec<String>,ApplicationError> {
+		let (kyokumen,mhash,shash,_) = self.debug_info.snapshot(self.position_count,false)?;
+
+		let kyokumen = match kyokumen {
+			Some(kyokumen) => kyokumen,
+			None => {
+				return Ok(vec![String::from("局面が設定されていません。")]);
+			}
+		};
+
+		let mut lines = Vec::new();
+
+		lines.push(String::from("  ９ ８ ７ ６ ５ ４ ３ ２ １"));
+		lines.push(String::from("+---------------------------+"));
+
+		let kansuji = ["一","二","三","四","五","六","七","八","九"];
+
+		for (y,row) in kyokumen.state.get_banmen().0.iter().enumerate() {
+			let mut line = String::from("|");
+
+			for &kind in row.iter() {
+				line.push_str(&DebugInputReader::<R,W,NN>::koma_to_string(kind));
+			}
+
+			line.push_str(&format!("|{}",kansuji[y]));
+
+			lines.push(line);
+		}
+
+		lines.push(String::from("+---------------------------+"));
+
+		let (ms,mg) = match kyokumen.mc {
+			MochigomaCollections::Pair(ref ms,ref mg) => {
+				(MochigomaCollections::Pair(ms.clone(),Mochigoma::new()).to_sfen().map_err(CommonError::from)?,
+				 MochigomaCollections::Pair(Mochigoma::new(),mg.clone()).to_sfen().map_err(CommonError::from)?)
+			},
+			MochigomaCollections::Empty => {
+				(String::from("-"),String::from("-"))
+			}
+		};
+
+		lines.push(format!("先手持駒: {}",ms));
+		lines.push(format!("後手持駒: {}",mg));
+		lines.push(format!("手番: {}",match kyokumen.teban {
+			Teban::Sente => "先手",
+			Teban::Gote => "後手"
+		}));
+		lines.push(format!("sfen: {}",(kyokumen.teban,kyokumen.state.get_banmen().clone(),kyokumen.mc.clone(),Vec::new()).to_sfen()?));
+		lines.push(format!("hash: {:016x} {:016x}",mhash,shash));
+
+		Ok(lines)
+	}
+
+	fn koma_to_string(kind:KomaKind) -> String {
+		let k = kind as usize;
+
+		if kind == KomaKind::Blank {
+			String::from(" ・")
+		} else if k < KomaKind::GFu as usize {
+			format!(" {}",KOMA_NAMES[k])
+		} else {
+			format!("v{}",KOMA_NAMES[k - KomaKind::GFu as usize])
+		}
+	}
+
+	fn evalute(&self) -> Result<Vec<String>,ApplicationError> {
+		let (kyokumen,_,_,evalutor) = self.debug_info.snapshot(self.position_count,self.ready_requested)?;
+
+		let kyokumen = match kyokumen {
+			Some(kyokumen) => kyokumen,
+			None => {
+				return Ok(vec![String::from("局面が設定されていません。")]);
+			}
+		};
+
+		let evalutor = match evalutor {
+			Some(evalutor) => evalutor,
+			None => {
+				return Ok(vec![String::from("評価関数が読み込まれていません。isreadyを送信してください。")]);
+			}
+		};
+
+		let (teban,state,mc) = (kyokumen.teban,&kyokumen.state,&kyokumen.mc);
+
+		let mut lines = Vec::new();
+
+		for (i,(o,w)) in evalutor.evalute_by_networks(true,teban,state.get_banmen(),mc)?.into_iter().zip(evalutor.weights().iter()).enumerate() {
+			lines.push(format!("nn{}: {} (weight {})",i,o,w));
+		}
+		let s = evalutor.evalute(true,teban,state.get_banmen(),mc)?;
+
+		lines.push(format!("evalute: {} (winrate {:.1}%)",s,win_rate(s,evalutor.eval_scale()) * 100.));
+
+		let accumulator = evalutor.make_accumulator(true,teban,state.get_banmen(),mc)?;
+		let mut dst = accumulator.clone();
+
+		let mut scores = Vec::new();
+
+		for m in Perft::new(true).moves(teban,state,mc) {
+			let m = m.to_move();
+			let s = evalutor.evalute_by_diff(&accumulator,true,teban,state.get_banmen(),mc,&m,teban,&mut dst)?;
+
+			scores.push((m,s));
+		}
+
+		scores.sort_by(|a,b| b.1.cmp(&a.1));
+
+		for (m,s) in scores {
+			lines.push(format!("{}: {}",m.to_sfen()?,s));
+		}
+
+		Ok(lines)
+	}
+
+	fn write(&self,r:Result<Vec<String>,ApplicationError>) {
+		match r {
+			Ok(lines) => {
+				let _ = self.writer.write(&lines);
+			},
+			Err(ref e) => {
+				let _ = USIStdErrorWriter::write(&format!("{}\n",e));
+			}
+		}
+	}
+}
+impl<R,W,NN> USIInputReader for DebugInputReader<R,W,NN>
+	where R: USIInputReader, W: USIOutputWriter,
+		  NN: Network {
+	fn read(&mut self) -> io::Result<Option<String>> {
+		loop {
+			match self.reader.read()? {
+				Some(line) => {
+					match line.trim() {
+						"d" => {
+							let r = self.display();
+							self.write(r);
+						},
+						"eval" => {
+							let r = self.evalute();
+							self.write(r);
+						},
+						l => {
+							let f = l.split(" ").collect::<Vec<&str>>();
+
+							if f[0] == "position" && f.len() > 1 && self.position_parser.parse(&f[1..]).is_ok() {
+								self.position_count += 1;
+							} else if f[0] == "isready" {
+								self.ready_requested = true;
+							}
+
+							return Ok(Some(line));
+						}
+					}
+				},
+				None => {
+					return Ok(None);
+				}
+			}
+		}
+	}
+}
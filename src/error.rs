@@ -14,6 +14,7 @@ use usiagent::error::SelfMatchRunningError;
 use usiagent::error::TypeConvertError;
 use usiagent::error::SfenStringConvertError;
 use usiagent::error::KifuWriteError;
+use usiagent::error::ToMoveStringConvertError;
 use csaparser::error::CsaParserError;
 use nncombinator::error::{ConfigReadError, CudaError, DeviceError, EvaluateError, IndexOutBoundError, PersistenceError, TrainingError};
 
@@ -124,6 +125,8 @@ pub enum ApplicationError {
 	DeviceError(DeviceError),
 	PersistenceError(PersistenceError),
 	CudaError(CudaError),
+	ToMoveStringConvertError(ToMoveStringConvertError),
+	CommonError(CommonError),
 }
 impl fmt::Display for ApplicationError {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -147,6 +150,8 @@ impl fmt::Display for ApplicationError {
 			ApplicationError::DeviceError(ref e) => write!(f,"{}",e),
 			ApplicationError::PersistenceError(ref e) => write!(f,"{}",e),
 			ApplicationError::CudaError(ref e) => write!(f, "An error occurred in the process of cuda. ({})",e),
+			ApplicationError::ToMoveStringConvertError(ref e) => write!(f, "{}",e),
+			ApplicationError::CommonError(ref e) => write!(f, "{}",e),
 		}
 	}
 }
@@ -172,6 +177,8 @@ impl error::Error for ApplicationError {
 			ApplicationError::DeviceError(_) => "An error occurred during device initialization.",
 			ApplicationError::PersistenceError(_) => "An error occurred when saving model information.",
 			ApplicationError::CudaError(_) => "An error occurred in the process of cuda.",
+			ApplicationError::ToMoveStringConvertError(_) => "An error occurred during conversion to move string.",
+			ApplicationError::CommonError(_) => "An error occurred in the player.",
 		}
 	}
 
@@ -195,7 +202,9 @@ impl error::Error for ApplicationError {
 			ApplicationError::EvaluateError(ref e) => Some(e),
 			ApplicationError::DeviceError(ref e) => Some(e),
 			ApplicationError::PersistenceError(ref e) => Some(e),
-			ApplicationError::CudaError(_) => None
+			ApplicationError::CudaError(_) => None,
+			ApplicationError::ToMoveStringConvertError(ref e) => Some(e),
+			ApplicationError::CommonError(ref e) => Some(e)
 		}
 	}
 }
@@ -276,3 +285,13 @@ impl From<CudaError> for ApplicationError {
 		ApplicationError::CudaError(err)
 	}
 }
+impl From<ToMoveStringConvertError> for ApplicationError {
+	fn from(err: ToMoveStringConvertError) -> ApplicationError {
+		ApplicationError::ToMoveStringConvertError(err)
+	}
+}
+impl From<CommonError> for ApplicationError {
+	fn from(err: CommonError) -> ApplicationError {
+		ApplicationError::CommonError(err)
+	}
+}
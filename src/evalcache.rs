@@ -0,0 +1,97 @@
+use std::mem;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use usiagent::shogi::Teban;
+
+#[derive(Clone, Copy)]
+struct EvalCacheEntry {
+	teban:Teban,
+	mhash:u64,
+	shash:u64,
+	score:i32,
+}
+/// Evaluations of the positions shared by the search threads, keyed by (mhash, shash, teban).
+///
+/// The number of entries is fixed by the size in megabytes, and an entry is indexed by mhash
+/// and overwritten by the latest evaluation mapped to the same index.
+pub struct EvalCache {
+	size:usize,
+	entries:Vec<Mutex<Option<EvalCacheEntry>>>,
+	probes:AtomicU64,
+	hits:AtomicU64,
+}
+impl EvalCache {
+	pub fn new(size:usize) -> EvalCache {
+		let len = size * 1024 * 1024 / mem::size_of::<Mutex<Option<EvalCacheEntry>>>();
+
+		EvalCache {
+			size:size,
+			entries:(0..len).map(|_| Mutex::new(None)).collect(),
+			probes:AtomicU64::new(0),
+			hits:AtomicU64::new(0),
+		}
+	}
+
+	/// Size in megabytes specified at the creation.
+	pub fn size(&self) -> usize {
+		self.size
+	}
+
+	pub fn get(&self,teban:Teban,mhash:u64,shash:u64) -> Option<i32> {
+		if self.entries.len() == 0 {
+			return None;
+		}
+
+		self.probes.fetch_add(1,Ordering::Relaxed);
+
+		let entry = match self.entries[mhash as usize % self.entries.len()].lock() {
+			Ok(entry) => *entry,
+			Err(_) => None,
+		};
+
+		match entry {
+			Some(e) if e.teban == teban && e.mhash == mhash && e.shash == shash => {
+				self.hits.fetch_add(1,Ordering::Relaxed);
+				Some(e.score)
+			},
+			_ => None,
+		}
+	}
+
+	pub fn insert(&self,teban:Teban,mhash:u64,shash:u64,score:i32) {
+		if self.entries.len() == 0 {
+			return;
+		}
+
+		if let Ok(mut entry) = self.entries[mhash as usize % self.entries.len()].lock() {
+			*entry = Some(EvalCacheEntry {
+				teban:teban,
+				mhash:mhash,
+				shash:shash,
+				score:score,
+			});
+		}
+	}
+
+	/// Removes all the entries, used when the evaluation function is replaced.
+	pub fn clear(&self) {
+		for entry in &self.entries {
+			if let Ok(mut entry) = entry.lock() {
+				*entry = None;
+			}
+		}
+
+		self.reset_stats();
+	}
+
+	pub fn reset_stats(&self) {
+		self.probes.store(0,Ordering::Relaxed);
+		self.hits.store(0,Ordering::Relaxed);
+	}
+
+	/// Number of the hits and the lookups since the last reset.
+	pub fn stats(&self) -> (u64,u64) {
+		(self.hits.load(Ordering::Relaxed),self.probes.load(Ordering::Relaxed))
+	}
+}
@@ -0,0 +1,98 @@
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read};
+use std::path::Path;
+
+use packedsfen::traits::Reader;
+use packedsfen::hcpe::reader::HcpeReader;
+use packedsfen::hcpe::haffman_code::ExtendFields;
+use usiagent::output::USIStdErrorWriter;
+
+use error::ApplicationError;
+use records::HCPE_SIZE;
+use nn::{Architecture, DEFAULT_EVAL_SCALE, EVAL_MAX, Ensemble, fallback_warning, IntelligenceCreator, logit};
+
+const FIT_POSITIONS:usize = 100000;
+/// Teacher evaluations beyond this value are mostly the mate scores, and excluded from the fitting.
+const FIT_MAX_EVAL:i32 = 3000;
+
+/// Fits the coefficient converting the output of the network to centipawns to the evaluations of the hcpe teacher data.
+///
+/// The coefficient minimizes the squared error between eval_scale * logit(p) and the teacher evaluations,
+/// where p is the winning probability estimated by the network.
+pub struct EvalScaleFitter;
+impl EvalScaleFitter {
+	pub fn run<A: Architecture>(savedir:String,ensemble:Ensemble,kifudir:String) -> Result<(),ApplicationError> {
+		let evalutor = IntelligenceCreator::create::<A>(savedir,ensemble,false)?;
+
+		if let Some(file) = evalutor.fallback() {
+			let _ = USIStdErrorWriter::write(&format!("{}\n",fallback_warning(file)));
+		}
+
+		let mut reader = HcpeReader::new();
+
+		let mut paths = fs::read_dir(Path::new(&kifudir))?
+			.map(|p| p.map(|p| p.path()))
+			.collect::<Result<Vec<_>,_>>()?;
+
+		paths.sort();
+
+		let mut samples = Vec::new();
+
+		'files: for path in paths {
+			if !path.extension().map(|e| e == "hcpe").unwrap_or(false) {
+				continue;
+			}
+
+			print!("{}\n", path.display());
+
+			let mut r = BufReader::new(File::open(&path)?);
+
+			loop {
+				let mut buf = vec![0; HCPE_SIZE];
+
+				match r.read_exact(&mut buf) {
+					Ok(()) => (),
+					Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => break,
+					Err(e) => return Err(ApplicationError::from(e)),
+				}
+
+				let ((teban,banmen,mc),ExtendFields { eval, .. }) = reader.read_sfen_with_extended(buf).map_err(|e| {
+					ApplicationError::LearningError(format!("{}",e))
+				})?;
+
+				if (eval as i32).abs() >= FIT_MAX_EVAL {
+					continue;
+				}
+
+				let outputs = evalutor.evalute_by_networks(true,teban,&banmen,&mc)?;
+
+				samples.push((logit(evalutor.combine(&outputs)),eval as f64));
+
+				if samples.len() >= FIT_POSITIONS {
+					break 'files;
+				}
+			}
+		}
+
+		let (el,ll) = samples.iter().fold((0.,0.),|(el,ll),&(l,e)| (el + e * l,ll + l * l));
+
+		if samples.len() == 0 || ll == 0. {
+			return Err(ApplicationError::StartupError(String::from(
+				"評価値スケールの推定に使用できる局面がありません。"
+			)));
+		}
+
+		let eval_scale = (el / ll).max(1.).min(EVAL_MAX as f64);
+
+		let error = |scale:f64| {
+			samples.iter().map(|&(l,e)| (scale * l - e).abs()).sum::<f64>() / samples.len() as f64
+		};
+
+		print!("局面数: {}\n",samples.len());
+		print!("評価値スケール: {:.1}\n",eval_scale);
+		print!("平均誤差: {:.1}cp (既定値{}では{:.1}cp)\n",error(eval_scale),DEFAULT_EVAL_SCALE,error(DEFAULT_EVAL_SCALE as f64));
+
+		Ok(())
+	}
+}
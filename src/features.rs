@@ -0,0 +1,453 @@
+use std::convert::TryFrom;
+use std::ops::Mul;
+
+use nncombinator::arr::{Arr, DiffArr};
+
+use usiagent::shogi::*;
+
+use error::CommonError;
+use material;
+
+const BANMEN_SIZE:usize = 81;
+
+const SELF_TEBAN_INDEX:usize = 0;
+const OPPONENT_TEBAN_INDEX:usize = SELF_TEBAN_INDEX + 1;
+
+const OU_INDEX:usize = OPPONENT_TEBAN_INDEX + 1;
+const FU_INDEX:usize = OU_INDEX + BANMEN_SIZE;
+const KYOU_INDEX:usize = FU_INDEX + BANMEN_SIZE;
+const KEI_INDEX:usize = KYOU_INDEX + BANMEN_SIZE;
+const GIN_INDEX:usize = KEI_INDEX + BANMEN_SIZE;
+const KIN_INDEX:usize = GIN_INDEX + BANMEN_SIZE;
+const KAKU_INDEX:usize = KIN_INDEX + BANMEN_SIZE;
+const HISHA_INDEX:usize = KAKU_INDEX + BANMEN_SIZE;
+const NARIFU_INDEX:usize = HISHA_INDEX + BANMEN_SIZE;
+const NARIKYOU_INDEX:usize = NARIFU_INDEX + BANMEN_SIZE;
+const NARIKEI_INDEX:usize = NARIKYOU_INDEX + BANMEN_SIZE;
+const NARIGIN_INDEX:usize = NARIKEI_INDEX + BANMEN_SIZE;
+const NARIKAKU_INDEX:usize = NARIGIN_INDEX + BANMEN_SIZE;
+const NARIHISHA_INDEX:usize = NARIKAKU_INDEX + BANMEN_SIZE;
+const OPPONENT_FU_INDEX:usize = NARIHISHA_INDEX + BANMEN_SIZE;
+const OPPONENT_KYOU_INDEX:usize = OPPONENT_FU_INDEX + BANMEN_SIZE;
+const OPPONENT_KEI_INDEX:usize = OPPONENT_KYOU_INDEX + BANMEN_SIZE;
+const OPPONENT_GIN_INDEX:usize = OPPONENT_KEI_INDEX + BANMEN_SIZE;
+const OPPONENT_KIN_INDEX:usize = OPPONENT_GIN_INDEX + BANMEN_SIZE;
+const OPPONENT_KAKU_INDEX:usize = OPPONENT_KIN_INDEX + BANMEN_SIZE;
+const OPPONENT_HISHA_INDEX:usize = OPPONENT_KAKU_INDEX + BANMEN_SIZE;
+const OPPONENT_OU_INDEX:usize = OPPONENT_HISHA_INDEX + BANMEN_SIZE;
+const OPPONENT_NARIFU_INDEX:usize = OPPONENT_OU_INDEX + BANMEN_SIZE;
+const OPPONENT_NARIKYOU_INDEX:usize = OPPONENT_NARIFU_INDEX + BANMEN_SIZE;
+const OPPONENT_NARIKEI_INDEX:usize = OPPONENT_NARIKYOU_INDEX + BANMEN_SIZE;
+const OPPONENT_NARIGIN_INDEX:usize = OPPONENT_NARIKEI_INDEX + BANMEN_SIZE;
+const OPPONENT_NARIKAKU_INDEX:usize = OPPONENT_NARIGIN_INDEX + BANMEN_SIZE;
+const OPPONENT_NARIHISHA_INDEX:usize = OPPONENT_NARIKAKU_INDEX + BANMEN_SIZE;
+
+const MOCHIGOMA_FU_INDEX:usize = OPPONENT_NARIHISHA_INDEX + BANMEN_SIZE;
+const MOCHIGOMA_KYOU_INDEX:usize = MOCHIGOMA_FU_INDEX + 19;
+const MOCHIGOMA_KEI_INDEX:usize = MOCHIGOMA_KYOU_INDEX + 5;
+const MOCHIGOMA_GIN_INDEX:usize = MOCHIGOMA_KEI_INDEX + 5;
+const MOCHIGOMA_KIN_INDEX:usize = MOCHIGOMA_GIN_INDEX + 5;
+const MOCHIGOMA_KAKU_INDEX:usize = MOCHIGOMA_KIN_INDEX + 5;
+const MOCHIGOMA_HISHA_INDEX:usize = MOCHIGOMA_KAKU_INDEX + 3;
+const OPPONENT_MOCHIGOMA_FU_INDEX:usize = MOCHIGOMA_HISHA_INDEX + 3;
+const OPPONENT_MOCHIGOMA_KYOU_INDEX:usize = OPPONENT_MOCHIGOMA_FU_INDEX + 19;
+const OPPONENT_MOCHIGOMA_KEI_INDEX:usize = OPPONENT_MOCHIGOMA_KYOU_INDEX + 5;
+const OPPONENT_MOCHIGOMA_GIN_INDEX:usize = OPPONENT_MOCHIGOMA_KEI_INDEX + 5;
+const OPPONENT_MOCHIGOMA_KIN_INDEX:usize = OPPONENT_MOCHIGOMA_GIN_INDEX + 5;
+const OPPONENT_MOCHIGOMA_KAKU_INDEX:usize = OPPONENT_MOCHIGOMA_KIN_INDEX + 5;
+const OPPONENT_MOCHIGOMA_HISHA_INDEX:usize = OPPONENT_MOCHIGOMA_KAKU_INDEX + 3;
+
+/// Maximum numbers of the pieces in hand, indexed by MochigomaKind.
+const MOCHIGOMA_MAX:[usize; 7] = [19, 5, 5, 5, 5, 3, 3];
+
+const SELF_INDEX_MAP:[usize; 7] = [
+	MOCHIGOMA_FU_INDEX,
+	MOCHIGOMA_KYOU_INDEX,
+	MOCHIGOMA_KEI_INDEX,
+	MOCHIGOMA_GIN_INDEX,
+	MOCHIGOMA_KIN_INDEX,
+	MOCHIGOMA_KAKU_INDEX,
+	MOCHIGOMA_HISHA_INDEX
+];
+
+const OPPONENT_INDEX_MAP:[usize; 7] = [
+	OPPONENT_MOCHIGOMA_FU_INDEX,
+	OPPONENT_MOCHIGOMA_KYOU_INDEX,
+	OPPONENT_MOCHIGOMA_KEI_INDEX,
+	OPPONENT_MOCHIGOMA_GIN_INDEX,
+	OPPONENT_MOCHIGOMA_KIN_INDEX,
+	OPPONENT_MOCHIGOMA_KAKU_INDEX,
+	OPPONENT_MOCHIGOMA_HISHA_INDEX
+];
+
+const SENTE_INDEX_MAP:[usize; 28] = [
+	FU_INDEX,
+	KYOU_INDEX,
+	KEI_INDEX,
+	GIN_INDEX,
+	KIN_INDEX,
+	KAKU_INDEX,
+	HISHA_INDEX,
+	OU_INDEX,
+	NARIFU_INDEX,
+	NARIKYOU_INDEX,
+	NARIKEI_INDEX,
+	NARIGIN_INDEX,
+	NARIKAKU_INDEX,
+	NARIHISHA_INDEX,
+	OPPONENT_FU_INDEX,
+	OPPONENT_KYOU_INDEX,
+	OPPONENT_KEI_INDEX,
+	OPPONENT_GIN_INDEX,
+	OPPONENT_KIN_INDEX,
+	OPPONENT_KAKU_INDEX,
+	OPPONENT_HISHA_INDEX,
+	OPPONENT_OU_INDEX,
+	OPPONENT_NARIFU_INDEX,
+	OPPONENT_NARIKYOU_INDEX,
+	OPPONENT_NARIKEI_INDEX,
+	OPPONENT_NARIGIN_INDEX,
+	OPPONENT_NARIKAKU_INDEX,
+	OPPONENT_NARIHISHA_INDEX
+];
+
+const GOTE_INDEX_MAP:[usize; 28] = [
+	OPPONENT_FU_INDEX,
+	OPPONENT_KYOU_INDEX,
+	OPPONENT_KEI_INDEX,
+	OPPONENT_GIN_INDEX,
+	OPPONENT_KIN_INDEX,
+	OPPONENT_KAKU_INDEX,
+	OPPONENT_HISHA_INDEX,
+	OPPONENT_OU_INDEX,
+	OPPONENT_NARIFU_INDEX,
+	OPPONENT_NARIKYOU_INDEX,
+	OPPONENT_NARIKEI_INDEX,
+	OPPONENT_NARIGIN_INDEX,
+	OPPONENT_NARIKAKU_INDEX,
+	OPPONENT_NARIHISHA_INDEX,
+	FU_INDEX,
+	KYOU_INDEX,
+	KEI_INDEX,
+	GIN_INDEX,
+	KIN_INDEX,
+	KAKU_INDEX,
+	HISHA_INDEX,
+	OU_INDEX,
+	NARIFU_INDEX,
+	NARIKYOU_INDEX,
+	NARIKEI_INDEX,
+	NARIGIN_INDEX,
+	NARIKAKU_INDEX,
+	NARIHISHA_INDEX
+];
+
+/// Position mirrored left and right, which has the same value as the original.
+pub fn mirror_banmen(b:&Banmen) -> Banmen {
+	let &Banmen(ref kinds) = b;
+
+	let mut mirrored = kinds.clone();
+
+	for y in 0..9 {
+		for x in 0..9 {
+			mirrored[y][x] = kinds[y][8 - x];
+		}
+	}
+
+	Banmen(mirrored)
+}
+
+/// Encoding of a position into the input of the network.
+pub trait FeatureSet: Send + Sync + 'static {
+	/// Identifier of the input features written to the model file.
+	const NAME:&'static str;
+	/// Number of the inputs.
+	const INPUTS:usize;
+
+	type Input: Clone + Mul<f32,Output=Self::Input> + Send + Sync + 'static;
+	type DiffInput: Clone + Mul<f32,Output=Self::DiffInput> + Send + Sync + 'static;
+
+	fn make_input(is_self:bool,t:Teban,b:&Banmen,mc:&MochigomaCollections) -> Self::Input;
+	/// Difference of the input by the move m made by mover on the position before the move (b,mc).
+	fn make_diff_input(is_self:bool,t:Teban,b:&Banmen,mc:&MochigomaCollections,m:&Move,mover:Teban)
+		-> Result<Self::DiffInput,CommonError>;
+
+	/// Weights of the inputs making the hand-crafted evaluation in centipawns. (see MaterialNetwork)
+	fn material_weights() -> Vec<f32>;
+	/// Sum of the inputs multiplied by the weights.
+	fn dot(input:&Self::Input,weights:&[f32]) -> f32;
+	/// Sum of the changes of the inputs multiplied by the weights.
+	fn dot_diff(input:&Self::DiffInput,weights:&[f32]) -> f32;
+}
+/// Piece-square one-hot features from the side of t and the counts of the pieces in hand. (2517 inputs)
+pub struct InputCreator;
+
+impl FeatureSet for InputCreator {
+	const NAME:&'static str = "piece2517";
+	const INPUTS:usize = 2517;
+
+	type Input = Arr<f32,2517>;
+	type DiffInput = DiffArr<f32,2517>;
+
+	fn make_input(is_self:bool,t:Teban,b:&Banmen,mc:&MochigomaCollections) -> Arr<f32,2517> {
+		let mut inputs = Arr::new();
+
+		let index = if is_self {
+			SELF_TEBAN_INDEX
+		} else {
+			OPPONENT_TEBAN_INDEX
+		};
+
+		inputs[index] = 1f32;
+
+		match b {
+			&Banmen(ref kinds) => {
+				for y in 0..9 {
+					for x in 0..9 {
+						let kind = kinds[y][x];
+
+						if kind != KomaKind::Blank {
+							let index = InputCreator::input_index_of_banmen(t,kind,x as u32,y as u32).unwrap();
+
+							inputs[index] = 1f32;
+						}
+					}
+				}
+			}
+		}
+
+		let ms = Mochigoma::new();
+		let mg = Mochigoma::new();
+		let (ms,mg) = match mc {
+			&MochigomaCollections::Pair(ref ms,ref mg) => (ms,mg),
+			&MochigomaCollections::Empty => (&ms,&mg),
+		};
+
+		let (ms,mg) = match t {
+			Teban::Sente => (ms,mg),
+			Teban::Gote => (mg,ms),
+		};
+
+		for &k in &MOCHIGOMA_KINDS {
+			let c = ms.get(k);
+
+			for i in 0..c {
+				let offset = SELF_INDEX_MAP[k as usize];
+
+				let offset = offset as usize;
+
+				inputs[offset + i as usize] = 1f32;
+			}
+
+			let c = mg.get(k);
+
+			for i in 0..c {
+				let offset = OPPONENT_INDEX_MAP[k as usize];
+
+				let offset = offset as usize;
+
+				inputs[offset + i as usize] = 1f32;
+			}
+		}
+		inputs
+	}
+
+	fn make_diff_input(is_self:bool, t:Teban, b:&Banmen, mc:&MochigomaCollections, m:&Move, mover:Teban) -> Result<DiffArr<f32,2517>,CommonError> {
+		let mut d = DiffArr::new();
+
+		let (addi,subi) = if is_self {
+			(SELF_TEBAN_INDEX,OPPONENT_TEBAN_INDEX)
+		} else {
+			(OPPONENT_TEBAN_INDEX,SELF_TEBAN_INDEX)
+		};
+
+		d.push(subi,-1.)?;
+		d.push(addi,1.)?;
+
+		match m {
+			&Move::To(KomaSrcPosition(sx,sy),KomaDstToPosition(dx,dy,n)) => {
+				match b {
+					&Banmen(ref kinds) => {
+						let (sx,sy) = (9-sx,sy-1);
+						let (dx,dy) = (9-dx,dy-1);
+
+						let sk = kinds[sy as usize][sx as usize];
+
+						d.push(InputCreator::input_index_of_banmen(t, sk, sx, sy)?, -1.)?;
+
+						if n {
+							d.push(InputCreator::input_index_of_banmen(t,sk.to_nari(),dx,dy)?,1.)?;
+						} else {
+							d.push(InputCreator::input_index_of_banmen(t,sk,dx,dy)?,1.)?;
+						}
+
+						let dk = kinds[dy as usize][dx as usize];
+
+						if dk != KomaKind::Blank {
+							d.push(InputCreator::input_index_of_banmen(t,dk,dx,dy)?,-1.)?;
+						}
+
+						if dk != KomaKind::Blank && dk != KomaKind::SOu && dk != KomaKind::GOu {
+							let offset = InputCreator::input_index_with_of_mochigoma_get(t, mover, MochigomaKind::try_from(dk)?, mc)?;
+
+							d.push(offset, 1.)?;
+						}
+					}
+				}
+			},
+			&Move::Put(kind,KomaDstPutPosition(dx,dy))  => {
+				let (dx,dy) = (9-dx,dy-1);
+				let offset = InputCreator::input_index_with_of_mochigoma_get(t, mover, kind, mc)?;
+
+				if offset == InputCreator::input_index_of_mochigoma(t, mover, kind) {
+					return Err(CommonError::Fail(
+						String::from(
+							"Calculation of index of difference input data of neural network failed. (The number of holding pieces is 0)"
+						)))
+				} else {
+					d.push(offset - 1, -1.)?;
+
+					d.push(InputCreator::input_index_of_banmen(t, KomaKind::from((mover, kind)), dx, dy)?, 1.)?;
+				}
+			}
+		}
+
+		Ok(d)
+	}
+
+	fn material_weights() -> Vec<f32> {
+		// A piece near the king is not linear in the absolute squares, so the king safety is only the rank of the king.
+		let mut weights = vec![0.; 2517];
+
+		for k in 0..14 {
+			for y in 0..9 {
+				for x in 0..9 {
+					let (own,opponent) = if k == KomaKind::SOu as usize {
+						(material::king_rank_value(8 - y),-material::king_rank_value(y))
+					} else {
+						(material::PIECE_VALUES[k],-material::PIECE_VALUES[k])
+					};
+
+					weights[SENTE_INDEX_MAP[k] + y * 9 + x] = own;
+					weights[SENTE_INDEX_MAP[k + 14] + y * 9 + x] = opponent;
+				}
+			}
+		}
+
+		for &kind in &MOCHIGOMA_KINDS {
+			for i in 0..MOCHIGOMA_MAX[kind as usize] {
+				weights[SELF_INDEX_MAP[kind as usize] + i] = material::HAND_VALUES[kind as usize];
+				weights[OPPONENT_INDEX_MAP[kind as usize] + i] = -material::HAND_VALUES[kind as usize];
+			}
+		}
+
+		weights
+	}
+
+	fn dot(input:&Arr<f32,2517>,weights:&[f32]) -> f32 {
+		material::dot(input,weights)
+	}
+
+	fn dot_diff(input:&DiffArr<f32,2517>,weights:&[f32]) -> f32 {
+		material::dot_diff(input,weights)
+	}
+}
+impl InputCreator {
+	#[inline]
+	fn input_index_of_banmen(teban:Teban,kind:KomaKind,x:u32,y:u32) -> Result<usize,CommonError> {
+		let index = match teban {
+			Teban::Sente | Teban::Gote if kind == KomaKind::Blank => {
+				return Err(CommonError::Fail(
+					String::from(
+						"Calculation of index of difference input data of neural network failed. (KomaKind is 'Blank')"
+					)));
+			},
+			Teban::Sente => {
+				SENTE_INDEX_MAP[kind as usize] + y as usize * 9 + x as usize
+			},
+			Teban::Gote => {
+				let (x,y) = (8-x,8-y);
+
+				GOTE_INDEX_MAP[kind as usize] + y as usize * 9 + x as usize
+			}
+		};
+
+		Ok(index as usize)
+	}
+
+	#[inline]
+	fn input_index_of_mochigoma(t:Teban, owner:Teban, kind:MochigomaKind) -> usize {
+		if owner == t {
+			SELF_INDEX_MAP[kind as usize]
+		} else {
+			OPPONENT_INDEX_MAP[kind as usize]
+		}
+	}
+
+	/// Index of the next piece of the kind put into the hand of owner, seen from t.
+	fn input_index_with_of_mochigoma_get(t:Teban, owner:Teban, kind:MochigomaKind, mc:&MochigomaCollections)
+										 -> Result<usize,CommonError> {
+
+		let ms = Mochigoma::new();
+		let mg = Mochigoma::new();
+
+		let (ms,mg) = match mc {
+			&MochigomaCollections::Pair(ref ms,ref mg) => (ms,mg),
+			&MochigomaCollections::Empty => (&ms,&mg),
+		};
+
+		let mc = match owner {
+			Teban::Sente => ms,
+			Teban::Gote => mg,
+		};
+
+		let offset = InputCreator::input_index_of_mochigoma(t, owner, kind);
+
+		let c = mc.get(kind);
+
+		Ok(offset + c as usize)
+	}
+}
+#[cfg(test)]
+mod tests {
+	use usiagent::rule::BANMEN_START_POS;
+
+	use super::*;
+
+	#[test]
+	fn mirror_banmen_swaps_the_files() {
+		let mut kinds = [[KomaKind::Blank; 9]; 9];
+
+		kinds[0][4] = KomaKind::GOu;
+		kinds[8][4] = KomaKind::SOu;
+		kinds[2][0] = KomaKind::GKyou;
+		kinds[6][7] = KomaKind::SKakuN;
+
+		let Banmen(mirrored) = mirror_banmen(&Banmen(kinds));
+
+		let mut expected = [[KomaKind::Blank; 9]; 9];
+
+		expected[0][4] = KomaKind::GOu;
+		expected[8][4] = KomaKind::SOu;
+		expected[2][8] = KomaKind::GKyou;
+		expected[6][1] = KomaKind::SKakuN;
+
+		assert_eq!(mirrored,expected);
+	}
+
+	#[test]
+	fn mirror_banmen_of_the_start_position_swaps_the_bishops_and_the_rooks() {
+		let Banmen(kinds) = BANMEN_START_POS.clone();
+		let Banmen(mirrored) = mirror_banmen(&BANMEN_START_POS);
+
+		assert_eq!(mirrored[7][1],kinds[7][7]);
+		assert_eq!(mirrored[7][7],kinds[7][1]);
+		assert_eq!(mirrored[1][1],kinds[1][7]);
+		assert_eq!(mirrored[1][7],kinds[1][1]);
+		assert_ne!(mirrored,kinds);
+
+		let Banmen(restored) = mirror_banmen(&Banmen(mirrored));
+
+		assert_eq!(restored,kinds);
+	}
+}
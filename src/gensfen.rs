@@ -0,0 +1,313 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Instant;
+
+use rand::{Rng, SeedableRng};
+use rand_xorshift::XorShiftRng;
+
+use usiagent::OnErrorHandler;
+use usiagent::command::*;
+use usiagent::event::*;
+use usiagent::logger::FileLogger;
+use usiagent::output::USIStdOutputWriter;
+use usiagent::player::*;
+use usiagent::rule::*;
+use usiagent::shogi::*;
+
+use error::{ApplicationError, CommonError};
+use nn::{EVAL_MAX, Ensemble, Intelligence, Network};
+use perft::Perft;
+use player::NNShogiPlayer;
+use records::{RecordFormat, RecordWriter, TeacherPosition};
+
+pub const GENSFEN_THREADS:usize = 1;
+pub const GENSFEN_POSITIONS:usize = 1000000;
+pub const GENSFEN_DEPTH:u32 = 3;
+pub const GENSFEN_OPENING_MOVES:u32 = 16;
+pub const GENSFEN_MAX_MOVES:u32 = 256;
+pub const GENSFEN_EVAL_LIMIT:i32 = 3000;
+const MATE_EVAL:i16 = 32000;
+const PROGRESS_INTERVAL:usize = 10000;
+
+/// [gensfen] table of settings.toml.
+#[derive(Debug, Deserialize)]
+pub struct GensfenSettings {
+	threads:Option<usize>,
+	positions:Option<usize>,
+	depth:Option<u32>,
+	nodes:Option<u64>,
+	opening_moves:Option<u32>,
+	max_moves:Option<u32>,
+	eval_limit:Option<i32>,
+}
+/// Generates the teacher phases by playing the games with the search of the engine.
+///
+/// Each thread plays the games from the random openings with a player searching at the fixed depth (or node count),
+/// and records the positions with the score and the best move of the search, the ply and the final game result.
+/// A game ends by the resignation, by reaching max_moves (a draw), or when the score reaches eval_limit.
+pub struct SfenGenerator<NN,C>
+	where NN: Network,
+		  C: Fn(String,Ensemble,bool) -> Result<Intelligence<NN>,ApplicationError> + Copy + Send + 'static {
+	threads:usize,
+	positions:usize,
+	depth:u32,
+	nodes:u64,
+	opening_moves:u32,
+	max_moves:u32,
+	eval_limit:i32,
+	create:C,
+	ensemble:Ensemble,
+	options:Vec<(String,SysEventOption)>,
+}
+impl<NN,C> SfenGenerator<NN,C>
+	where NN: Network,
+		  C: Fn(String,Ensemble,bool) -> Result<Intelligence<NN>,ApplicationError> + Copy + Send + 'static {
+	pub fn new(settings:Option<&GensfenSettings>,
+			   create:C,
+			   ensemble:Ensemble,
+			   options:Vec<(String,SysEventOption)>) -> Result<SfenGenerator<NN,C>,ApplicationError> {
+		let threads = settings.and_then(|s| s.threads).unwrap_or(GENSFEN_THREADS);
+
+		if threads == 0 {
+			return Err(ApplicationError::StartupError(String::from(
+				"gensfen.threadsには1以上の値を指定してください。"
+			)));
+		}
+
+		let depth = settings.and_then(|s| s.depth).unwrap_or(GENSFEN_DEPTH);
+
+		if depth == 0 {
+			return Err(ApplicationError::StartupError(String::from(
+				"gensfen.depthには1以上の値を指定してください。"
+			)));
+		}
+
+		let eval_limit = settings.and_then(|s| s.eval_limit).unwrap_or(GENSFEN_EVAL_LIMIT);
+
+		if eval_limit <= 0 {
+			return Err(ApplicationError::StartupError(String::from(
+				"gensfen.eval_limitには1以上の値を指定してください。"
+			)));
+		}
+
+		Ok(SfenGenerator {
+			threads:threads,
+			positions:settings.and_then(|s| s.positions).unwrap_or(GENSFEN_POSITIONS),
+			depth:depth,
+			nodes:settings.and_then(|s| s.nodes).unwrap_or(0),
+			opening_moves:settings.and_then(|s| s.opening_moves).unwrap_or(GENSFEN_OPENING_MOVES),
+			max_moves:settings.and_then(|s| s.max_moves).unwrap_or(GENSFEN_MAX_MOVES),
+			eval_limit:eval_limit,
+			create:create,
+			ensemble:ensemble,
+			options:options,
+		})
+	}
+
+	/// Appends the generated positions to the file until the number of the positions of the settings is written.
+	pub fn run<P: AsRef<Path>>(&self,path:P,format:RecordFormat,
+							   on_error_handler:Arc<Mutex<OnErrorHandler<FileLogger>>>) -> Result<(),ApplicationError> {
+		let writer = Arc::new(Mutex::new(RecordWriter::new(path,format)?));
+		let quit = Arc::new(AtomicBool::new(false));
+
+		print!("threads = {}, positions = {}, depth = {}, nodes = {}\n",self.threads,self.positions,self.depth,self.nodes);
+
+		let start_time = Instant::now();
+
+		let mut workers = Vec::with_capacity(self.threads);
+
+		for i in 0..self.threads {
+			let writer = writer.clone();
+			let quit = quit.clone();
+			let on_error_handler = on_error_handler.clone();
+			let mut player = self.player()?;
+			let positions = self.positions;
+			let opening_moves = self.opening_moves;
+			let max_moves = self.max_moves;
+			let eval_limit = self.eval_limit;
+
+			workers.push(thread::Builder::new().name(format!("gensfen-{}",i)).spawn(move || {
+				let r = (|| {
+					player.take_ready(OnKeepAlive::new(Arc::new(Mutex::new(USIStdOutputWriter::new())),on_error_handler.clone()))?;
+
+					let mut rnd = rand::thread_rng();
+					let mut rnd = XorShiftRng::from_seed(rnd.gen());
+
+					while !quit.load(Ordering::Acquire) {
+						let game = SfenGenerator::<NN,C>::play(&mut player,&mut rnd,opening_moves,max_moves,eval_limit,
+															   &on_error_handler)?;
+
+						let mut writer = writer.lock().map_err(|_| ApplicationError::LearningError(String::from(
+							"Failed to secure exclusive lock of the record writer."
+						)))?;
+
+						for p in game.iter() {
+							if writer.count() >= positions {
+								break;
+							}
+
+							writer.write(p)?;
+
+							if writer.count() % PROGRESS_INTERVAL == 0 {
+								print!("{}局面を書き出しました。\n",writer.count());
+							}
+						}
+
+						if writer.count() >= positions {
+							quit.store(true,Ordering::Release);
+						}
+					}
+
+					Ok(())
+				})();
+
+				if r.is_err() {
+					quit.store(true,Ordering::Release);
+				}
+
+				r
+			})?);
+		}
+
+		let mut result = Ok(());
+
+		for worker in workers.into_iter() {
+			let r = worker.join().map_err(|_| ApplicationError::LearningError(String::from(
+				"The generating thread has panicked."
+			))).and_then(|r| r);
+
+			if let Err(e) = r {
+				let _ = on_error_handler.lock().map(|h| h.call(&e));
+
+				if result.is_ok() {
+					result = Err(e);
+				}
+			}
+		}
+
+		let mut writer = writer.lock().map_err(|_| ApplicationError::LearningError(String::from(
+			"Failed to secure exclusive lock of the record writer."
+		)))?;
+
+		writer.flush()?;
+
+		let secs = (Instant::now() - start_time).as_secs();
+
+		print!("{}局面を書き出しました。(経過時間: {}時間{}分{}秒)\n",writer.count(),secs / (60 * 60),secs % (60 * 60) / 60,secs % 60);
+
+		result
+	}
+
+	fn player(&self) -> Result<NNShogiPlayer<NN>,ApplicationError> {
+		let create = self.create;
+		let ensemble = self.ensemble.clone();
+
+		let mut player = NNShogiPlayer::new(move |dir,files,material| {
+			create(dir.to_string(),ensemble.with_filenames(files)?,material)
+		});
+
+		let mut options = self.options.clone();
+
+		options.push((String::from("Threads"),SysEventOption::Num(1)));
+		options.push((String::from("BaseDepth"),SysEventOption::Num(self.depth as i64)));
+		options.push((String::from("MaxDepth"),SysEventOption::Num(self.depth as i64)));
+		options.push((String::from("AdjustDepth"),SysEventOption::Bool(false)));
+		options.push((String::from("MaxNodes"),SysEventOption::Num(self.nodes as i64)));
+		options.push((String::from("ResignValue"),SysEventOption::Num(0)));
+		options.push((String::from("DrawValue"),SysEventOption::Num(0)));
+
+		for (name,value) in options.into_iter() {
+			player.set_option(name,value)?;
+		}
+
+		Ok(player)
+	}
+
+	/// Evaluation of the record from the score of the search in centipawns.
+	fn eval(score:Option<UsiScore>) -> i16 {
+		match score {
+			Some(UsiScore::Cp(s)) => s.max(-EVAL_MAX as i64).min(EVAL_MAX as i64) as i16,
+			Some(UsiScore::Mate(UsiScoreMate::Plus)) => MATE_EVAL,
+			Some(UsiScore::Mate(UsiScoreMate::Minus)) => -MATE_EVAL,
+			_ => 0,
+		}
+	}
+
+	/// Plays a game and returns the positions after the opening labelled with the result of the game.
+	fn play(player:&mut NNShogiPlayer<NN>,rnd:&mut XorShiftRng,opening_moves:u32,max_moves:u32,eval_limit:i32,
+			on_error_handler:&Arc<Mutex<OnErrorHandler<FileLogger>>>) -> Result<Vec<TeacherPosition>,ApplicationError> {
+		let perft = Perft::new(true);
+
+		let (mut teban,mut state,mut mc,mut mvs) = perft.random_opening(opening_moves,rnd);
+
+		let event_queue = Arc::new(Mutex::new(UserEventQueue::new()));
+
+		let mut positions:Vec<TeacherPosition> = Vec::new();
+
+		player.newgame()?;
+
+		let winner = loop {
+			if mvs.len() >= max_moves as usize {
+				break None;
+			}
+
+			if perft.moves(teban,&state,&mc).is_empty() {
+				break Some(teban.opposite());
+			}
+
+			player.set_position(Teban::Sente,BANMEN_START_POS.clone(),Mochigoma::new(),Mochigoma::new(),1,mvs.clone())?;
+
+			let m = match player.think(Instant::now(),&UsiGoTimeLimit::Infinite,event_queue.clone(),
+									   ConsoleInfoSender::new(true),ConsolePeriodicallyInfo::new(true),
+									   on_error_handler.clone())? {
+				BestMove::Move(m,_) => m,
+				BestMove::Abort => {
+					return Err(ApplicationError::from(CommonError::Fail(String::from(
+						"The search was aborted."
+					))));
+				},
+				_ => {
+					break Some(teban.opposite());
+				}
+			};
+
+			let eval = SfenGenerator::<NN,C>::eval(player.last_score());
+
+			if eval as i32 >= eval_limit {
+				break Some(teban);
+			} else if eval as i32 <= -eval_limit {
+				break Some(teban.opposite());
+			}
+
+			positions.push(TeacherPosition {
+				teban:teban,
+				banmen:state.get_banmen().clone(),
+				mc:mc.clone(),
+				eval:eval,
+				best_move:Some(m),
+				ply:mvs.len() as u16 + 1,
+				result:GameEndState::Draw,
+			});
+
+			let (next,nmc,_) = Rule::apply_move_none_check(&state,teban,&mc,m.to_applied_move());
+
+			state = next;
+			mc = nmc;
+			teban = teban.opposite();
+
+			mvs.push(m);
+		};
+
+		for p in positions.iter_mut() {
+			p.result = match winner {
+				None => GameEndState::Draw,
+				Some(winner) if winner == p.teban => GameEndState::Win,
+				Some(_) => GameEndState::Lose,
+			};
+		}
+
+		Ok(positions)
+	}
+}
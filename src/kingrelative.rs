@@ -0,0 +1,489 @@
+use std::convert::TryFrom;
+
+use nncombinator::arr::{Arr, DiffArr};
+
+use usiagent::shogi::*;
+use usiagent::rule::*;
+
+use error::CommonError;
+use features::FeatureSet;
+use material;
+
+const BANMEN_SIZE:usize = 81;
+/// Kinds of the pieces other than the kings, 13 of the own pieces followed by 13 of the opponent's.
+const PIECE_CLASSES:usize = 26;
+/// Squares relative to the king. (-8..=8 for both x and y)
+const RELATIVE_SQUARES:usize = 17 * 17;
+
+const SELF_TEBAN_INDEX:usize = 0;
+const OPPONENT_TEBAN_INDEX:usize = SELF_TEBAN_INDEX + 1;
+
+const OU_INDEX:usize = OPPONENT_TEBAN_INDEX + 1;
+const OPPONENT_OU_INDEX:usize = OU_INDEX + BANMEN_SIZE;
+
+const KING_RELATIVE_INDEX:usize = OPPONENT_OU_INDEX + BANMEN_SIZE;
+const OPPONENT_KING_RELATIVE_INDEX:usize = KING_RELATIVE_INDEX + PIECE_CLASSES * RELATIVE_SQUARES;
+
+const MOCHIGOMA_INDEX:usize = OPPONENT_KING_RELATIVE_INDEX + PIECE_CLASSES * RELATIVE_SQUARES;
+const MOCHIGOMA_SIZE:usize = 19 + 5 + 5 + 5 + 5 + 3 + 3;
+const OPPONENT_MOCHIGOMA_INDEX:usize = MOCHIGOMA_INDEX + MOCHIGOMA_SIZE;
+
+const MOCHIGOMA_OFFSET_MAP:[usize; 7] = [
+	0,
+	19,
+	19 + 5,
+	19 + 5 + 5,
+	19 + 5 + 5 + 5,
+	19 + 5 + 5 + 5 + 5,
+	19 + 5 + 5 + 5 + 5 + 3
+];
+
+pub const KING_RELATIVE_INPUTS:usize = OPPONENT_MOCHIGOMA_INDEX + MOCHIGOMA_SIZE;
+
+/// Feature set encoding the pieces on the board by their positions relative to each king.
+///
+/// The pieces are seen from the own king as they are, and from the opponent king rotated with the owners swapped,
+/// so both kings see their surroundings the same way. The squares of the kings and the pieces in hand are encoded as in InputCreator.
+/// A king move changes all the relative positions, so its difference is computed by refreshing the whole input.
+pub struct KingRelativeInputCreator;
+impl FeatureSet for KingRelativeInputCreator {
+	const NAME:&'static str = "kingrelative15282";
+	const INPUTS:usize = KING_RELATIVE_INPUTS;
+
+	type Input = Arr<f32,KING_RELATIVE_INPUTS>;
+	type DiffInput = DiffArr<f32,KING_RELATIVE_INPUTS>;
+
+	fn make_input(is_self:bool,t:Teban,b:&Banmen,mc:&MochigomaCollections) -> Self::Input {
+		let mut inputs = Arr::new();
+
+		for i in KingRelativeInputCreator::active_features(is_self,t,b,mc) {
+			inputs[i] = 1f32;
+		}
+
+		inputs
+	}
+
+	fn make_diff_input(is_self:bool,t:Teban,b:&Banmen,mc:&MochigomaCollections,m:&Move,mover:Teban)
+		-> Result<Self::DiffInput,CommonError> {
+		let mut d = DiffArr::new();
+
+		let (addi,subi) = if is_self {
+			(SELF_TEBAN_INDEX,OPPONENT_TEBAN_INDEX)
+		} else {
+			(OPPONENT_TEBAN_INDEX,SELF_TEBAN_INDEX)
+		};
+
+		match m {
+			&Move::To(KomaSrcPosition(sx,sy),KomaDstToPosition(dx,dy,n)) => {
+				let &Banmen(ref kinds) = b;
+
+				let (sx,sy) = (9-sx as usize,sy as usize-1);
+				let (dx,dy) = (9-dx as usize,dy as usize-1);
+
+				let sk = kinds[sy][sx];
+				let dk = kinds[dy][dx];
+
+				if sk == KomaKind::Blank {
+					return Err(CommonError::Fail(String::from(
+						"Calculation of index of difference input data of neural network failed. (KomaKind is 'Blank')"
+					)));
+				}
+
+				if KingRelativeInputCreator::is_ou(sk) || KingRelativeInputCreator::is_ou(dk) {
+					return KingRelativeInputCreator::make_refresh_input(is_self,t,b,mc,m,mover);
+				}
+
+				d.push(subi,-1.)?;
+				d.push(addi,1.)?;
+
+				let kings = KingRelativeInputCreator::kings(t,b);
+
+				for &i in KingRelativeInputCreator::relative_indexes(t,&kings,sk,sx,sy).iter().flatten() {
+					d.push(i,-1.)?;
+				}
+
+				let nk = if n {
+					sk.to_nari()
+				} else {
+					sk
+				};
+
+				for &i in KingRelativeInputCreator::relative_indexes(t,&kings,nk,dx,dy).iter().flatten() {
+					d.push(i,1.)?;
+				}
+
+				if dk != KomaKind::Blank {
+					for &i in KingRelativeInputCreator::relative_indexes(t,&kings,dk,dx,dy).iter().flatten() {
+						d.push(i,-1.)?;
+					}
+
+					let owner = KingRelativeInputCreator::owner(sk);
+					let kind = MochigomaKind::try_from(dk)?;
+					let c = KingRelativeInputCreator::mochigoma_count(owner,kind,mc);
+
+					d.push(KingRelativeInputCreator::mochigoma_index(t,owner,kind,c),1.)?;
+				}
+			},
+			&Move::Put(kind,KomaDstPutPosition(dx,dy)) => {
+				let (dx,dy) = (9-dx as usize,dy as usize-1);
+
+				let c = KingRelativeInputCreator::mochigoma_count(mover,kind,mc);
+
+				if c < 1 {
+					return Err(CommonError::Fail(String::from(
+						"Calculation of index of difference input data of neural network failed. (The number of holding pieces is 0)"
+					)));
+				}
+
+				d.push(subi,-1.)?;
+				d.push(addi,1.)?;
+
+				d.push(KingRelativeInputCreator::mochigoma_index(t,mover,kind,c-1),-1.)?;
+
+				let kings = KingRelativeInputCreator::kings(t,b);
+
+				for &i in KingRelativeInputCreator::relative_indexes(t,&kings,KomaKind::from((mover,kind)),dx,dy).iter().flatten() {
+					d.push(i,1.)?;
+				}
+			}
+		}
+
+		Ok(d)
+	}
+
+	fn material_weights() -> Vec<f32> {
+		let mut weights = vec![0.; KING_RELATIVE_INPUTS];
+
+		for y in 0..9 {
+			for x in 0..9 {
+				weights[OU_INDEX + y * 9 + x] = material::king_rank_value(8 - y);
+				weights[OPPONENT_OU_INDEX + y * 9 + x] = -material::king_rank_value(y);
+			}
+		}
+
+		// Each piece is seen from both kings, so the half of its value is given to each.
+		for class in 0..PIECE_CLASSES {
+			let own = class < PIECE_CLASSES / 2;
+			let k = class % (PIECE_CLASSES / 2);
+			let k = if k < KomaKind::SOu as usize {
+				k
+			} else {
+				k + 1
+			};
+
+			let value = material::PIECE_VALUES[k] / 2.;
+
+			for ry in 0..17 {
+				for rx in 0..17 {
+					let near = (ry as i32 - 8).abs() <= 2 && (rx as i32 - 8).abs() <= 2;
+
+					let (defender,attacker) = if near {
+						(material::KING_DEFENDER_VALUE,material::KING_ATTACKER_VALUE)
+					} else {
+						(0.,0.)
+					};
+
+					let i = class * RELATIVE_SQUARES + ry * 17 + rx;
+
+					// Seen from the opponent king, the classes of the own pieces are those of the opponent's pieces.
+					if own {
+						weights[KING_RELATIVE_INDEX + i] = value + defender;
+						weights[OPPONENT_KING_RELATIVE_INDEX + i] = -value - defender;
+					} else {
+						weights[KING_RELATIVE_INDEX + i] = -value - attacker;
+						weights[OPPONENT_KING_RELATIVE_INDEX + i] = value + attacker;
+					}
+				}
+			}
+		}
+
+		for &kind in &MOCHIGOMA_KINDS {
+			let c = MOCHIGOMA_OFFSET_MAP.get(kind as usize + 1).cloned().unwrap_or(MOCHIGOMA_SIZE) - MOCHIGOMA_OFFSET_MAP[kind as usize];
+
+			for i in 0..c {
+				weights[KingRelativeInputCreator::mochigoma_index(Teban::Sente,Teban::Sente,kind,i)] = material::HAND_VALUES[kind as usize];
+				weights[KingRelativeInputCreator::mochigoma_index(Teban::Sente,Teban::Gote,kind,i)] = -material::HAND_VALUES[kind as usize];
+			}
+		}
+
+		weights
+	}
+
+	fn dot(input:&Self::Input,weights:&[f32]) -> f32 {
+		material::dot(input,weights)
+	}
+
+	fn dot_diff(input:&Self::DiffInput,weights:&[f32]) -> f32 {
+		material::dot_diff(input,weights)
+	}
+}
+impl KingRelativeInputCreator {
+	fn make_refresh_input(is_self:bool,t:Teban,b:&Banmen,mc:&MochigomaCollections,m:&Move,mover:Teban)
+		-> Result<DiffArr<f32,KING_RELATIVE_INPUTS>,CommonError> {
+		let (next,nmc,_) = Rule::apply_move_none_check(&State::new(b.clone()),mover,mc,m.to_applied_move());
+
+		let prev = KingRelativeInputCreator::active_features(!is_self,t,b,mc);
+		let next = KingRelativeInputCreator::active_features(is_self,t,next.get_banmen(),&nmc);
+
+		let mut d = DiffArr::new();
+
+		let mut prev = prev.into_iter().peekable();
+		let mut next = next.into_iter().peekable();
+
+		loop {
+			match (prev.peek().cloned(),next.peek().cloned()) {
+				(Some(p),Some(n)) if p == n => {
+					prev.next();
+					next.next();
+				},
+				(Some(p),Some(n)) if p < n => {
+					d.push(p,-1.)?;
+					prev.next();
+				},
+				(_,Some(n)) => {
+					d.push(n,1.)?;
+					next.next();
+				},
+				(Some(p),None) => {
+					d.push(p,-1.)?;
+					prev.next();
+				},
+				(None,None) => {
+					break;
+				}
+			}
+		}
+
+		Ok(d)
+	}
+
+	/// Indexes of the inputs set to 1, in ascending order.
+	fn active_features(is_self:bool,t:Teban,b:&Banmen,mc:&MochigomaCollections) -> Vec<usize> {
+		let mut features = Vec::with_capacity(128);
+
+		features.push(if is_self {
+			SELF_TEBAN_INDEX
+		} else {
+			OPPONENT_TEBAN_INDEX
+		});
+
+		let kings = KingRelativeInputCreator::kings(t,b);
+
+		if let Some((x,y)) = kings.0 {
+			features.push(OU_INDEX + y * 9 + x);
+		}
+
+		if let Some((x,y)) = kings.1 {
+			features.push(OPPONENT_OU_INDEX + y * 9 + x);
+		}
+
+		let &Banmen(ref kinds) = b;
+
+		for y in 0..9 {
+			for x in 0..9 {
+				let kind = kinds[y][x];
+
+				if kind != KomaKind::Blank && !KingRelativeInputCreator::is_ou(kind) {
+					for &i in KingRelativeInputCreator::relative_indexes(t,&kings,kind,x,y).iter().flatten() {
+						features.push(i);
+					}
+				}
+			}
+		}
+
+		for &owner in [t,t.opposite()].iter() {
+			for &kind in &MOCHIGOMA_KINDS {
+				let c = KingRelativeInputCreator::mochigoma_count(owner,kind,mc);
+
+				for i in 0..c {
+					features.push(KingRelativeInputCreator::mochigoma_index(t,owner,kind,i));
+				}
+			}
+		}
+
+		features.sort();
+
+		features
+	}
+
+	fn is_ou(kind:KomaKind) -> bool {
+		kind == KomaKind::SOu || kind == KomaKind::GOu
+	}
+
+	fn owner(kind:KomaKind) -> Teban {
+		if kind < KomaKind::GFu {
+			Teban::Sente
+		} else {
+			Teban::Gote
+		}
+	}
+
+	/// Coordinates seen from t.
+	fn local(t:Teban,x:usize,y:usize) -> (usize,usize) {
+		match t {
+			Teban::Sente => (x,y),
+			Teban::Gote => (8-x,8-y),
+		}
+	}
+
+	/// Positions of the own king and the opponent king seen from t.
+	fn kings(t:Teban,b:&Banmen) -> (Option<(usize,usize)>,Option<(usize,usize)>) {
+		let &Banmen(ref kinds) = b;
+
+		let mut kings = (None,None);
+
+		for y in 0..9 {
+			for x in 0..9 {
+				let kind = kinds[y][x];
+
+				if KingRelativeInputCreator::is_ou(kind) {
+					if KingRelativeInputCreator::owner(kind) == t {
+						kings.0 = Some(KingRelativeInputCreator::local(t,x,y));
+					} else {
+						kings.1 = Some(KingRelativeInputCreator::local(t,x,y));
+					}
+				}
+			}
+		}
+
+		kings
+	}
+
+	fn relative_indexes(t:Teban,kings:&(Option<(usize,usize)>,Option<(usize,usize)>),kind:KomaKind,x:usize,y:usize) -> [Option<usize>; 2] {
+		let k = kind as usize % 14;
+
+		if kind == KomaKind::Blank || k == KomaKind::SOu as usize {
+			return [None,None];
+		}
+
+		let class = if k < KomaKind::SOu as usize {
+			k
+		} else {
+			k - 1
+		};
+
+		let (class,opponent_class) = if KingRelativeInputCreator::owner(kind) == t {
+			(class,class + PIECE_CLASSES / 2)
+		} else {
+			(class + PIECE_CLASSES / 2,class)
+		};
+
+		let (x,y) = KingRelativeInputCreator::local(t,x,y);
+
+		[
+			kings.0.map(|(kx,ky)| {
+				KING_RELATIVE_INDEX + class * RELATIVE_SQUARES + (y + 8 - ky) * 17 + (x + 8 - kx)
+			}),
+			kings.1.map(|(kx,ky)| {
+				OPPONENT_KING_RELATIVE_INDEX + opponent_class * RELATIVE_SQUARES + (ky + 8 - y) * 17 + (kx + 8 - x)
+			})
+		]
+	}
+
+	fn mochigoma_count(owner:Teban,kind:MochigomaKind,mc:&MochigomaCollections) -> usize {
+		match mc {
+			&MochigomaCollections::Pair(ref ms,ref mg) => {
+				match owner {
+					Teban::Sente => ms.get(kind),
+					Teban::Gote => mg.get(kind),
+				}
+			},
+			&MochigomaCollections::Empty => 0,
+		}
+	}
+
+	fn mochigoma_index(t:Teban,owner:Teban,kind:MochigomaKind,i:usize) -> usize {
+		let offset = if owner == t {
+			MOCHIGOMA_INDEX
+		} else {
+			OPPONENT_MOCHIGOMA_INDEX
+		};
+
+		offset + MOCHIGOMA_OFFSET_MAP[kind as usize] + i
+	}
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use rand::{Rng, SeedableRng};
+	use rand_xorshift::XorShiftRng;
+
+	use perft::Perft;
+
+	const POSITIONS:[&'static str; 3] = [
+		"startpos",
+		"startpos moves 7g7f 3c3d 8h2b+ 3a2b",
+		"sfen 4k4/9/4P4/9/9/9/9/9/4K4 b RBGSNLPrb3g3s3n3l16p 1",
+	];
+
+	#[test]
+	fn diff_input_equals_the_input_recomputed_after_the_move() {
+		let perft = Perft::new(true);
+
+		let mut rnd = XorShiftRng::from_seed([3; 16]);
+
+		let (mut king_moves,mut captures,mut drops,mut promotions) = (0,0,0,0);
+
+		for position in POSITIONS.iter() {
+			for _ in 0..8 {
+				let (mut teban,mut state,mut mc) = Perft::parse_position(position).unwrap();
+
+				for _ in 0..150 {
+					let mvs = perft.moves(teban,&state,&mc);
+
+					if mvs.len() == 0 {
+						break;
+					}
+
+					let m = mvs[rnd.gen_range(0..mvs.len())].to_move();
+
+					let &Banmen(ref kinds) = state.get_banmen();
+
+					match m {
+						Move::To(KomaSrcPosition(sx,sy),KomaDstToPosition(dx,dy,n)) => {
+							let sk = kinds[sy as usize - 1][9 - sx as usize];
+
+							if KingRelativeInputCreator::is_ou(sk) {
+								king_moves += 1;
+							}
+
+							if kinds[dy as usize - 1][9 - dx as usize] != KomaKind::Blank {
+								captures += 1;
+							}
+
+							if n {
+								promotions += 1;
+							}
+						},
+						Move::Put(_,_) => {
+							drops += 1;
+						}
+					}
+
+					let (next,nmc,_) = Rule::apply_move_none_check(&state,teban,&mc,m.to_applied_move());
+
+					for &t in [teban,teban.opposite()].iter() {
+						let mut input = KingRelativeInputCreator::make_input(t == teban,t,state.get_banmen(),&mc);
+						let d = KingRelativeInputCreator::make_diff_input(t != teban,t,state.get_banmen(),&mc,&m,teban).unwrap();
+
+						for &(i,v) in d.iter() {
+							input[i] += v;
+						}
+
+						let expected = KingRelativeInputCreator::make_input(t != teban,t,next.get_banmen(),&nmc);
+
+						assert!(input.iter().zip(expected.iter()).all(|(a,e)| a == e),"{} {:?}",position,m);
+					}
+
+					state = next;
+					mc = nmc;
+					teban = teban.opposite();
+				}
+			}
+		}
+
+		assert!(king_moves > 0 && captures > 0 && drops > 0 && promotions > 0,
+				"king moves {}, captures {}, drops {}, promotions {}",king_moves,captures,drops,promotions);
+	}
+}
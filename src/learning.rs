@@ -1,4 +1,5 @@
 use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::thread;
 use std::sync::Mutex;
 use std::sync::Arc;
@@ -22,19 +23,16 @@ use csaparser::EndState;
 
 use error::ApplicationError;
 use error::CommonError;
-use nn::{Trainer};
+use nn::{Trainer, TrainerNetwork};
+use validation::Validation;
+use records::{RecordFormat, RecordReader, ShuffleBuffer};
+use pipeline::DecodePipeline;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::io::{BufReader, Read, BufWriter};
 use std::fs::{DirEntry, File, OpenOptions};
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
-use nncombinator::arr::{Arr, VecArr};
-use nncombinator::device::DeviceGpu;
-use nncombinator::layer::{BatchForwardBase, BatchTrain, ForwardAll};
-use nncombinator::persistence::{BinFilePersistence, Linear, Persistence};
-use rand::prelude::SliceRandom;
-use rand::{Rng, SeedableRng};
-use rand_xorshift::XorShiftRng;
+use nncombinator::device::Device;
 use usiagent::output::USIStdErrorWriter;
 
 #[derive(Debug,Deserialize,Serialize)]
@@ -101,18 +99,69 @@ impl<'a,P: AsRef<Path>> CheckPointWriter<P> {
 		}
 	}
 }
-pub struct Learnener<NN>
-	where NN: ForwardAll<Input=Arr<f32,2517>,Output=Arr<f32,1>> +
-			  BatchForwardBase<BatchInput=VecArr<f32,Arr<f32,2517>>,BatchOutput=VecArr<f32,Arr<f32,1>>> +
-			  BatchTrain<f32,DeviceGpu<f32>> + Persistence<f32,BinFilePersistence<f32>,Linear> {
-	nn:PhantomData<NN>}
-impl<NN> Learnener<NN>
-	where NN: ForwardAll<Input=Arr<f32,2517>,Output=Arr<f32,1>> +
-			  BatchForwardBase<BatchInput=VecArr<f32,Arr<f32,2517>>,BatchOutput=VecArr<f32,Arr<f32,1>>> +
-			  BatchTrain<f32,DeviceGpu<f32>> + Persistence<f32,BinFilePersistence<f32>,Linear>{
-	pub fn new() -> Learnener<NN> {
+/// Whether the game of the csa file is used for the learning.
+///
+/// The game has to end with the resignation or the checkmate, and one of the players has to be rated lowerrate or higher.
+pub fn is_learning_target(p:&CsaData,lowerrate:f64) -> bool {
+	match p.end_state {
+		Some(EndState::Toryo) | Some(EndState::Tsumi) => (),
+		_ => {
+			return false;
+		}
+	}
+
+	p.comments.iter().any(|c| {
+		if !c.starts_with("white_rate:") && !c.starts_with("black_rate:") {
+			return false;
+		}
+
+		let c = c.split(':').collect::<Vec<&str>>();
+
+		if c.len() != 3 {
+			false
+		} else {
+			let rate:f64 = match c[2].parse() {
+				Err(_) => {
+					return false;
+				},
+				Ok(rate) => rate,
+			};
+
+			rate >= lowerrate
+		}
+	})
+}
+/// Moves of the game of the csa file.
+pub fn csa_moves(p:&CsaData) -> Vec<AppliedMove> {
+	p.moves.iter().fold(Vec::new(),|mut mvs,m| match *m {
+		CsaMove::Move(m,_) => {
+			mvs.push(m.to_applied_move());
+			mvs
+		},
+		_ => {
+			mvs
+		}
+	})
+}
+pub struct Learnener<NN,D>
+	where NN: TrainerNetwork<D>,
+		  D: Device<f32> {
+	validation_size:usize,
+	validation_interval:usize,
+	nn:PhantomData<NN>,
+	device:PhantomData<D>,
+}
+impl<NN,D> Learnener<NN,D>
+	where NN: TrainerNetwork<D>,
+		  D: Device<f32> {
+	/// validation_size is the number of the positions of the held-out set (0 for all),
+	/// and validation_interval is the number of the steps (games for csa, batches otherwise) between the validations. (0 for only at the end)
+	pub fn new(validation_size:usize,validation_interval:usize) -> Learnener<NN,D> {
 		Learnener {
-			nn:PhantomData::<NN>
+			validation_size:validation_size,
+			validation_interval:validation_interval,
+			nn:PhantomData::<NN>,
+			device:PhantomData::<D>
 		}
 	}
 
@@ -208,7 +257,7 @@ impl<NN> Learnener<NN>
 							 kifudir:String,
 							 lowerrate:f64,
 							 maxepoch: usize,
-							 evalutor: Trainer<NN>,
+							 evalutor: Trainer<NN,D>,
 							 on_error_handler_arc:Arc<Mutex<OnErrorHandler<FileLogger>>>) -> Result<(),ApplicationError> {
 		let system_event_queue_arc:Arc<Mutex<EventQueue<SystemEvent,SystemEventKind>>> = Arc::new(Mutex::new(EventQueue::new()));
 		let user_event_queue:Arc<Mutex<EventQueue<UserEvent,UserEventKind>>> = Arc::new(Mutex::new(EventQueue::new()));
@@ -223,6 +272,8 @@ impl<NN> Learnener<NN>
 
 		let mut evalutor = evalutor;
 
+		evalutor.set_source(format!("csa {}",kifudir));
+
 		print!("learning start... kifudir = {}\n", kifudir);
 
 		let on_error_handler = on_error_handler_arc.clone();
@@ -252,13 +303,14 @@ impl<NN> Learnener<NN>
 		let mut skip_files = checkpoint.is_some();
 		let mut skip_items = checkpoint.is_some();
 
-		let mut rng = rand::thread_rng();
-		let mut rng = XorShiftRng::from_seed(rng.gen());
-
 		let extend = RefCell::new(0);
 
 		let mut item_count = 0;
 
+		let historys = self.load_csa_tests(&kifudir,lowerrate)?;
+
+		let mut step_count = 0;
+
 		'epochs: for _ in (0..).take_while(|&c| c < maxepoch + *extend.borrow()) {
 			let mut paths = fs::read_dir(Path::new(&kifudir)
 				.join("training"))?.into_iter()
@@ -308,44 +360,11 @@ impl<NN> Learnener<NN>
 							continue;
 						}
 					}
-					match p.end_state {
-						Some(EndState::Toryo) | Some(EndState::Tsumi) => (),
-						_ => {
-							continue;
-						}
-					}
-
-					if !p.comments.iter().any(|c| {
-						if !c.starts_with("white_rate:") && !c.starts_with("black_rate:") {
-							return false;
-						}
-
-						let c = c.split(':').collect::<Vec<&str>>();
-
-						if c.len() != 3 {
-							false
-						} else {
-							let rate: f64 = match c[2].parse() {
-								Err(_) => {
-									return false;
-								},
-								Ok(rate) => rate,
-							};
-
-							rate >= lowerrate
-						}
-					}) {
+					if !is_learning_target(&p,lowerrate) {
 						continue;
 					}
-					let m = p.moves.iter().fold(Vec::new(), |mut mvs, m| match *m {
-						CsaMove::Move(m, _) => {
-							mvs.push(m);
-							mvs
-						},
-						_ => {
-							mvs
-						}
-					});
+
+					let m = csa_moves(&p);
 					let teban = p.teban_at_start;
 					let banmen = p.initial_position;
 					let state = State::new(banmen);
@@ -355,9 +374,7 @@ impl<NN> Learnener<NN>
 
 					let (teban, _, _, history) = Rule::apply_moves_with_callback(state,
 																				 teban,
-																				 mc, &m.into_iter().map(|m| {
-							m.to_applied_move()
-						}).collect::<Vec<AppliedMove>>(),
+																				 mc, &m,
 																				 history,
 																				 |_, banmen, mc, _, _, history| {
 																					 let mut history = history;
@@ -378,12 +395,18 @@ impl<NN> Learnener<NN>
 								"An error occurred while learning the neural network."
 							)));
 						},
-						Ok((msa,msb)) => {
-							println!("error_total: {}, {}", msa, msb);
+						Ok(losses) => {
+							println!("error_total: {}", losses.iter().map(|l| l.to_string()).collect::<Vec<String>>().join(", "));
 						}
 					};
 
 					processed_count += history_count;
+					step_count += 1;
+
+					if self.validation_interval > 0 && step_count % self.validation_interval == 0 {
+						print!("{}局学習後の検証結果\n",step_count);
+						self.validate_csa(&mut evalutor,&historys)?;
+					}
 
 					system_event_dispatcher.dispatch_events(&(), &*system_event_queue)?;
 
@@ -411,205 +434,170 @@ impl<NN> Learnener<NN>
 
 			skip_files = false;
 			skip_items = false;
+
+			if processed_count > 0 {
+				evalutor.add_epoch();
+				evalutor.save()?;
+			}
 		}
 
 		if notify_run_test_arc.load(Ordering::Acquire) {
-			let mut historys:Vec<(Teban,GameEndState,(Banmen,MochigomaCollections,u64,u64))> = Vec::new();
+			print!("検証結果\n");
+			self.validate_csa(&mut evalutor,&historys)?;
+		}
 
+		print!("{}件の棋譜を学習しました。\n", processed_count);
 
-			let mut paths = fs::read_dir(Path::new(&kifudir)
-				.join("tests"))?.into_iter()
-				.collect::<Vec<Result<DirEntry,_>>>();
+		Ok(())
+	}
 
-			paths.sort_by(Self::cmp);
+	fn load_csa_tests(&self,kifudir:&str,lowerrate:f64)
+		-> Result<Vec<(Teban,GameEndState,(Banmen,MochigomaCollections,u64,u64))>,ApplicationError> {
+		let mut historys:Vec<(Teban,GameEndState,(Banmen,MochigomaCollections,u64,u64))> = Vec::new();
 
-			'test_files: for path in paths {
-				let path = path?.path();
+		if !Path::new(kifudir).join("tests").exists() {
+			return Ok(historys);
+		}
 
-				if !path.as_path().extension().map(|e| e == "csa").unwrap_or(false) {
-					continue;
-				}
+		let mut paths = fs::read_dir(Path::new(&kifudir)
+			.join("tests"))?.into_iter()
+			.collect::<Vec<Result<DirEntry,_>>>();
 
-				let parsed:Vec<CsaData> = CsaParser::new(CsaFileStream::new(path)?).parse()?;
+		paths.sort_by(Self::cmp);
 
-				for p in parsed.into_iter() {
-					match p.end_state {
-						Some(EndState::Toryo) | Some(EndState::Tsumi) => (),
-						_ => {
-							continue;
-						}
-					}
+		'test_files: for path in paths {
+			let path = path?.path();
 
-					if !p.comments.iter().any(|c| {
-						if !c.starts_with("white_rate:") && !c.starts_with("black_rate:") {
-							return false;
-						}
+			if !path.as_path().extension().map(|e| e == "csa").unwrap_or(false) {
+				continue;
+			}
 
-						let c = c.split(':').collect::<Vec<&str>>();
+			let parsed:Vec<CsaData> = CsaParser::new(CsaFileStream::new(path)?).parse()?;
 
-						if c.len() != 3 {
-							false
-						} else {
-							let rate:f64 = match c[2].parse() {
-								Err(_) => {
-									return false;
-								},
-								Ok(rate) => rate,
-							};
+			for p in parsed.into_iter() {
+				if !is_learning_target(&p,lowerrate) {
+					continue;
+				}
 
-							rate >= lowerrate
-						}
-					}) {
-						continue;
-					}
-					let m = p.moves.iter().fold(Vec::new(),|mut mvs,m| match *m {
-						CsaMove::Move(m,_) => {
-							mvs.push(m);
-							mvs
-						},
-						_ => {
-							mvs
-						}
+				let m = csa_moves(&p);
+				let teban = p.teban_at_start;
+				let banmen = p.initial_position;
+				let state = State::new(banmen);
+				let mc = p.initial_mochigoma;
+				let history = Vec::new();
+
+				let (mut teban,_,_,history) = Rule::apply_moves_with_callback(state,
+																		   teban,
+																		   mc,&m,
+					history,
+						|_,banmen,mc,_,_,history| {
+						let mut history = history;
+						history.push((banmen.clone(),mc.clone(),0,0));
+						history
 					});
-					let teban = p.teban_at_start;
-					let banmen = p.initial_position;
-					let state = State::new(banmen);
-					let mc = p.initial_mochigoma;
-					let history = Vec::new();
 
-					let (mut teban,_,_,history) = Rule::apply_moves_with_callback(state,
-																			   teban,
-																			   mc,&m.into_iter().map(|m| {
-							m.to_applied_move()
-						}).collect::<Vec<AppliedMove>>(),
-						history,
-							|_,banmen,mc,_,_,history| {
-							let mut history = history;
-							history.push((banmen.clone(),mc.clone(),0,0));
-							history
-						});
-
-					let mut s = GameEndState::Win;
-
-					for h in history.into_iter() {
-						historys.push((teban,s.clone(),h));
-						teban = teban.opposite();
-
-						if s == GameEndState::Win {
-							s = GameEndState::Lose;
-						} else {
-							s = GameEndState::Win;
-						}
-					}
+				let mut s = GameEndState::Win;
+
+				for h in history.into_iter() {
+					historys.push((teban,s.clone(),h));
+					teban = teban.opposite();
 
-					if historys.len() >= 10000 {
-						break 'test_files;
+					if s == GameEndState::Win {
+						s = GameEndState::Lose;
+					} else {
+						s = GameEndState::Win;
 					}
 				}
-			}
-
-			historys.shuffle(&mut rng);
 
-			let mut successed = 0;
-			let mut count = 0;
+				if self.validation_size > 0 && historys.len() >= self.validation_size {
+					historys.truncate(self.validation_size);
+					break 'test_files;
+				}
+			}
+		}
 
-			for (teban,s,kyokumen) in historys.iter().take(100) {
-				let score = evalutor.test_by_csa(*teban, kyokumen)?;
+		Ok(historys)
+	}
 
-				let success = match s {
-					GameEndState::Win => {
-						score >= 0.0
-					},
-					_ => {
-						score < 0.0
-					}
-				};
+	fn validate_csa(&self,evalutor:&mut Trainer<NN,D>,
+					historys:&[(Teban,GameEndState,(Banmen,MochigomaCollections,u64,u64))]) -> Result<(),ApplicationError> {
+		let mut validation = Validation::new(evalutor.eval_scale());
 
-				if success {
-					successed += 1;
-					println!("評価値{} 正解!",score * (1 << 29) as f32);
-				} else {
-					println!("評価値{} 不正解...",score * (1 << 29) as f32);
-				}
+		for &(teban,ref s,ref kyokumen) in historys.iter() {
+			validation.add(evalutor.test_by_csa(teban,kyokumen,s)?);
+		}
 
-				count += 1;
-			}
+		validation.print();
+		print!("学習率: {}\n",evalutor.learning_rate());
 
-			println!("正解率 {}%",successed as f32 / count as f32 * 100.);
+		if !historys.is_empty() {
+			evalutor.on_validation(&validation)?;
 		}
 
-		print!("{}件の棋譜を学習しました。\n", processed_count);
-
 		Ok(())
 	}
 
 	pub fn learning_from_yaneuraou_bin(&mut self, kifudir:String,
-									   evalutor: Trainer<NN>,
+									   evalutor: Trainer<NN,D>,
 									   on_error_handler_arc:Arc<Mutex<OnErrorHandler<FileLogger>>>,
-									   learn_sfen_read_size:usize,
+									   shuffle_buffer_size:usize,
+									   decode_threads:usize,
 									   learn_batch_size:usize,
 									   save_batch_count:usize,
 									   maxepoch:usize) -> Result<(),ApplicationError> {
+		let mut evalutor = evalutor;
+
+		evalutor.set_source(format!("yaneuraou {}",kifudir));
+
 		self.learning_batch(kifudir,
-							"bin",
-							40,
+							RecordFormat::PackedSfen,
 							evalutor,
 							on_error_handler_arc,
-							learn_sfen_read_size,
+							shuffle_buffer_size,
+							decode_threads,
 							learn_batch_size,
 							save_batch_count,
-							maxepoch,
-							Self::learning_from_yaneuraou_bin_batch,
-							|evalutor,packed| {
-								evalutor.test_by_packed_sfens(packed)
-							})
+							maxepoch)
 
 	}
 
 	pub fn learning_from_hcpe(&mut self, kifudir:String,
-									   evalutor: Trainer<NN>,
+									   evalutor: Trainer<NN,D>,
 							  		   on_error_handler_arc:Arc<Mutex<OnErrorHandler<FileLogger>>>,
-									   learn_sfen_read_size:usize,
+									   shuffle_buffer_size:usize,
+							  		   decode_threads:usize,
 									   learn_batch_size:usize,
 							  		   save_batch_count:usize,
 							  		   maxepoch:usize
 	) -> Result<(),ApplicationError> {
+		let mut evalutor = evalutor;
+
+		evalutor.set_source(format!("hcpe {}",kifudir));
+
 		self.learning_batch(kifudir,
-							"hcpe",
-								38,
+							RecordFormat::Hcpe,
 							evalutor,
 							on_error_handler_arc,
-							learn_sfen_read_size,
+							shuffle_buffer_size,
+							decode_threads,
 							learn_batch_size,
 							save_batch_count,
-							maxepoch,
-							Self::learning_from_hcpe_batch,
-							|evalutor,packed| {
-								evalutor.test_by_hcpe(packed)
-							})
+							maxepoch)
 
 	}
 
-	pub fn learning_batch<'a,F>(&mut self,kifudir:String,
-							   ext:&str,
-							   item_size:usize,
-							   evalutor: Trainer<NN>,
+	/// Each batch is decoded by decode_threads threads together, while the previous batch is trained.
+	pub fn learning_batch(&mut self,kifudir:String,
+							   format:RecordFormat,
+							   evalutor: Trainer<NN,D>,
 							   on_error_handler_arc:Arc<Mutex<OnErrorHandler<FileLogger>>>,
-							   learn_sfen_read_size:usize,
+							   shuffle_buffer_size:usize,
+							   decode_threads:usize,
 							   learn_batch_size:usize,
 							   save_batch_count:usize,
-							   maxepoch:usize,
-							   learning_process:fn(
-								   &mut Trainer<NN>,
-								   Vec<Vec<u8>>,
-								   &Arc<Mutex<EventQueue<UserEvent,UserEventKind>>>
-							   ) -> Result<(),ApplicationError>,
-							   mut test_process:F
-	) -> Result<(),ApplicationError>
-		where F: FnMut(&mut Trainer<NN>,Vec<u8>) -> Result<(GameEndState,f32),ApplicationError> {
-
+							   maxepoch:usize
+	) -> Result<(),ApplicationError> {
 		let system_event_queue_arc:Arc<Mutex<EventQueue<SystemEvent,SystemEventKind>>> = Arc::new(Mutex::new(EventQueue::new()));
-		let user_event_queue:Arc<Mutex<EventQueue<UserEvent,UserEventKind>>> = Arc::new(Mutex::new(EventQueue::new()));
 
 		let notify_quit_arc = Arc::new(AtomicBool::new(false));
 
@@ -636,8 +624,6 @@ impl<NN> Learnener<NN>
 
 		let mut processed_count = 0;
 
-		let mut record = Vec::with_capacity(item_size);
-
 		let mut pending_count = 0;
 
 		let checkpoint_path = Path::new(&kifudir).join("checkpoint.toml");
@@ -653,17 +639,35 @@ impl<NN> Learnener<NN>
 		let mut skip_files = checkpoint.is_some();
 		let mut skip_items = checkpoint.is_some();
 
-		let mut rng = rand::thread_rng();
-		let mut rng = XorShiftRng::from_seed(rng.gen());
-
 		let mut current_item = 0;
 
 		let mut item_count = 0;
 
+		let mut read_count = 0;
+
 		let extend = RefCell::new(0);
 
+		let item_size = format.item_size();
+
+		let testdata = self.load_tests(&kifudir,format.ext(),item_size)?;
+
+		let mut step_count = 0;
+
+		let mut pipeline = DecodePipeline::new(evalutor.sample_decoder(),format,decode_threads)?;
+
+		// File name and item number read up to when each pending batch was submitted,
+		// so that the checkpoint does not pass over the batches not trained yet.
+		let mut batch_tags = VecDeque::new();
+
+		let mut trained_filename = current_filename.clone();
+		let mut trained_item = current_item;
+
 		'epochs: for _ in (0..).take_while(|&c| c < maxepoch + *extend.borrow()) {
-			let mut teachers = Vec::with_capacity(learn_sfen_read_size);
+			let mut shuffle_buffer = ShuffleBuffer::new(item_size,shuffle_buffer_size);
+
+			let mut batch = Vec::with_capacity(learn_batch_size * item_size);
+
+			let mut quit = false;
 
 			let mut paths = fs::read_dir(Path::new(&kifudir)
 				.join("training"))?.into_iter()
@@ -671,7 +675,7 @@ impl<NN> Learnener<NN>
 
 			paths.sort_by(Self::cmp);
 
-			for path in paths {
+			'files: for path in paths {
 				let path = path?.path();
 
 				current_filename = path.as_path().file_name().map(|s| {
@@ -690,7 +694,7 @@ impl<NN> Learnener<NN>
 					}
 				}
 
-				if !path.as_path().extension().map(|e| e == ext).unwrap_or(false) {
+				if !path.as_path().extension().map(|e| e == format.ext()).unwrap_or(false) {
 					continue;
 				}
 
@@ -698,283 +702,259 @@ impl<NN> Learnener<NN>
 
 				current_item = 0;
 
-				for b in BufReader::new(File::open(path)?).bytes() {
-					let b = b?;
-
-					record.push(b);
-
-					if record.len() == item_size {
-						item_count += 1;
-						current_item += 1;
-
-						if let Some(ref checkpoint) = checkpoint {
-							if skip_items && current_item < checkpoint.item {
-								record.clear();
-								continue;
-							} else {
-								if skip_items && current_item == checkpoint.item {
-									println!("Processing starts from {}th item of file {}", current_item, &current_filename);
-									skip_items = false;
-									record.clear();
-									continue;
-								}
-							}
-						}
-						teachers.push(record);
-						record = Vec::with_capacity(item_size);
-					} else {
-						continue;
-					}
-
-					if teachers.len() == learn_sfen_read_size {
-						let mut rng = rand::thread_rng();
-						teachers.shuffle(&mut rng);
+				let mut reader = RecordReader::new(&path,item_size)?;
 
-						let mut batch = Vec::with_capacity(learn_batch_size);
+				while let Some(record) = reader.next()? {
+					item_count += 1;
+					current_item += 1;
 
-						let it = teachers.into_iter();
-						teachers = Vec::with_capacity(learn_sfen_read_size);
+					if let Some(ref checkpoint) = checkpoint {
+						if skip_items && current_item < checkpoint.item {
+							continue;
+						} else if skip_items && current_item == checkpoint.item {
+							println!("Processing starts from {}th item of file {}", current_item, &current_filename);
+							skip_items = false;
+							continue;
+						}
+					}
 
-						for sfen in it {
-							batch.push(sfen);
+					read_count += 1;
 
-							if batch.len() == learn_batch_size {
-								learning_process(&mut evalutor,
-												 batch,
-												 &user_event_queue)?;
-								pending_count += 1;
+					shuffle_buffer.push(record,&mut batch);
 
-								batch = Vec::with_capacity(learn_batch_size);
-								processed_count += learn_batch_size;
+					if batch.len() == learn_batch_size * item_size {
+						pipeline.submit(batch)?;
+						batch_tags.push_back((current_filename.clone(),current_item));
 
-								self.save(&mut evalutor,
-										  &checkpoint_path,
-										  &current_filename,
-										  current_item,
-										  pending_count >= save_batch_count,
-										  &mut pending_count)?;
-							}
+						batch = Vec::with_capacity(learn_batch_size * item_size);
 
-							system_event_dispatcher.dispatch_events(&(), &*system_event_queue)?;
+						if pipeline.is_full() {
+							processed_count += self.learn_step(&mut evalutor,
+															   &mut pipeline,
+															   format,
+															   &testdata,
+															   &mut step_count)?;
+							pending_count += 1;
 
-							if notify_quit.load(Ordering::Acquire) {
-								break 'epochs;
+							if let Some((filename,item)) = batch_tags.pop_front() {
+								trained_filename = filename;
+								trained_item = item;
 							}
-						}
-
-						let remaing = batch.len();
-
-						if remaing > 0 {
-							learning_process(&mut evalutor,
-											 batch,
-											 &user_event_queue)?;
-							pending_count += 1;
 
 							self.save(&mut evalutor,
 									  &checkpoint_path,
-									  &current_filename,
-									  current_item,
+									  &trained_filename,
+									  trained_item,
 									  pending_count >= save_batch_count,
 									  &mut pending_count)?;
-							processed_count += remaing;
-						}
 
-						system_event_dispatcher.dispatch_events(&(), &*system_event_queue)?;
+							system_event_dispatcher.dispatch_events(&(), &*system_event_queue)?;
 
-						if notify_quit.load(Ordering::Acquire) {
-							break 'epochs;
+							if notify_quit.load(Ordering::Acquire) {
+								quit = true;
+								break 'files;
+							}
 						}
 					}
 				}
 
-				if processed_count == 0 && item_count > 0 {
+				if read_count == 0 && item_count > 0 {
 					*extend.borrow_mut() += 1;
 				}
 
 				skip_files = false;
 				skip_items = false;
-			}
 
-			if record.len() > 0 {
-				return Err(ApplicationError::LearningError(String::from(
-					"The data size of the teacher phase is invalid."
-				)));
-			}
-
-			if !notify_quit.load(Ordering::Acquire) && teachers.len() > 0 {
-				teachers.shuffle(&mut rng);
+				system_event_dispatcher.dispatch_events(&(), &*system_event_queue)?;
 
-				let mut batch = Vec::with_capacity(learn_batch_size);
-
-				for sfen in teachers.into_iter() {
-					batch.push(sfen);
+				if notify_quit.load(Ordering::Acquire) {
+					quit = true;
+					break;
+				}
+			}
 
-					if batch.len() == learn_batch_size {
-						learning_process(&mut evalutor,
-										 batch,
-										 &user_event_queue)?;
+			// The records in the shuffle buffer are counted as read by the checkpoint,
+			// so they are trained before quitting as well.
+			while shuffle_buffer.pop(&mut batch) {
+				if batch.len() == learn_batch_size * item_size || shuffle_buffer.len() == 0 {
+					pipeline.submit(batch)?;
+					batch_tags.push_back((current_filename.clone(),current_item));
+
+					batch = Vec::with_capacity(learn_batch_size * item_size);
+
+					if pipeline.is_full() {
+						processed_count += self.learn_step(&mut evalutor,
+														   &mut pipeline,
+														   format,
+														   &testdata,
+														   &mut step_count)?;
 						pending_count += 1;
 
-						batch = Vec::with_capacity(learn_batch_size);
-						processed_count += learn_batch_size;
+						if let Some((filename,item)) = batch_tags.pop_front() {
+							trained_filename = filename;
+							trained_item = item;
+						}
 
 						self.save(&mut evalutor,
 								  &checkpoint_path,
-								  &current_filename,
-								  current_item,
-								  current_filename != "" && pending_count >= save_batch_count,
+								  &trained_filename,
+								  trained_item,
+								  pending_count >= save_batch_count,
 								  &mut pending_count)?;
-					}
-
-					system_event_dispatcher.dispatch_events(&(), &*system_event_queue)?;
 
-					if notify_quit.load(Ordering::Acquire) {
-						break;
+						system_event_dispatcher.dispatch_events(&(), &*system_event_queue)?;
 					}
 				}
+			}
 
-				let remaing = batch.len();
+			if batch.len() > 0 {
+				pipeline.submit(batch)?;
+				batch_tags.push_back((current_filename.clone(),current_item));
+			}
 
-				if !notify_quit.load(Ordering::Acquire) && remaing > 0 {
-					learning_process(&mut evalutor,
-									 batch,
-									 &user_event_queue)?;
-					pending_count += 1;
+			while pipeline.pending() > 0 {
+				processed_count += self.learn_step(&mut evalutor,
+												   &mut pipeline,
+												   format,
+												   &testdata,
+												   &mut step_count)?;
+				pending_count += 1;
+
+				if let Some((filename,item)) = batch_tags.pop_front() {
+					trained_filename = filename;
+					trained_item = item;
+				}
 
+				if pipeline.pending() > 0 {
 					self.save(&mut evalutor,
 							  &checkpoint_path,
-							  &current_filename,
-							  current_item,
+							  &trained_filename,
+							  trained_item,
 							  pending_count >= save_batch_count,
 							  &mut pending_count)?;
-					processed_count += remaing;
+
+					system_event_dispatcher.dispatch_events(&(), &*system_event_queue)?;
 				}
 			}
 
+			if quit {
+				self.save(&mut evalutor,
+						  &checkpoint_path,
+						  &current_filename,
+						  current_item,
+						  true,
+						  &mut pending_count)?;
+
+				break 'epochs;
+			}
+
+			evalutor.add_epoch();
+
 			self.save(&mut evalutor,
 					  &checkpoint_path,
 					  &current_filename,
 					  current_item,
-					  pending_count > 0,
+					  pending_count > 0 || processed_count > 0,
 					  &mut pending_count)?;
 		}
 
 		if notify_run_test_arc.load(Ordering::Acquire) {
-			let mut testdata = Vec::new();
+			print!("検証結果\n");
+			self.validate(&mut evalutor,&testdata,format)?;
+		}
 
-			let mut paths = fs::read_dir(Path::new(&kifudir)
-				.join("tests"))?.into_iter()
-				.collect::<Vec<Result<DirEntry,_>>>();
+		print!("{}局面を学習しました。\n", processed_count);
 
-			paths.sort_by(Self::cmp);
+		Ok(())
+	}
 
-			'test_files: for path in paths {
-				let path = path?.path();
+	/// Records of the held-out set laid out back to back.
+	fn load_tests(&self,kifudir:&str,ext:&str,item_size:usize) -> Result<Vec<u8>,ApplicationError> {
+		let mut testdata = Vec::new();
 
-				if !path.as_path().extension().map(|e| e == ext).unwrap_or(false) {
-					continue;
-				}
-
-				print!("{}\n", path.display());
+		if !Path::new(kifudir).join("tests").exists() {
+			return Ok(testdata);
+		}
 
-				for b in BufReader::new(File::open(path)?).bytes() {
-					let b = b?;
+		let mut paths = fs::read_dir(Path::new(kifudir)
+			.join("tests"))?.into_iter()
+			.collect::<Vec<Result<DirEntry,_>>>();
 
-					record.push(b);
+		paths.sort_by(Self::cmp);
 
-					if record.len() == item_size {
-						testdata.push(record);
-						record = Vec::with_capacity(item_size);
-					} else {
-						continue;
-					}
+		'test_files: for path in paths {
+			let path = path?.path();
 
-					if testdata.len() >= 10000 {
-						break 'test_files;
-					}
-				}
+			if !path.as_path().extension().map(|e| e == ext).unwrap_or(false) {
+				continue;
 			}
 
-			testdata.shuffle(&mut rng);
+			print!("{}\n", path.display());
 
-			let mut successed = 0;
-			let mut count = 0;
+			let mut reader = RecordReader::new(&path,item_size)?;
 
-			for packed in testdata.into_iter().take(100) {
-				let (s,score) = test_process(&mut evalutor,packed)?;
+			while let Some(record) = reader.next()? {
+				testdata.extend_from_slice(record);
 
-				let success = match s {
-					GameEndState::Win => {
-						score >= 0.0
-					},
-					_ => {
-						score < 0.0
-					}
-				};
-
-				match s {
-					GameEndState::Win => println!("結果　勝ち"),
-					GameEndState::Lose => println!("結果　負け"),
-					_ => println!("結果　引き分け")
-				};
-
-				if success {
-					successed += 1;
-					println!("評価値{} 正解!",score * (1 << 29) as f32);
-				} else {
-					println!("評価値{} 不正解...",score * (1 << 29) as f32);
+				if self.validation_size > 0 && testdata.len() >= self.validation_size * item_size {
+					break 'test_files;
 				}
-
-				count += 1;
 			}
-
-			println!("正解率 {}%",successed as f32 / count as f32 * 100.);
 		}
 
-		print!("{}局面を学習しました。\n", processed_count);
-
-		Ok(())
+		Ok(testdata)
 	}
 
-	fn learning_from_yaneuraou_bin_batch(evalutor:&mut Trainer<NN>,
-										 batch:Vec<Vec<u8>>,
-										 user_event_queue:&Arc<Mutex<EventQueue<UserEvent,UserEventKind>>>
-	) -> Result<(),ApplicationError> {
-		match evalutor.learning_by_packed_sfens(
-			batch,
-			&*user_event_queue) {
+	/// Trains the oldest batch decoded by the pipeline and returns the number of the positions of the batch.
+	fn learn_step(&self,evalutor:&mut Trainer<NN,D>,
+				  pipeline:&mut DecodePipeline<NN::Features>,
+				  format:RecordFormat,
+				  testdata:&[u8],
+				  step_count:&mut usize) -> Result<usize,ApplicationError> {
+		let (positions,samples) = match pipeline.next()? {
+			Some(batch) => batch,
+			None => return Ok(0),
+		};
+
+		match evalutor.learning_by_samples(positions,samples) {
 			Err(e) => {
 				return Err(ApplicationError::LearningError(format!(
 					"An error occurred while learning the neural network. {}",e
 				)));
 			},
-			Ok((msa,msb)) => {
-				println!("error_total: {}, {}",msa,msb);
-				Ok(())
+			Ok(losses) => {
+				println!("error_total: {}",losses.iter().map(|l| l.to_string()).collect::<Vec<String>>().join(", "));
 			}
 		}
+
+		*step_count += 1;
+
+		if self.validation_interval > 0 && *step_count % self.validation_interval == 0 {
+			print!("{}バッチ学習後の検証結果\n",*step_count);
+			self.validate(evalutor,testdata,format)?;
+		}
+
+		Ok(positions)
 	}
 
-	fn learning_from_hcpe_batch(evalutor: &mut Trainer<NN>,
-								batch:Vec<Vec<u8>>,
-								user_event_queue:&Arc<Mutex<EventQueue<UserEvent,UserEventKind>>>
-	) -> Result<(),ApplicationError> {
-		match evalutor.learning_by_hcpe(
-			batch,
-			 &*user_event_queue) {
-			Err(e) => {
-				return Err(ApplicationError::LearningError(format!(
-					"An error occurred while learning the neural network. {}",e
-				)));
-			},
-			Ok((msa,msb)) => {
-				println!("error_total: {}, {}",msa,msb);
-				Ok(())
-			}
+	fn validate(&self,evalutor:&mut Trainer<NN,D>,testdata:&[u8],format:RecordFormat) -> Result<(),ApplicationError> {
+		let mut validation = Validation::new(evalutor.eval_scale());
+
+		for record in testdata.chunks(format.item_size()) {
+			validation.add(evalutor.test_by_record(format,record)?);
+		}
+
+		validation.print();
+		print!("学習率: {}\n",evalutor.learning_rate());
+
+		if !testdata.is_empty() {
+			evalutor.on_validation(&validation)?;
 		}
+
+		Ok(())
 	}
 
-	fn save(&self,evalutor: &mut Trainer<NN>,
+	fn save(&self,evalutor: &mut Trainer<NN,D>,
 							 checkpoint_path:&PathBuf,
 							 current_filename:&str,
 							 current_item:usize,
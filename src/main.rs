@@ -17,7 +17,25 @@ pub mod player;
 pub mod solver;
 pub mod error;
 pub mod nn;
+pub mod features;
 pub mod learning;
+pub mod perft;
+pub mod debug;
+pub mod trace;
+pub mod model;
+pub mod quantized;
+pub mod kingrelative;
+pub mod material;
+pub mod validation;
+pub mod optimizer;
+pub mod metrics;
+pub mod records;
+pub mod pipeline;
+pub mod reinforcement;
+pub mod gensfen;
+pub mod csaconvert;
+pub mod evalcache;
+pub mod evalscale;
 
 use std::env;
 use std::io::{ Write, BufReader, Read, BufRead };
@@ -30,7 +48,7 @@ use rand::Rng;
 use rand::SeedableRng;
 use rand_xorshift::XorShiftRng;
 
-use getopts::Options;
+use getopts::{Options, Matches};
 
 use usiagent::{OnErrorHandler, UsiAgent};
 use usiagent::selfmatch::*;
@@ -42,21 +60,55 @@ use usiagent::protocol::*;
 use usiagent::error::*;
 use usiagent::logger::FileLogger;
 use usiagent::player::*;
+use usiagent::input::USIStdInputReader;
 
 use player::NNShogiPlayer;
 use error::ApplicationError;
 use learning::Learnener;
-use nn::{IntelligenceCreator, TrainerCreator};
+use nn::{Architecture, Augmentation, DEFAULT_EVAL_SCALE, DRAW_VALUE, Ensemble, EnsembleTargets, Intelligence, IntelligenceCreator, KingRelativeArchitecture, Network, StandardArchitecture, SwishArchitecture, Trainer, TrainerCreator, TrainerNetwork, WideArchitecture};
+use validation::{VALIDATION_INTERVAL, VALIDATION_SIZE};
+use metrics::{METRICS_LOG, MetricsSummarizer};
+use records::{HCPE_SIZE, PACKED_SFEN_SIZE, RecordFormat};
+use reinforcement::{ReinforcementLoop, ReinforcementSettings};
+use gensfen::{GensfenSettings, SfenGenerator};
+use csaconvert::CsaConverter;
+use optimizer::{LrScheduler, LrScheduleSettings, OptimizerSettings, TrainerOptimizer};
+use nncombinator::device::Device;
+use perft::Perft;
+use debug::DebugInputReader;
+use trace::TraceSummarizer;
+use model::ModelInspector;
+use quantized::{QuantizedNetwork, Quantizer};
+use evalscale::EvalScaleFitter;
 
 const LEAN_SFEN_READ_SIZE:usize = 1000 * 1000 * 10;
 const LEAN_BATCH_SIZE:usize = 256;
+const LEAN_MAX_THREADS:usize = 1;
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
 	max_threads:Option<u32>,
+	learn_max_threads:Option<usize>,
 	learn_sfen_read_size:Option<usize>,
+	learn_shuffle_buffer_mb:Option<usize>,
 	learn_batch_size:Option<usize>,
+	learn_device:Option<String>,
+	architecture:Option<String>,
+	quantized:Option<bool>,
 	save_batch_count:Option<usize>,
+	validation_size:Option<usize>,
+	validation_interval:Option<usize>,
+	metrics_log:Option<String>,
+	learn_mirror:Option<String>,
+	eval_scale:Option<f32>,
+	learn_lambda:Option<f32>,
+	teacher_eval_scale:Option<f32>,
+	resign_value:Option<u32>,
+	draw_value:Option<i32>,
+	material_evaluation:Option<bool>,
+	ensemble_files:Option<Vec<String>>,
+	ensemble_weights:Option<Vec<f32>>,
+	ensemble_targets:Option<String>,
 	base_depth:Option<u32>,
 	max_depth:Option<u32>,
 	max_ply:Option<u32>,
@@ -70,6 +122,10 @@ pub struct Config {
 	number_of_games:Option<u32>,
 	silent:bool,
 	initial_position:Option<InitialPositionKifu>,
+	optimizer:Option<OptimizerSettings>,
+	lr_schedule:Option<LrScheduleSettings>,
+	reinforcement:Option<ReinforcementSettings>,
+	gensfen:Option<GensfenSettings>,
 	bias_shake_shake_with_kifu:bool
 }
 #[derive(Debug, Deserialize)]
@@ -134,6 +190,18 @@ fn run() -> Result<(),ApplicationError> {
 	opts.optflag("", "yaneuraou", "YaneuraOu format teacher phase.");
 	opts.optflag("", "hcpe", "hcpe format teacher phase.");
 	opts.optopt("e","maxepoch", "Number of epochs in batch learning.","number of epoch");
+	opts.optopt("", "device", "Device used for learning.", "cpu or gpu.");
+	opts.optopt("", "perft", "Count the leaf nodes of move generation.", "number of depth");
+	opts.optopt("", "position", "Position of perft.", "startpos or sfen string.");
+	opts.optflag("", "pseudolegal", "Perft counts the moves expanded by the search as they are.");
+	opts.optopt("", "tracesummary", "Summarize the search trace file.", "path string.");
+	opts.optopt("", "metricssummary", "Summarize the recent trends of the training metrics log.", "path string.");
+	opts.optopt("", "inspect", "Show the information of the model file.", "path string.");
+	opts.optflag("", "quantize", "Convert the model to the quantized model.");
+	opts.optopt("", "reinforce", "Self-play reinforcement learning loop.", "number of generations");
+	opts.optopt("", "gensfen", "Generate the teacher phases by the search of the engine.", "path string.");
+	opts.optopt("", "convertcsa", "Convert the csa files in kifudir to the hcpe or YaneuraOu format teacher phases.", "path string.");
+	opts.optflag("", "fitevalscale", "Fit the scale of the evaluation in centipawns to the hcpe teacher data in kifudir.");
 
 	let matches = match opts.parse(&args[1..]) {
 		Ok(m) => m,
@@ -142,137 +210,176 @@ fn run() -> Result<(),ApplicationError> {
 		}
 	};
 
-	if let Some(kifudir) = matches.opt_str("kifudir") {
+	if let Some(depth) = matches.opt_str("perft") {
+		let depth = depth.parse::<u32>()?;
+		let position = matches.opt_str("position").unwrap_or(String::from("startpos"));
+
+		Perft::new(!matches.opt_present("pseudolegal")).run(&position,depth).map(|_| ())
+	} else if let Some(file) = matches.opt_str("tracesummary") {
+		TraceSummarizer::run(&file)
+	} else if let Some(file) = matches.opt_str("metricssummary") {
+		MetricsSummarizer::run(&file)
+	} else if let Some(file) = matches.opt_str("inspect") {
+		ModelInspector::run(&file)
+	} else if let Some(outdir) = matches.opt_str("convertcsa") {
+		let kifudir = matches.opt_str("kifudir").ok_or(ApplicationError::StartupError(String::from(
+			"変換する棋譜のディレクトリを--kifudirで指定してください。"
+		)))?;
+
+		let lowerrate: f64 = matches.opt_str("lowerrate").unwrap_or(String::from("3000.0")).parse()?;
+
+		let format = if matches.opt_present("hcpe") {
+			RecordFormat::Hcpe
+		} else {
+			RecordFormat::PackedSfen
+		};
+
+		CsaConverter::new(lowerrate,format).run(&kifudir,&outdir)
+	} else {
+		let (architecture,quantized,ensemble) = if Path::new("settings.toml").exists() {
+			let config = ConfigLoader::new("settings.toml")?.load()?;
+			let ensemble = create_ensemble(&config)?;
+
+			(config.architecture,config.quantized,ensemble)
+		} else {
+			(None,None,Ensemble::new(default_ensemble_files(),None,EnsembleTargets::Split)?)
+		};
+
+		let architecture = architecture.unwrap_or(String::from(StandardArchitecture::NAME));
+		let quantized = quantized.unwrap_or(false);
+
+		match &*architecture {
+			StandardArchitecture::NAME => run_with_architecture::<StandardArchitecture>(&matches,quantized,ensemble),
+			WideArchitecture::NAME => run_with_architecture::<WideArchitecture>(&matches,quantized,ensemble),
+			SwishArchitecture::NAME => run_with_architecture::<SwishArchitecture>(&matches,quantized,ensemble),
+			KingRelativeArchitecture::NAME => run_with_architecture::<KingRelativeArchitecture>(&matches,quantized,ensemble),
+			_ => {
+				Err(ApplicationError::StartupError(String::from(
+					"ネットワーク構成の指定が不正です。(standard, wide, swish, kingrelativeのいずれかを指定してください)"
+				)))
+			}
+		}
+	}
+}
+fn default_ensemble_files() -> Vec<String> {
+	vec![String::from("nn.a.bin"),String::from("nn.b.bin")]
+}
+fn create_ensemble(config:&Config) -> Result<Ensemble,ApplicationError> {
+	let targets = match config.ensemble_targets {
+		Some(ref targets) => EnsembleTargets::parse(targets)?,
+		None if config.bias_shake_shake_with_kifu => EnsembleTargets::ShakeShake,
+		None => EnsembleTargets::Split,
+	};
+
+	Ensemble::new(config.ensemble_files.clone().unwrap_or_else(default_ensemble_files),config.ensemble_weights.clone(),targets)
+}
+fn run_with_architecture<A>(matches:&Matches,quantized:bool,ensemble:Ensemble) -> Result<(),ApplicationError>
+	where A: Architecture + 'static,
+		  QuantizedNetwork<A::Features>: Network<Features=A::Features> {
+	if matches.opt_present("quantize") {
+		Quantizer::run::<A>(String::from("data"),ensemble)
+	} else if matches.opt_present("fitevalscale") {
+		let kifudir = matches.opt_str("kifudir").ok_or(ApplicationError::StartupError(String::from(
+			"教師データのディレクトリを--kifudirで指定してください。"
+		)))?;
+
+		EvalScaleFitter::run::<A>(String::from("data"),ensemble,kifudir)
+	} else if quantized && matches.opt_present("reinforce") {
+		Err(ApplicationError::StartupError(String::from(
+			"量子化されたモデルでは強化学習を実行できません。"
+		)))
+	} else if quantized {
+		run_with_evalutor::<A,_,_>(matches,ensemble,IntelligenceCreator::create_quantized::<A>)
+	} else {
+		run_with_evalutor::<A,_,_>(matches,ensemble,IntelligenceCreator::create::<A>)
+	}
+}
+fn run_with_evalutor<A,NN,C>(matches:&Matches,ensemble:Ensemble,create:C) -> Result<(),ApplicationError>
+	where A: Architecture + 'static,
+		  NN: Network,
+		  C: Fn(String,Ensemble,bool) -> Result<Intelligence<NN>,ApplicationError> + Copy + Send + 'static {
+	if let Some(generations) = matches.opt_str("reinforce") {
+		let generations = generations.parse::<u32>()?;
+
 		let logger = Arc::new(Mutex::new(FileLogger::new(String::from("logs/log.txt"))?));
 		let on_error_handler = Arc::new(Mutex::new(OnErrorHandler::new(logger)));
 
 		let config = ConfigLoader::new("settings.toml")?.load()?;
 
-		let maxepoch = matches.opt_str("maxepoch").unwrap_or(String::from("1")).parse::<usize>()?;
-
-		let r = if matches.opt_present("yaneuraou") {
-			Learnener::new().learning_from_yaneuraou_bin(kifudir,
-														 TrainerCreator::create(String::from("data"),
-																				String::from("nn.a.bin"),
-																				String::from("nn.b.bin"),config.bias_shake_shake_with_kifu)?,
-														 				on_error_handler.clone(),
-														 config.learn_sfen_read_size.unwrap_or(LEAN_SFEN_READ_SIZE),
-														 config.learn_batch_size.unwrap_or(LEAN_BATCH_SIZE),
-																		config.save_batch_count.unwrap_or(1),
-														 				maxepoch)
-		} else if matches.opt_present("hcpe") {
-				Learnener::new().learning_from_hcpe(kifudir,
-													TrainerCreator::create(String::from("data"),
-																		   String::from("nn.a.bin"),
-																		   String::from("nn.b.bin"),config.bias_shake_shake_with_kifu)?,
-																	 				  on_error_handler.clone(),
-																	 config.learn_sfen_read_size.unwrap_or(LEAN_SFEN_READ_SIZE),
-													config.learn_batch_size.unwrap_or(LEAN_BATCH_SIZE),
-																			config.save_batch_count.unwrap_or(1),
-																			maxepoch)
-		} else {
-			let lowerrate: f64 = matches.opt_str("lowerrate").unwrap_or(String::from("3000.0")).parse()?;
-			Learnener::new().learning_from_csa(kifudir,
-											   lowerrate,
-											   maxepoch,
-
-											   TrainerCreator::create(String::from("data"),
-																	  String::from("nn.a.bin"),
-																	  String::from("nn.b.bin"),config.bias_shake_shake_with_kifu)?,
-																			  on_error_handler.clone())
-		};
+		let silent = matches.opt_present("silent") || config.silent;
+
+		let (base_depth,max_depth) = search_depths(&config,matches)?;
+		let time_limit = game_time_limit(&config,matches)?;
+
+		let mut reinforcement = ReinforcementLoop::new(config.reinforcement.as_ref(),
+													   create,
+													   ensemble.clone(),
+													   player_options(&config,base_depth,max_depth),
+													   time_limit,
+													   silent)?;
+
+		let r = reinforcement.run(generations,|savedir,kifudir| {
+			// The self-play records have no evaluation of the search, so they are trained on the game results only.
+			learning_on_device::<A>(savedir,ensemble.clone(),kifudir,Some(RecordFormat::Hcpe),true,
+									matches,&config,on_error_handler.clone())
+		});
 
 		if let Err(ref e) = r {
 			let _ = on_error_handler.lock().map(|h| h.call(e));
 		}
 
 		r
-	} else if matches.opt_present("l") {
+	} else if let Some(path) = matches.opt_str("gensfen") {
+		let logger = Arc::new(Mutex::new(FileLogger::new(String::from("logs/log.txt"))?));
+		let on_error_handler = Arc::new(Mutex::new(OnErrorHandler::new(logger)));
+
 		let config = ConfigLoader::new("settings.toml")?.load()?;
 
-		let silent =  matches.opt_present("silent") || config.silent;
+		let (base_depth,max_depth) = search_depths(&config,matches)?;
 
-		let base_depth = match config.base_depth {
-			Some(base_depth) => base_depth,
-			_ => {
-				return Err(ApplicationError::StartupError(String::from(
-					"base_depthの値が未設定です。"
-				)))
-			}
+		let format = if matches.opt_present("hcpe") {
+			RecordFormat::Hcpe
+		} else {
+			RecordFormat::PackedSfen
 		};
 
-		let base_depth:u32 = match matches.opt_str("basedepth") {
-			Some(base_depth) => base_depth.parse()?,
-			None => base_depth,
-		};
+		let generator = SfenGenerator::new(config.gensfen.as_ref(),
+										   create,
+										   ensemble,
+										   player_options(&config,base_depth,max_depth))?;
 
-		if base_depth <= 0 {
-			return Err(ApplicationError::StartupError(String::from(
-				"base_depthの設定値が不正です。"
-			)));
-		}
+		generator.run(path,format,on_error_handler)
+	} else if let Some(kifudir) = matches.opt_str("kifudir") {
+		let logger = Arc::new(Mutex::new(FileLogger::new(String::from("logs/log.txt"))?));
+		let on_error_handler = Arc::new(Mutex::new(OnErrorHandler::new(logger)));
 
-		let max_depth = match config.max_depth {
-			Some(max_depth) => max_depth,
-			_ => {
-				return Err(ApplicationError::StartupError(String::from(
-					"base_depthが未設定です。"
-				)));
-			}
-		};
+		let config = ConfigLoader::new("settings.toml")?.load()?;
 
-		let max_depth:u32 = match matches.opt_str("maxdepth") {
-			Some(max_depth) => max_depth.parse()?,
-			None => max_depth,
+		let format = if matches.opt_present("yaneuraou") {
+			Some(RecordFormat::PackedSfen)
+		} else if matches.opt_present("hcpe") {
+			Some(RecordFormat::Hcpe)
+		} else {
+			None
 		};
 
-		if max_depth <= 0 {
-			return Err(ApplicationError::StartupError(String::from(
-				"max_depthの設定値が不正です。"
-			)));
+		let r = learning_on_device::<A>(String::from("data"),ensemble,kifudir,format,false,&matches,&config,on_error_handler.clone());
+
+		if let Err(ref e) = r {
+			let _ = on_error_handler.lock().map(|h| h.call(e));
 		}
 
-		let time_limit = config.time_limit.map_or(UsiGoTimeLimit::Infinite, |l| {
-			if l == 0 && config.time_limit_byoyomi.unwrap_or(0) ==0 {
-				UsiGoTimeLimit::Infinite
-			} else if config.time_limit_byoyomi.unwrap_or(0) == 0 {
-				UsiGoTimeLimit::Limit(Some((l,l)),None)
-			} else if l == 0 {
-				UsiGoTimeLimit::Limit(None,Some(UsiGoByoyomiOrInc::Byoyomi(config.time_limit_byoyomi.unwrap_or(0))))
-			} else {
-				UsiGoTimeLimit::Limit(Some((l,l)),Some(UsiGoByoyomiOrInc::Byoyomi(config.time_limit_byoyomi.unwrap_or(0))))
-			}
-		});
+		r
+	} else if matches.opt_present("l") {
+		let config = ConfigLoader::new("settings.toml")?.load()?;
 
-		let time_limit:UsiGoTimeLimit = match matches.opt_str("timelimit") {
-			Some(time_limit) => {
-				let l = time_limit.parse()?;
-				let b = matches.opt_str("timelimit_byoyomi");
+		let silent =  matches.opt_present("silent") || config.silent;
 
-				if let Some(b) = b {
-					let b = b.parse()?;
+		let (base_depth,max_depth) = search_depths(&config,matches)?;
 
-					if l == 0 && b ==0 {
-						UsiGoTimeLimit::Infinite
-					} else if b == 0 {
-						UsiGoTimeLimit::Limit(Some((l,l)),None)
-					} else if l == 0 {
-						UsiGoTimeLimit::Limit(None,Some(UsiGoByoyomiOrInc::Byoyomi(b)))
-					} else {
-						UsiGoTimeLimit::Limit(Some((l,l)),Some(UsiGoByoyomiOrInc::Byoyomi(b)))
-					}
-				} else {
-					if l == 0 {
-						UsiGoTimeLimit::Infinite
-					} else {
-						UsiGoTimeLimit::Limit(Some((l,l)),None)
-					}
-				}
-			}
-			None => time_limit,
-		};
+		let time_limit = game_time_limit(&config,matches)?;
 
-		let uptime = config.uptime.map_or(None,|t| {
+		let uptime = config.uptime.clone().map_or(None,|t| {
 			if t == "" || t == "0" || t == "0s" || t == "0m" || t == "0h" || t == "0d" {
 				None
 			} else {
@@ -462,6 +569,8 @@ fn run() -> Result<(),ApplicationError> {
 
 		let mut engine = SelfMatchEngine::new();
 
+		let opponent_ensemble = ensemble.with_suffix("_opponent");
+
 		let mut flip = true;
 
 		let flip_players = move || {
@@ -626,40 +735,18 @@ fn run() -> Result<(),ApplicationError> {
 								initial_position_creator,
 								Some(Box::new(move |sfen,mvs| kifuwriter.write(sfen,mvs))),
 								input_read_handler,
-								NNShogiPlayer::new(|| IntelligenceCreator::create(
-													   String::from("data"),
-													   String::from("nn.a.bin"),
-													   String::from("nn.b.bin"),
+								NNShogiPlayer::new(move |dir,files,material| create(
+													   dir.to_string(),
+													   ensemble.with_filenames(files)?,
+													   material
 													   )),
-								NNShogiPlayer::new(|| IntelligenceCreator::create(
-													   String::from("data"),
-													   String::from("nn_opponent.a.bin"),
-													   String::from("nn_opponent.b.bin"),
+								NNShogiPlayer::new(move |dir,files,material| create(
+													   dir.to_string(),
+													   opponent_ensemble.with_filenames(files)?,
+													   material
 													   )),
-										[
-									("Threads",SysEventOption::Num(config.max_threads.unwrap_or(1) as i64)),
-									("BaseDepth",SysEventOption::Num(base_depth as i64)),
-									("MaxDepth",SysEventOption::Num(max_depth as i64)),
-									("MAX_PLY",SysEventOption::Num(config.max_ply.unwrap_or(0) as i64)),
-									("MAX_PLY_TIMELIMIT",SysEventOption::Num(config.max_ply_timelimit.unwrap_or(0) as i64)),
-									("TURN_COUNT",SysEventOption::Num(config.turn_count.unwrap_or(0) as i64)),
-									("MIN_TURN_COUNT",SysEventOption::Num(config.min_turn_count.unwrap_or(0) as i64)),
-									("AdjustDepth",SysEventOption::Bool(config.adjust_depth.unwrap_or(false))),
-								].iter().map(|&(ref k,ref v)| {
-									(k.to_string(),v.clone())
-								}).collect::<Vec<(String,SysEventOption)>>(),
-								[
-									("Threads",SysEventOption::Num(config.max_threads.unwrap_or(1) as i64)),
-									("BaseDepth",SysEventOption::Num(base_depth as i64)),
-									("MaxDepth",SysEventOption::Num(max_depth as i64)),
-									("MAX_PLY",SysEventOption::Num(config.max_ply.unwrap_or(0) as i64)),
-									("MAX_PLY_TIMELIMIT",SysEventOption::Num(config.max_ply_timelimit.unwrap_or(0) as i64)),
-									("TURN_COUNT",SysEventOption::Num(config.turn_count.unwrap_or(0) as i64)),
-									("MIN_TURN_COUNT",SysEventOption::Num(config.min_turn_count.unwrap_or(0) as i64)),
-									("AdjustDepth",SysEventOption::Bool(config.adjust_depth.unwrap_or(false))),
-								].iter().map(|&(ref k,ref v)| {
-									(k.to_string(),v.clone())
-								}).collect::<Vec<(String,SysEventOption)>>(),
+								player_options(&config,base_depth,max_depth),
+								player_options(&config,base_depth,max_depth),
 								info_sender,
 								pinfo_sender,
 								time_limit,
@@ -686,12 +773,26 @@ fn run() -> Result<(),ApplicationError> {
 					secs / (60 * 60), secs  % (60 * 60) / 60, secs % 60, r.elapsed.subsec_nanos() / 1_000_000);
 		})
 	} else {
-		let agent = UsiAgent::new(NNShogiPlayer::new(|| IntelligenceCreator::create(
-																		 String::from("data"),
-																		 String::from("nn.a.bin"),
-																		 String::from("nn.b.bin"))));
+		let mut player = NNShogiPlayer::new(move |dir,files,material| create(
+																		 dir.to_string(),
+																		 ensemble.with_filenames(files)?,
+																		 material));
 
-		let r = agent.start_default(|on_error_handler,e| {
+		player.set_logger(Arc::new(Mutex::new(FileLogger::new(String::from("logs/log.txt")).map_err(|_| ApplicationError::StartupError(String::from(
+			"ログファイルを開けませんでした。"
+		)))?)));
+
+		let input_reader = DebugInputReader::new(USIStdInputReader::new(),
+												 USIStdOutputWriter::new(),
+												 player.debug_info());
+
+		let logger = FileLogger::new(String::from("logs/log.txt")).map_err(|_| ApplicationError::StartupError(String::from(
+			"ログファイルを開けませんでした。"
+		)))?;
+
+		let agent = UsiAgent::new(player);
+
+		let r = agent.start(input_reader,USIStdOutputWriter::new(),logger,|on_error_handler,e| {
 			match on_error_handler {
 				Some(ref h) => {
 					let _ = h.lock().map(|h| h.call(e));
@@ -704,3 +805,208 @@ fn run() -> Result<(),ApplicationError> {
 		)))
 	}
 }
+fn search_depths(config:&Config,matches:&Matches) -> Result<(u32,u32),ApplicationError> {
+	let base_depth = match config.base_depth {
+		Some(base_depth) => base_depth,
+		_ => {
+			return Err(ApplicationError::StartupError(String::from(
+				"base_depthの値が未設定です。"
+			)))
+		}
+	};
+
+	let base_depth:u32 = match matches.opt_str("basedepth") {
+		Some(base_depth) => base_depth.parse()?,
+		None => base_depth,
+	};
+
+	if base_depth <= 0 {
+		return Err(ApplicationError::StartupError(String::from(
+			"base_depthの設定値が不正です。"
+		)));
+	}
+
+	let max_depth = match config.max_depth {
+		Some(max_depth) => max_depth,
+		_ => {
+			return Err(ApplicationError::StartupError(String::from(
+				"base_depthが未設定です。"
+			)));
+		}
+	};
+
+	let max_depth:u32 = match matches.opt_str("maxdepth") {
+		Some(max_depth) => max_depth.parse()?,
+		None => max_depth,
+	};
+
+	if max_depth <= 0 {
+		return Err(ApplicationError::StartupError(String::from(
+			"max_depthの設定値が不正です。"
+		)));
+	}
+
+	Ok((base_depth,max_depth))
+}
+fn game_time_limit(config:&Config,matches:&Matches) -> Result<UsiGoTimeLimit,ApplicationError> {
+	let time_limit = config.time_limit.map_or(UsiGoTimeLimit::Infinite, |l| {
+		if l == 0 && config.time_limit_byoyomi.unwrap_or(0) ==0 {
+			UsiGoTimeLimit::Infinite
+		} else if config.time_limit_byoyomi.unwrap_or(0) == 0 {
+			UsiGoTimeLimit::Limit(Some((l,l)),None)
+		} else if l == 0 {
+			UsiGoTimeLimit::Limit(None,Some(UsiGoByoyomiOrInc::Byoyomi(config.time_limit_byoyomi.unwrap_or(0))))
+		} else {
+			UsiGoTimeLimit::Limit(Some((l,l)),Some(UsiGoByoyomiOrInc::Byoyomi(config.time_limit_byoyomi.unwrap_or(0))))
+		}
+	});
+
+	Ok(match matches.opt_str("timelimit") {
+		Some(time_limit) => {
+			let l = time_limit.parse()?;
+			let b = matches.opt_str("timelimit_byoyomi");
+
+			if let Some(b) = b {
+				let b = b.parse()?;
+
+				if l == 0 && b ==0 {
+					UsiGoTimeLimit::Infinite
+				} else if b == 0 {
+					UsiGoTimeLimit::Limit(Some((l,l)),None)
+				} else if l == 0 {
+					UsiGoTimeLimit::Limit(None,Some(UsiGoByoyomiOrInc::Byoyomi(b)))
+				} else {
+					UsiGoTimeLimit::Limit(Some((l,l)),Some(UsiGoByoyomiOrInc::Byoyomi(b)))
+				}
+			} else {
+				if l == 0 {
+					UsiGoTimeLimit::Infinite
+				} else {
+					UsiGoTimeLimit::Limit(Some((l,l)),None)
+				}
+			}
+		}
+		None => time_limit,
+	})
+}
+fn player_options(config:&Config,base_depth:u32,max_depth:u32) -> Vec<(String,SysEventOption)> {
+	[
+		("Threads",SysEventOption::Num(config.max_threads.unwrap_or(1) as i64)),
+		("BaseDepth",SysEventOption::Num(base_depth as i64)),
+		("MaxDepth",SysEventOption::Num(max_depth as i64)),
+		("MAX_PLY",SysEventOption::Num(config.max_ply.unwrap_or(0) as i64)),
+		("MAX_PLY_TIMELIMIT",SysEventOption::Num(config.max_ply_timelimit.unwrap_or(0) as i64)),
+		("TURN_COUNT",SysEventOption::Num(config.turn_count.unwrap_or(0) as i64)),
+		("MIN_TURN_COUNT",SysEventOption::Num(config.min_turn_count.unwrap_or(0) as i64)),
+		("AdjustDepth",SysEventOption::Bool(config.adjust_depth.unwrap_or(false))),
+		("EvalScale",SysEventOption::Num(config.eval_scale.unwrap_or(DEFAULT_EVAL_SCALE) as i64)),
+		("ResignValue",SysEventOption::Num(config.resign_value.unwrap_or(0) as i64)),
+		("DrawValue",SysEventOption::Num(config.draw_value.unwrap_or(DRAW_VALUE) as i64)),
+		("MaterialEvaluation",SysEventOption::Bool(config.material_evaluation.unwrap_or(false))),
+	].iter().map(|&(ref k,ref v)| {
+		(k.to_string(),v.clone())
+	}).collect::<Vec<(String,SysEventOption)>>()
+}
+/// Number of the records in the shuffle buffer, within learn_shuffle_buffer_mb if it is specified.
+fn shuffle_buffer_size(config:&Config,item_size:usize) -> usize {
+	match config.learn_shuffle_buffer_mb {
+		Some(mb) => mb * 1024 * 1024 / item_size,
+		None => config.learn_sfen_read_size.unwrap_or(LEAN_SFEN_READ_SIZE),
+	}
+}
+/// result_only trains on the game results only, ignoring learn_lambda, for the records without the evaluation of the search.
+fn learning_on_device<A>(savedir:String,ensemble:Ensemble,kifudir:String,format:Option<RecordFormat>,result_only:bool,
+						 matches:&Matches,config:&Config,
+						 on_error_handler:Arc<Mutex<OnErrorHandler<FileLogger>>>) -> Result<(),ApplicationError>
+	where A: Architecture + 'static {
+	let device = matches.opt_str("device").or(config.learn_device.clone()).unwrap_or(String::from("gpu"));
+
+	match &*device {
+		"gpu" => {
+			learning(TrainerCreator::create::<A>(savedir,ensemble)?,
+					 kifudir,format,result_only,matches,config,on_error_handler)
+		},
+		"cpu" => {
+			learning(TrainerCreator::create_cpu::<A>(savedir,ensemble)?,
+					 kifudir,format,result_only,matches,config,on_error_handler)
+		},
+		_ => {
+			Err(ApplicationError::StartupError(String::from(
+				"デバイスの指定が不正です。(cpuかgpuを指定してください)"
+			)))
+		}
+	}
+}
+fn learning<NN,D>(trainer:Trainer<NN,D>,kifudir:String,format:Option<RecordFormat>,result_only:bool,matches:&Matches,config:&Config,
+				  on_error_handler:Arc<Mutex<OnErrorHandler<FileLogger>>>) -> Result<(),ApplicationError>
+	where NN: TrainerNetwork<D>,
+		  D: Device<f32> {
+	let mut trainer = trainer;
+
+	trainer.set_eval_scale(config.eval_scale.unwrap_or(DEFAULT_EVAL_SCALE));
+
+	let lambda = config.learn_lambda.unwrap_or(0.);
+
+	if lambda < 0. || lambda > 1. {
+		return Err(ApplicationError::StartupError(String::from(
+			"learn_lambdaには0から1の値を指定してください。"
+		)));
+	}
+
+	let lambda = if result_only { 0. } else { lambda };
+
+	trainer.set_teacher(lambda,config.teacher_eval_scale.or(config.eval_scale).unwrap_or(DEFAULT_EVAL_SCALE));
+
+	let optimizer = TrainerOptimizer::from_settings(config.optimizer.as_ref())?;
+	let scheduler = LrScheduler::from_settings(config.lr_schedule.as_ref(),optimizer.learning_rate())?;
+
+	trainer.set_optimizer(optimizer,scheduler);
+
+	if let Some(ref mirror) = config.learn_mirror {
+		trainer.set_augmentation(Augmentation::parse(mirror)?);
+	}
+
+	match config.metrics_log {
+		Some(ref file) if file.is_empty() => (),
+		Some(ref file) => trainer.set_metrics_log(file)?,
+		None => trainer.set_metrics_log(METRICS_LOG)?,
+	}
+
+	let maxepoch = matches.opt_str("maxepoch").unwrap_or(String::from("1")).parse::<usize>()?;
+	let validation_size = config.validation_size.unwrap_or(VALIDATION_SIZE);
+	let validation_interval = config.validation_interval.unwrap_or(VALIDATION_INTERVAL);
+	let decode_threads = config.learn_max_threads.unwrap_or(LEAN_MAX_THREADS);
+
+	if decode_threads == 0 {
+		return Err(ApplicationError::StartupError(String::from(
+			"learn_max_threadsには1以上の値を指定してください。"
+		)));
+	}
+
+	if format == Some(RecordFormat::PackedSfen) {
+		Learnener::new(validation_size,validation_interval).learning_from_yaneuraou_bin(kifudir,
+													 trainer,
+													 on_error_handler,
+													 shuffle_buffer_size(config,PACKED_SFEN_SIZE),
+													 decode_threads,
+													 config.learn_batch_size.unwrap_or(LEAN_BATCH_SIZE),
+													 config.save_batch_count.unwrap_or(1),
+													 maxepoch)
+	} else if format == Some(RecordFormat::Hcpe) {
+		Learnener::new(validation_size,validation_interval).learning_from_hcpe(kifudir,
+											trainer,
+											on_error_handler,
+											shuffle_buffer_size(config,HCPE_SIZE),
+											decode_threads,
+											config.learn_batch_size.unwrap_or(LEAN_BATCH_SIZE),
+											config.save_batch_count.unwrap_or(1),
+											maxepoch)
+	} else {
+		let lowerrate: f64 = matches.opt_str("lowerrate").unwrap_or(String::from("3000.0")).parse()?;
+		Learnener::new(validation_size,validation_interval).learning_from_csa(kifudir,
+										   lowerrate,
+										   maxepoch,
+										   trainer,
+										   on_error_handler)
+	}
+}
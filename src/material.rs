@@ -0,0 +1,157 @@
+use std::marker::PhantomData;
+
+use nncombinator::arr::{Arr, DiffArr};
+use nncombinator::error::{EvaluateError, InvalidStateError};
+
+use features::FeatureSet;
+use nn::{DEFAULT_EVAL_SCALE, Network};
+
+/// Values in centipawns of the pieces on the board, indexed by KomaKind of sente. (the value of the king is not used)
+pub const PIECE_VALUES:[f32; 14] = [
+	90.,
+	315.,
+	405.,
+	495.,
+	540.,
+	855.,
+	990.,
+	0.,
+	540.,
+	540.,
+	540.,
+	540.,
+	945.,
+	1395.
+];
+/// Values in centipawns of the pieces in hand, indexed by MochigomaKind.
+pub const HAND_VALUES:[f32; 7] = [
+	100.,
+	350.,
+	450.,
+	550.,
+	600.,
+	950.,
+	1100.
+];
+/// Penalty of the king per rank advanced beyond the third rank of its side.
+pub const KING_ADVANCE_PENALTY:f32 = 60.;
+/// Bonus of the own piece and penalty of the opponent piece within two squares of the king.
+///
+/// Only the king relative features can express these terms, the absolute squares of piece2517 can not.
+pub const KING_DEFENDER_VALUE:f32 = 20.;
+pub const KING_ATTACKER_VALUE:f32 = 30.;
+
+/// Value of the king on the rank counted from its own side. (0 for the back rank)
+pub fn king_rank_value(rank:usize) -> f32 {
+	-(rank.saturating_sub(2) as f32) * KING_ADVANCE_PENALTY
+}
+
+pub fn dot<const N:usize>(input:&Arr<f32,N>,weights:&[f32]) -> f32 {
+	input.iter().zip(weights.iter()).filter(|&(&i,_)| i != 0.).map(|(i,w)| i * w).sum()
+}
+
+pub fn dot_diff<const N:usize>(input:&DiffArr<f32,N>,weights:&[f32]) -> f32 {
+	input.iter().map(|&(i,v)| v * weights[i]).sum()
+}
+
+/// Hand-crafted evaluation of the material, the pieces in hand and the king safety, as a linear function of the features.
+///
+/// The king safety depends on the features, with piece2517 it is only the rank of the king (king_rank_value),
+/// and the king relative features add the defenders and the attackers near the king.
+///
+/// The output is tanh(centipawns / (2 * DEFAULT_EVAL_SCALE)), so it is converted back to the same centipawns with the default scale.
+pub struct MaterialNetwork<F> where F: FeatureSet {
+	weights:Vec<f32>,
+	features:PhantomData<F>,
+}
+impl<F> MaterialNetwork<F> where F: FeatureSet {
+	pub fn new() -> MaterialNetwork<F> {
+		MaterialNetwork {
+			weights:F::material_weights(),
+			features:PhantomData::<F>,
+		}
+	}
+
+	fn output(&self,cp:f32) -> f32 {
+		(cp / (2. * DEFAULT_EVAL_SCALE)).tanh()
+	}
+}
+impl<F> Network for MaterialNetwork<F> where F: FeatureSet {
+	type Features = F;
+	type Accumulator = f32;
+
+	fn forward_input(&self,input:F::Input) -> Result<f32,EvaluateError> {
+		Ok(self.output(F::dot(&input,&self.weights)))
+	}
+
+	fn accumulate(&self,input:F::Input) -> Result<f32,EvaluateError> {
+		Ok(F::dot(&input,&self.weights))
+	}
+
+	fn accumulate_diff(&self,accumulator:&f32,input:F::DiffInput,dst:&mut f32) -> Result<f32,EvaluateError> {
+		*dst = accumulator + F::dot_diff(&input,&self.weights);
+
+		Ok(self.output(*dst))
+	}
+}
+/// Network of the model, or the material evaluation used in place of it when no model is available.
+pub enum FallbackNetwork<NN> where NN: Network {
+	Model(NN),
+	Material(MaterialNetwork<NN::Features>),
+}
+#[derive(Clone)]
+pub enum FallbackAccumulator<A> {
+	Model(A),
+	Material(f32),
+}
+impl<NN> Network for FallbackNetwork<NN> where NN: Network {
+	type Features = NN::Features;
+	type Accumulator = FallbackAccumulator<NN::Accumulator>;
+
+	fn forward_input(&self,input:<NN::Features as FeatureSet>::Input) -> Result<f32,EvaluateError> {
+		match self {
+			&FallbackNetwork::Model(ref nn) => nn.forward_input(input),
+			&FallbackNetwork::Material(ref nn) => nn.forward_input(input),
+		}
+	}
+
+	fn accumulate(&self,input:<NN::Features as FeatureSet>::Input) -> Result<Self::Accumulator,EvaluateError> {
+		match self {
+			&FallbackNetwork::Model(ref nn) => Ok(FallbackAccumulator::Model(nn.accumulate(input)?)),
+			&FallbackNetwork::Material(ref nn) => Ok(FallbackAccumulator::Material(nn.accumulate(input)?)),
+		}
+	}
+
+	fn accumulate_diff(&self,accumulator:&Self::Accumulator,input:<NN::Features as FeatureSet>::DiffInput,dst:&mut Self::Accumulator)
+		-> Result<f32,EvaluateError> {
+		match (self,accumulator) {
+			(&FallbackNetwork::Model(ref nn),&FallbackAccumulator::Model(ref accumulator)) => {
+				if let &mut FallbackAccumulator::Model(ref mut d) = dst {
+					return nn.accumulate_diff(accumulator,input,d);
+				}
+
+				let mut d = accumulator.clone();
+
+				let s = nn.accumulate_diff(accumulator,input,&mut d)?;
+
+				*dst = FallbackAccumulator::Model(d);
+
+				Ok(s)
+			},
+			(&FallbackNetwork::Material(ref nn),&FallbackAccumulator::Material(accumulator)) => {
+				let mut d = 0.;
+
+				let s = nn.accumulate_diff(&accumulator,input,&mut d)?;
+
+				*dst = FallbackAccumulator::Material(d);
+
+				Ok(s)
+			},
+			_ => {
+				Err(EvaluateError::InvalidStateError(InvalidStateError(String::from(
+					"The accumulator was made by the other kind of evaluation."
+				))))
+			}
+		}
+	}
+}
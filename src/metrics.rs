@@ -0,0 +1,175 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use error::ApplicationError;
+use model::ModelHeader;
+
+pub const METRICS_LOG:&'static str = "logs/metrics.csv";
+const HEADER:&'static str = "kind,time,step,epoch,positions,learning_rate,positions_per_sec,loss,accuracy";
+const TREND_STEPS:usize = 100;
+const RECENT_VALIDATIONS:usize = 5;
+
+/// Writes the progress of the training to a csv file, one line per training step or validation.
+///
+/// The columns are kind (train or valid), unix time, step, epoch, positions learned so far, learning rate,
+/// positions per second, loss (the losses of the members separated by ; for train) and accuracy (empty for train).
+pub struct MetricsWriter {
+	writer:BufWriter<File>,
+	last:Instant,
+}
+impl MetricsWriter {
+	pub fn new(file:&str) -> Result<MetricsWriter,ApplicationError> {
+		if let Some(dir) = Path::new(file).parent() {
+			if !dir.as_os_str().is_empty() {
+				fs::create_dir_all(dir)?;
+			}
+		}
+
+		let exists = Path::new(file).exists();
+
+		let mut writer = BufWriter::new(OpenOptions::new().append(true).create(true).open(file)?);
+
+		if !exists {
+			writer.write_all(format!("{}\n",HEADER).as_bytes())?;
+		}
+
+		Ok(MetricsWriter {
+			writer:writer,
+			last:Instant::now(),
+		})
+	}
+
+	pub fn train(&mut self,step:usize,epoch:u64,positions:u64,batch_positions:usize,
+				 learning_rate:f32,losses:&[f32]) -> Result<(),ApplicationError> {
+		let elapsed = self.last.elapsed().as_secs_f64();
+
+		self.last = Instant::now();
+
+		let throughput = if elapsed > 0. {
+			batch_positions as f64 / elapsed
+		} else {
+			0.
+		};
+
+		let losses = losses.iter().map(|l| l.to_string()).collect::<Vec<String>>().join(";");
+
+		self.write(format!("train,{},{},{},{},{},{:.1},{},",
+						   ModelHeader::now(),step,epoch,positions,learning_rate,throughput,losses))
+	}
+
+	pub fn validation(&mut self,step:usize,epoch:u64,positions:u64,
+					  learning_rate:f32,loss:f64,accuracy:f64) -> Result<(),ApplicationError> {
+		self.write(format!("valid,{},{},{},{},{},,{},{}",
+						   ModelHeader::now(),step,epoch,positions,learning_rate,loss,accuracy))
+	}
+
+	fn write(&mut self,line:String) -> Result<(),ApplicationError> {
+		self.writer.write_all(format!("{}\n",line).as_bytes())?;
+		self.writer.flush()?;
+
+		Ok(())
+	}
+}
+struct MetricsRecord {
+	step:usize,
+	epoch:u64,
+	positions:u64,
+	learning_rate:f32,
+	throughput:f64,
+	losses:Vec<f64>,
+	accuracy:f64,
+}
+impl MetricsRecord {
+	fn parse(columns:&[&str]) -> Result<MetricsRecord,ApplicationError> {
+		let invalid = || ApplicationError::StartupError(String::from(
+			"メトリクスファイルの形式が不正です。"
+		));
+
+		if columns.len() != 9 {
+			return Err(invalid());
+		}
+
+		Ok(MetricsRecord {
+			step:columns[2].parse().map_err(|_| invalid())?,
+			epoch:columns[3].parse().map_err(|_| invalid())?,
+			positions:columns[4].parse().map_err(|_| invalid())?,
+			learning_rate:columns[5].parse().map_err(|_| invalid())?,
+			throughput:if columns[6].is_empty() { 0. } else { columns[6].parse().map_err(|_| invalid())? },
+			losses:columns[7].split(';').map(|l| l.parse().map_err(|_| invalid())).collect::<Result<Vec<f64>,_>>()?,
+			accuracy:if columns[8].is_empty() { 0. } else { columns[8].parse().map_err(|_| invalid())? },
+		})
+	}
+}
+/// Prints the recent trends of the training recorded by MetricsWriter.
+pub struct MetricsSummarizer;
+impl MetricsSummarizer {
+	pub fn run(file:&str) -> Result<(),ApplicationError> {
+		if !Path::new(file).exists() {
+			return Err(ApplicationError::StartupError(String::from(
+				"メトリクスファイルが見つかりません。"
+			)));
+		}
+
+		let reader = BufReader::new(OpenOptions::new().read(true).create(false).open(file)?);
+
+		let mut train = Vec::new();
+		let mut valid = Vec::new();
+
+		for line in reader.lines() {
+			let line = line?;
+			let columns = line.split(',').collect::<Vec<&str>>();
+
+			match columns[0] {
+				"train" => train.push(MetricsRecord::parse(&columns)?),
+				"valid" => valid.push(MetricsRecord::parse(&columns)?),
+				_ => (),
+			}
+		}
+
+		let last = match train.last() {
+			Some(last) => last,
+			None => {
+				print!("学習の記録がありません。\n");
+				return Ok(());
+			}
+		};
+
+		print!("学習ステップ数: {} (エポック {}, 局面数 {})\n",last.step,last.epoch,last.positions);
+		print!("学習率: {}\n",last.learning_rate);
+
+		let recent = &train[train.len().saturating_sub(TREND_STEPS)..];
+		let previous = &train[train.len().saturating_sub(TREND_STEPS * 2)..train.len() - recent.len()];
+
+		print!("直近{}ステップの平均損失: {}\n",recent.len(),MetricsSummarizer::format_losses(recent));
+
+		if previous.len() > 0 {
+			print!("その前の{}ステップの平均損失: {}\n",previous.len(),MetricsSummarizer::format_losses(previous));
+		}
+
+		print!("直近{}ステップの処理速度: {:.1}局面/秒\n",
+			   recent.len(),recent.iter().map(|r| r.throughput).sum::<f64>() / recent.len() as f64);
+
+		if valid.len() > 0 {
+			print!("検証結果:\n");
+
+			for r in valid[valid.len().saturating_sub(RECENT_VALIDATIONS)..].iter() {
+				print!("ステップ {}: 損失 {} 正解率 {:.2}%\n",r.step,r.losses[0],r.accuracy * 100.);
+			}
+		}
+
+		Ok(())
+	}
+
+	fn format_losses(records:&[MetricsRecord]) -> String {
+		let members = records.iter().map(|r| r.losses.len()).max().unwrap_or(0);
+
+		(0..members).map(|i| {
+			let losses = records.iter().filter_map(|r| r.losses.get(i)).collect::<Vec<&f64>>();
+
+			losses.iter().map(|&&l| l).sum::<f64>() / losses.len() as f64
+		}).map(|l| l.to_string()).collect::<Vec<String>>().join(", ")
+	}
+}
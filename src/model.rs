@@ -0,0 +1,292 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use nncombinator::error::{ConfigReadError, PersistenceError};
+use nncombinator::persistence::LinearPersistence;
+
+use error::ApplicationError;
+
+const MAGIC:&'static [u8; 8] = b"NNSHOGIM";
+pub const FORMAT_VERSION:u32 = 1;
+
+/// Information stored at the head of the model file.
+#[derive(Debug,Clone,Deserialize,Serialize)]
+pub struct ModelHeader {
+	pub architecture:String,
+	pub feature_set:String,
+	/// Seconds since the UNIX epoch.
+	pub created_at:u64,
+	pub updated_at:u64,
+	pub positions:u64,
+	pub epochs:u64,
+	pub source:String,
+	/// Format of the quantized weights, None for the float models.
+	pub quantization:Option<String>,
+}
+impl ModelHeader {
+	pub fn new(architecture:&str,feature_set:&str) -> ModelHeader {
+		let now = ModelHeader::now();
+
+		ModelHeader {
+			architecture:architecture.to_string(),
+			feature_set:feature_set.to_string(),
+			created_at:now,
+			updated_at:now,
+			positions:0,
+			epochs:0,
+			source:String::from(""),
+			quantization:None,
+		}
+	}
+
+	pub fn now() -> u64 {
+		SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+	}
+}
+/// Persistence of the model file.
+///
+/// The file consists of the magic number, the format version (u32, big endian),
+/// the length of the header (u32, big endian), the header as a toml string,
+/// and then the weights in the same layout as BinFilePersistence.
+/// Files written before the header was introduced have no magic number, and are read as weights only
+/// of the standard preset, the only one that existed at the time.
+pub struct ModelFilePersistence {
+	reader:Option<BufReader<File>>,
+	version:Option<u32>,
+	header:Option<ModelHeader>,
+	weights_size:u64,
+	data:Vec<f32>,
+}
+impl ModelFilePersistence {
+	pub fn new() -> ModelFilePersistence {
+		ModelFilePersistence {
+			reader:None,
+			version:None,
+			header:None,
+			weights_size:0,
+			data:Vec::new(),
+		}
+	}
+
+	pub fn open(file:&str) -> Result<ModelFilePersistence,ApplicationError> {
+		if !Path::new(file).exists() {
+			return Err(ApplicationError::StartupError(format!(
+				"モデルファイル{}が見つかりません。",file
+			)));
+		}
+
+		let size = fs::metadata(file)?.len();
+
+		let mut reader = BufReader::new(OpenOptions::new().read(true).create(false).open(file)?);
+
+		let mut magic = [0; 8];
+
+		if size >= 16 {
+			reader.read_exact(&mut magic)?;
+		}
+
+		if &magic != MAGIC {
+			return Ok(ModelFilePersistence {
+				reader:Some(BufReader::new(OpenOptions::new().read(true).create(false).open(file)?)),
+				version:None,
+				header:None,
+				weights_size:size,
+				data:Vec::new(),
+			});
+		}
+
+		let version = ModelFilePersistence::read_u32(&mut reader)?;
+		let header_size = ModelFilePersistence::read_u32(&mut reader)?;
+
+		if 16 + header_size as u64 > size {
+			return Err(ApplicationError::StartupError(format!(
+				"モデルファイル{}のヘッダが壊れています。",file
+			)));
+		}
+
+		let mut buf = vec![0; header_size as usize];
+
+		reader.read_exact(&mut buf)?;
+
+		let header = String::from_utf8(buf).ok().and_then(|h| toml::from_str::<ModelHeader>(&h).ok());
+
+		let header = match header {
+			Some(header) => header,
+			None => {
+				return Err(ApplicationError::StartupError(format!(
+					"モデルファイル{}のヘッダが壊れています。",file
+				)));
+			}
+		};
+
+		Ok(ModelFilePersistence {
+			reader:Some(reader),
+			version:Some(version),
+			header:Some(header),
+			weights_size:size - 16 - header_size as u64,
+			data:Vec::new(),
+		})
+	}
+
+	fn read_u32(reader:&mut BufReader<File>) -> Result<u32,ApplicationError> {
+		let mut buf = [0; 4];
+
+		reader.read_exact(&mut buf)?;
+
+		Ok(u32::from_be_bytes(buf))
+	}
+
+	/// Format version of the file, None for the files without the header.
+	pub fn version(&self) -> Option<u32> {
+		self.version
+	}
+
+	pub fn header(&self) -> Option<&ModelHeader> {
+		self.header.as_ref()
+	}
+
+	/// Size in bytes of the weights following the header.
+	pub fn weights_size(&self) -> u64 {
+		self.weights_size
+	}
+
+	/// Weights written through LinearPersistence.
+	pub fn weights(&self) -> &[f32] {
+		&self.data
+	}
+
+	pub fn read_bytes(&mut self,buf:&mut [u8]) -> Result<(),ApplicationError> {
+		match self.reader {
+			Some(ref mut reader) => {
+				reader.read_exact(buf)?;
+				Ok(())
+			},
+			None => {
+				Err(ApplicationError::LogicError(String::from(
+					"File does not exist yet.")))
+			}
+		}
+	}
+
+	pub fn save(&self,file:&str,header:&ModelHeader) -> Result<(),ApplicationError> {
+		let mut buf = Vec::with_capacity(self.data.len() * 4);
+
+		for u in self.data.iter() {
+			buf.extend_from_slice(&u.to_bits().to_be_bytes());
+		}
+
+		ModelFilePersistence::save_bytes(file,header,&buf)
+	}
+
+	pub fn save_bytes(file:&str,header:&ModelHeader,payload:&[u8]) -> Result<(),ApplicationError> {
+		let header = toml::to_string(header)?;
+
+		let mut bw = BufWriter::new(OpenOptions::new().write(true).create(true).truncate(true).open(file)?);
+
+		bw.write_all(MAGIC)?;
+		bw.write_all(&FORMAT_VERSION.to_be_bytes())?;
+		bw.write_all(&(header.len() as u32).to_be_bytes())?;
+		bw.write_all(header.as_bytes())?;
+		bw.write_all(payload)?;
+
+		bw.flush()?;
+
+		Ok(())
+	}
+}
+impl LinearPersistence<f32> for ModelFilePersistence {
+	fn read(&mut self) -> Result<f32, ConfigReadError> {
+		match self.reader {
+			Some(ref mut reader) => {
+				let mut buf = [0; 4];
+
+				reader.read_exact(&mut buf)?;
+
+				Ok(f32::from_bits(u32::from_be_bytes(buf)))
+			},
+			None => {
+				Err(ConfigReadError::InvalidState(String::from(
+					"File does not exist yet.")))
+			}
+		}
+	}
+
+	fn write(&mut self, u: f32) -> Result<(), PersistenceError> {
+		self.data.push(u);
+		Ok(())
+	}
+
+	fn verify_eof(&mut self) -> Result<(), ConfigReadError> {
+		match self.reader {
+			Some(ref mut reader) => {
+				let mut buf: [u8; 1] = [0];
+
+				let n = reader.read(&mut buf)?;
+
+				if n == 0 {
+					Ok(())
+				} else {
+					Err(ConfigReadError::InvalidState(String::from("Data loaded , but the input has not reached the end.")))
+				}
+			},
+			None => {
+				Err(ConfigReadError::InvalidState(String::from(
+					"File does not exist yet.")))
+			}
+		}
+	}
+}
+pub struct ModelInspector;
+impl ModelInspector {
+	pub fn run(file:&str) -> Result<(),ApplicationError> {
+		let p = ModelFilePersistence::open(file)?;
+
+		print!("ファイル: {}\n",file);
+
+		match (p.version(),p.header()) {
+			(Some(version),Some(header)) => {
+				print!("形式バージョン: {}\n",version);
+				print!("ネットワーク構成: {}\n",header.architecture);
+				print!("特徴量: {}\n",header.feature_set);
+				print!("作成日時: {}\n",ModelInspector::format_time(header.created_at));
+				print!("更新日時: {}\n",ModelInspector::format_time(header.updated_at));
+				print!("学習局面数: {}\n",header.positions);
+				print!("エポック数: {}\n",header.epochs);
+				print!("学習データ: {}\n",if header.source.is_empty() { "-" } else { &header.source });
+				print!("量子化: {}\n",header.quantization.as_ref().map(|q| q.as_str()).unwrap_or("なし"));
+			},
+			_ => {
+				print!("形式バージョン: なし (ヘッダのない旧形式のファイルです。ネットワーク構成standardとしてのみ読み込めます)\n");
+			}
+		}
+
+		if p.header().map(|h| h.quantization.is_some()).unwrap_or(false) {
+			print!("パラメータのサイズ: {}バイト\n",p.weights_size());
+		} else {
+			print!("パラメータ数: {}\n",p.weights_size() / 4);
+		}
+
+		Ok(())
+	}
+
+	fn format_time(t:u64) -> String {
+		let days = (t / 86400) as i64;
+		let secs = t % 86400;
+
+		// Conversion from the days since 1970-01-01 to the civil date.
+		let z = days + 719468;
+		let era = z.div_euclid(146097);
+		let doe = z - era * 146097;
+		let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+		let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+		let mp = (5 * doy + 2) / 153;
+		let d = doy - (153 * mp + 2) / 5 + 1;
+		let m = if mp < 10 { mp + 3 } else { mp - 9 };
+		let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+
+		format!("{}年{:02}月{:02}日 {:02}:{:02}:{:02} (UTC)",y,m,d,secs / 3600,secs % 3600 / 60,secs % 60)
+	}
+}
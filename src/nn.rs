@@ -4,16 +4,16 @@ use std::fs;
 use std::ops::DerefMut;
 use std::path::Path;
 use std::rc::Rc;
-use std::convert::TryFrom;
+use std::marker::PhantomData;
 
-use nncombinator::activation::{ReLu, Tanh};
+use nncombinator::activation::{ReLu, Swish, Tanh};
 use nncombinator::arr::{Arr, DiffArr, VecArr};
+use nncombinator::error::{ConfigReadError, EvaluateError, PersistenceError, TrainingError};
 use nncombinator::cuda::mem::{Alloctype, MemoryPool};
-use nncombinator::device::{DeviceCpu, DeviceGpu};
+use nncombinator::device::{Device, DeviceCpu, DeviceGpu};
 use nncombinator::layer::{ActivationLayer, AddLayer, AddLayerTrain, AskDiffInput, BatchForwardBase, BatchTrain, DiffInput, DiffLinearLayer, ForwardAll, ForwardDiff, InputLayer, LinearLayer, LinearOutputLayer, PreTrain, TryAddLayer};
-use nncombinator::lossfunction::Mse;
-use nncombinator::optimizer::{Adam};
-use nncombinator::persistence::{BinFilePersistence, Linear, Persistence, SaveToFile};
+use nncombinator::lossfunction::{BatchLossFunction, Mse};
+use nncombinator::persistence::{Linear, Persistence};
 use nncombinator::Stack;
 use rand::{prelude, Rng, SeedableRng};
 use rand::prelude::{Distribution};
@@ -28,246 +28,615 @@ use usiagent::error::EventDispatchError;
 use usiagent::event::GameEndState;
 
 use error::*;
-use packedsfen::yaneuraou;
-use packedsfen::hcpe;
-use packedsfen::yaneuraou::reader::PackedSfenReader;
-use packedsfen::traits::Reader;
-use packedsfen::hcpe::reader::HcpeReader;
-use packedsfen::hcpe::haffman_code::GameResult;
+use features::{FeatureSet, InputCreator};
+use kingrelative::KingRelativeInputCreator;
+use model::{FORMAT_VERSION, ModelFilePersistence, ModelHeader};
+use quantized::{quantized_filename, QuantizedNetwork};
+use material::{FallbackNetwork, MaterialNetwork};
+use validation::{Phase, Validation, ValidationSample};
+use metrics::MetricsWriter;
+use optimizer::{DEFAULT_LEARNING_RATE, LrSchedule, LrScheduler, TrainerOptimizer};
+use pipeline::{SampleDecoder, Samples};
+use records::RecordFormat;
 
 pub struct Intelligence<NN>
-	where NN: ForwardAll<Input=DiffInput<DiffArr<f32,2517>,f32,2517,256>,Output=Arr<f32,1>> +
-			  PreTrain<f32> + ForwardDiff<f32> + AskDiffInput<f32,DiffInput=Arr<f32,256>> {
-	nna:NN,
-	nnb:NN,
+	where NN: Network {
+	/// Shared by the clones, which differ only in the settings such as eval_scale.
+	networks:Arc<Vec<NN>>,
+	weights:Vec<f32>,
+	eval_scale:f32,
 	quited:bool,
+	/// Model file whose absence made the evaluation fall back to the material.
+	fallback:Option<String>,
 }
+/// Accumulators of all the members of the ensemble.
+pub type Accumulators<NN> = Vec<<NN as Network>::Accumulator>;
 
-const BANMEN_SIZE:usize = 81;
-
-const SELF_TEBAN_INDEX:usize = 0;
-const OPPONENT_TEBAN_INDEX:usize = SELF_TEBAN_INDEX + 1;
-
-const OU_INDEX:usize = OPPONENT_TEBAN_INDEX + 1;
-const FU_INDEX:usize = OU_INDEX + BANMEN_SIZE;
-const KYOU_INDEX:usize = FU_INDEX + BANMEN_SIZE;
-const KEI_INDEX:usize = KYOU_INDEX + BANMEN_SIZE;
-const GIN_INDEX:usize = KEI_INDEX + BANMEN_SIZE;
-const KIN_INDEX:usize = GIN_INDEX + BANMEN_SIZE;
-const KAKU_INDEX:usize = KIN_INDEX + BANMEN_SIZE;
-const HISHA_INDEX:usize = KAKU_INDEX + BANMEN_SIZE;
-const NARIFU_INDEX:usize = HISHA_INDEX + BANMEN_SIZE;
-const NARIKYOU_INDEX:usize = NARIFU_INDEX + BANMEN_SIZE;
-const NARIKEI_INDEX:usize = NARIKYOU_INDEX + BANMEN_SIZE;
-const NARIGIN_INDEX:usize = NARIKEI_INDEX + BANMEN_SIZE;
-const NARIKAKU_INDEX:usize = NARIGIN_INDEX + BANMEN_SIZE;
-const NARIHISHA_INDEX:usize = NARIKAKU_INDEX + BANMEN_SIZE;
-const OPPONENT_FU_INDEX:usize = NARIHISHA_INDEX + BANMEN_SIZE;
-const OPPONENT_KYOU_INDEX:usize = OPPONENT_FU_INDEX + BANMEN_SIZE;
-const OPPONENT_KEI_INDEX:usize = OPPONENT_KYOU_INDEX + BANMEN_SIZE;
-const OPPONENT_GIN_INDEX:usize = OPPONENT_KEI_INDEX + BANMEN_SIZE;
-const OPPONENT_KIN_INDEX:usize = OPPONENT_GIN_INDEX + BANMEN_SIZE;
-const OPPONENT_KAKU_INDEX:usize = OPPONENT_KIN_INDEX + BANMEN_SIZE;
-const OPPONENT_HISHA_INDEX:usize = OPPONENT_KAKU_INDEX + BANMEN_SIZE;
-const OPPONENT_OU_INDEX:usize = OPPONENT_HISHA_INDEX + BANMEN_SIZE;
-const OPPONENT_NARIFU_INDEX:usize = OPPONENT_OU_INDEX + BANMEN_SIZE;
-const OPPONENT_NARIKYOU_INDEX:usize = OPPONENT_NARIFU_INDEX + BANMEN_SIZE;
-const OPPONENT_NARIKEI_INDEX:usize = OPPONENT_NARIKYOU_INDEX + BANMEN_SIZE;
-const OPPONENT_NARIGIN_INDEX:usize = OPPONENT_NARIKEI_INDEX + BANMEN_SIZE;
-const OPPONENT_NARIKAKU_INDEX:usize = OPPONENT_NARIGIN_INDEX + BANMEN_SIZE;
-const OPPONENT_NARIHISHA_INDEX:usize = OPPONENT_NARIKAKU_INDEX + BANMEN_SIZE;
-
-const MOCHIGOMA_FU_INDEX:usize = OPPONENT_NARIHISHA_INDEX + BANMEN_SIZE;
-const MOCHIGOMA_KYOU_INDEX:usize = MOCHIGOMA_FU_INDEX + 19;
-const MOCHIGOMA_KEI_INDEX:usize = MOCHIGOMA_KYOU_INDEX + 5;
-const MOCHIGOMA_GIN_INDEX:usize = MOCHIGOMA_KEI_INDEX + 5;
-const MOCHIGOMA_KIN_INDEX:usize = MOCHIGOMA_GIN_INDEX + 5;
-const MOCHIGOMA_KAKU_INDEX:usize = MOCHIGOMA_KIN_INDEX + 5;
-const MOCHIGOMA_HISHA_INDEX:usize = MOCHIGOMA_KAKU_INDEX + 3;
-const OPPONENT_MOCHIGOMA_FU_INDEX:usize = MOCHIGOMA_HISHA_INDEX + 3;
-const OPPONENT_MOCHIGOMA_KYOU_INDEX:usize = OPPONENT_MOCHIGOMA_FU_INDEX + 19;
-const OPPONENT_MOCHIGOMA_KEI_INDEX:usize = OPPONENT_MOCHIGOMA_KYOU_INDEX + 5;
-const OPPONENT_MOCHIGOMA_GIN_INDEX:usize = OPPONENT_MOCHIGOMA_KEI_INDEX + 5;
-const OPPONENT_MOCHIGOMA_KIN_INDEX:usize = OPPONENT_MOCHIGOMA_GIN_INDEX + 5;
-const OPPONENT_MOCHIGOMA_KAKU_INDEX:usize = OPPONENT_MOCHIGOMA_KIN_INDEX + 5;
-const OPPONENT_MOCHIGOMA_HISHA_INDEX:usize = OPPONENT_MOCHIGOMA_KAKU_INDEX + 3;
-
-const SELF_INDEX_MAP:[usize; 7] = [
-	MOCHIGOMA_FU_INDEX,
-	MOCHIGOMA_KYOU_INDEX,
-	MOCHIGOMA_KEI_INDEX,
-	MOCHIGOMA_GIN_INDEX,
-	MOCHIGOMA_KIN_INDEX,
-	MOCHIGOMA_KAKU_INDEX,
-	MOCHIGOMA_HISHA_INDEX
-];
-
-const OPPONENT_INDEX_MAP:[usize; 7] = [
-	OPPONENT_MOCHIGOMA_FU_INDEX,
-	OPPONENT_MOCHIGOMA_KYOU_INDEX,
-	OPPONENT_MOCHIGOMA_KEI_INDEX,
-	OPPONENT_MOCHIGOMA_GIN_INDEX,
-	OPPONENT_MOCHIGOMA_KIN_INDEX,
-	OPPONENT_MOCHIGOMA_KAKU_INDEX,
-	OPPONENT_MOCHIGOMA_HISHA_INDEX
-];
 const SCALE:f32 = 1.;
+/// Default value of the coefficient converting the winning probability to the evaluation in centipawns.
+pub const DEFAULT_EVAL_SCALE:f32 = 600.;
+/// Upper limit of the absolute value of the evaluation in centipawns.
+pub const EVAL_MAX:i32 = 30000;
+/// Evaluations within this value of 0 are regarded as a draw,
+/// and by default the search accepts the repetition draw only when it evaluates the position below -DRAW_VALUE.
+pub const DRAW_VALUE:i32 = 100;
+
+/// Converts the output of the ensemble to the evaluation in centipawns.
+///
+/// The ensemble estimates the expected game result in [-1,1], so the winning probability p is (1 + answer) / 2,
+/// and the evaluation is eval_scale * ln(p / (1 - p)), that is, eval_scale centipawns wins e / (1 + e) of the games.
+pub fn centipawn(answer:f32,eval_scale:f32) -> i32 {
+	let cp = eval_scale as f64 * logit(answer);
+
+	cp.round().max(-EVAL_MAX as f64).min(EVAL_MAX as f64) as i32
+}
 
-pub struct IntelligenceCreator;
-impl IntelligenceCreator {
-	pub fn create(savedir:String,nna_filename:String,nnb_filename:String)
-		-> Result<Intelligence<impl ForwardAll<Input=DiffInput<DiffArr<f32,2517>,f32,2517,256>,Output=Arr<f32,1>> +
-							 PreTrain<f32> + ForwardDiff<f32> +
-							 AskDiffInput<f32,DiffInput=Arr<f32,256>> + Send + Sync + 'static>,ApplicationError> {
+/// ln(p / (1 - p)) of the winning probability p estimated from the output of the ensemble.
+pub fn logit(answer:f32) -> f64 {
+	let v = (answer as f64).max(-1. + 1e-12).min(1. - 1e-12);
+
+	((1. + v) / (1. - v)).ln()
+}
+
+/// How the members of the ensemble learn the game result.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum EnsembleTargets {
+	/// Each of the N members learns 1 / N of the result, and the outputs are summed.
+	Split,
+	/// Each member learns the result multiplied by random ratios summing to 1 per position, and the outputs are summed.
+	ShakeShake,
+	/// Each member learns the result as it is, and the outputs are averaged.
+	Independent,
+}
+impl EnsembleTargets {
+	pub fn parse(s:&str) -> Result<EnsembleTargets,ApplicationError> {
+		match s {
+			"split" => Ok(EnsembleTargets::Split),
+			"shakeshake" => Ok(EnsembleTargets::ShakeShake),
+			"independent" => Ok(EnsembleTargets::Independent),
+			_ => Err(ApplicationError::StartupError(String::from(
+				"アンサンブルの学習方法の指定が不正です。(split, shakeshake, independentのいずれかを指定してください)"
+			)))
+		}
+	}
+
+	/// Ratios of the game result learned by the members for one position.
+	pub fn ratios(&self,members:usize,rnd:&mut XorShiftRng) -> Vec<f32> {
+		match *self {
+			EnsembleTargets::Split => vec![1. / members as f32; members],
+			EnsembleTargets::ShakeShake => {
+				let r = (0..members).map(|_| rnd.gen::<f32>()).collect::<Vec<f32>>();
+				let sum = r.iter().sum::<f32>();
+
+				if sum > 0. {
+					r.into_iter().map(|r| r / sum).collect()
+				} else {
+					vec![1. / members as f32; members]
+				}
+			},
+			EnsembleTargets::Independent => vec![1.; members],
+		}
+	}
+}
+/// Model files of the members of the ensemble, and the weights of their outputs at the inference.
+#[derive(Debug,Clone)]
+pub struct Ensemble {
+	pub filenames:Vec<String>,
+	pub weights:Vec<f32>,
+	pub targets:EnsembleTargets,
+}
+impl Ensemble {
+	/// Without the weights, the outputs are summed or averaged according to how the members learned.
+	pub fn new(filenames:Vec<String>,weights:Option<Vec<f32>>,targets:EnsembleTargets) -> Result<Ensemble,ApplicationError> {
+		if filenames.len() == 0 {
+			return Err(ApplicationError::StartupError(String::from(
+				"アンサンブルのモデルファイルが指定されていません。"
+			)));
+		}
+
+		let weights = match weights {
+			Some(weights) if weights.len() != filenames.len() => {
+				return Err(ApplicationError::StartupError(String::from(
+					"アンサンブルの重みの数がモデルファイルの数と一致しません。"
+				)));
+			},
+			Some(weights) => weights,
+			None if targets == EnsembleTargets::Independent => vec![1. / filenames.len() as f32; filenames.len()],
+			None => vec![1.; filenames.len()],
+		};
+
+		Ok(Ensemble {
+			filenames:filenames,
+			weights:weights,
+			targets:targets,
+		})
+	}
+
+	/// The ensemble with the same members read from the files with the suffix added to the name. (nn.a.bin -> nn_opponent.a.bin)
+	pub fn with_suffix(&self,suffix:&str) -> Ensemble {
+		let filenames = self.filenames.iter().map(|f| {
+			match f.find('.') {
+				Some(i) => format!("{}{}{}",&f[..i],suffix,&f[i..]),
+				None => format!("{}{}",f,suffix),
+			}
+		}).collect();
+
+		Ensemble {
+			filenames:filenames,
+			weights:self.weights.clone(),
+			targets:self.targets,
+		}
+	}
+
+	/// The ensemble read from the other files. (the weights are kept when the number of the members is the same)
+	pub fn with_filenames(&self,filenames:&[String]) -> Result<Ensemble,ApplicationError> {
+		if filenames.len() == 0 || filenames == &self.filenames[..] {
+			Ok(self.clone())
+		} else if filenames.len() == self.filenames.len() {
+			Ensemble::new(filenames.to_vec(),Some(self.weights.clone()),self.targets)
+		} else {
+			Ensemble::new(filenames.to_vec(),None,self.targets)
+		}
+	}
+}
+
+/// Left-right mirror augmentation of the positions used for the training.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Augmentation {
+	None,
+	/// Learns both the original and the mirrored positions.
+	Double,
+	/// Learns either the original or the mirrored position chosen at random.
+	Random,
+}
+impl Augmentation {
+	pub fn parse(s:&str) -> Result<Augmentation,ApplicationError> {
+		match s {
+			"none" => Ok(Augmentation::None),
+			"double" => Ok(Augmentation::Double),
+			"random" => Ok(Augmentation::Random),
+			_ => Err(ApplicationError::StartupError(String::from(
+				"左右反転による水増しの指定が不正です。(none, double, randomのいずれかを指定してください)"
+			)))
+		}
+	}
+}
+
+/// Winning probability of the evaluation in centipawns, the inverse of centipawn.
+pub fn win_rate(cp:i32,eval_scale:f32) -> f32 {
+	1. / (1. + (-cp as f32 / eval_scale).exp())
+}
+
+/// Network of the evaluation function, independent of the number of units in the hidden layers.
+pub trait Network: Send + Sync + 'static {
+	/// Feature set encoding the input of the network.
+	type Features: FeatureSet;
+	/// Output of the first layer, updated incrementally along the search.
+	type Accumulator: Clone + Send + Sync + 'static;
+
+	fn forward_input(&self,input:<Self::Features as FeatureSet>::Input) -> Result<f32,EvaluateError>;
+	fn accumulate(&self,input:<Self::Features as FeatureSet>::Input) -> Result<Self::Accumulator,EvaluateError>;
+	/// Writes the accumulator updated by the difference of the input to dst,
+	/// and returns the output of the upper layers recomputed from it.
+	fn accumulate_diff(&self,accumulator:&Self::Accumulator,input:<Self::Features as FeatureSet>::DiffInput,dst:&mut Self::Accumulator)
+		-> Result<f32,EvaluateError>;
+}
+/// Network used by the training.
+pub trait TrainerNetwork<D>: Persistence<f32,ModelFilePersistence,Linear> where D: Device<f32> {
+	/// Feature set encoding the input of the network.
+	type Features: FeatureSet;
+
+	fn forward_input(&self,input:<Self::Features as FeatureSet>::Input) -> Result<f32,EvaluateError>;
+	fn batch_train(&mut self,expected:Vec<Arr<f32,1>>,input:Vec<<Self::Features as FeatureSet>::Input>,optimizer:&mut TrainerOptimizer)
+		-> Result<f32,TrainingError>;
+}
+/// Network combined with the feature set encoding its input.
+pub struct FeatureNetwork<F,NN> where F: FeatureSet {
+	nn:NN,
+	features:PhantomData<F>,
+}
+impl<F,NN> FeatureNetwork<F,NN> where F: FeatureSet {
+	pub fn new(nn:NN) -> FeatureNetwork<F,NN> {
+		FeatureNetwork {
+			nn:nn,
+			features:PhantomData::<F>,
+		}
+	}
+}
+impl<F,NN> Persistence<f32,ModelFilePersistence,Linear> for FeatureNetwork<F,NN>
+	where F: FeatureSet, NN: Persistence<f32,ModelFilePersistence,Linear> {
+	fn load(&mut self,persistence:&mut ModelFilePersistence) -> Result<(),ConfigReadError> {
+		self.nn.load(persistence)
+	}
+
+	fn save(&mut self,persistence:&mut ModelFilePersistence) -> Result<(),PersistenceError> {
+		self.nn.save(persistence)
+	}
+}
+impl<F,NN,const N:usize,const H:usize> Network for FeatureNetwork<F,NN>
+	where F: FeatureSet<Input=Arr<f32,N>,DiffInput=DiffArr<f32,N>>,
+		  NN: ForwardAll<Input=DiffInput<DiffArr<f32,N>,f32,N,H>,Output=Arr<f32,1>> +
+			  PreTrain<f32> + ForwardDiff<f32> + AskDiffInput<f32,DiffInput=Arr<f32,H>> + Send + Sync + 'static {
+	type Features = F;
+	type Accumulator = Arr<f32,H>;
+
+	fn forward_input(&self,input:Arr<f32,N>) -> Result<f32,EvaluateError> {
+		Ok(self.nn.forward_all(DiffInput::NotDiff(input))?[0])
+	}
+
+	fn accumulate(&self,input:Arr<f32,N>) -> Result<Self::Accumulator,EvaluateError> {
+		Ok(self.nn.ask_diff_input(&self.nn.forward_diff(DiffInput::NotDiff(input))?))
+	}
+
+	fn accumulate_diff(&self,accumulator:&Self::Accumulator,input:DiffArr<f32,N>,dst:&mut Self::Accumulator)
+		-> Result<f32,EvaluateError> {
+		let s = self.nn.forward_diff(DiffInput::Diff(input,accumulator.clone()))?;
+
+		*dst = self.nn.ask_diff_input(&s);
+
+		Ok(s.map(|ans| ans[0]))
+	}
+}
+impl<F,NN,D,const N:usize> TrainerNetwork<D> for FeatureNetwork<F,NN>
+	where F: FeatureSet<Input=Arr<f32,N>,DiffInput=DiffArr<f32,N>>,
+		  NN: ForwardAll<Input=Arr<f32,N>,Output=Arr<f32,1>> +
+			  BatchForwardBase<BatchInput=VecArr<f32,Arr<f32,N>>,BatchOutput=VecArr<f32,Arr<f32,1>>> +
+			  BatchTrain<f32,D> + Persistence<f32,ModelFilePersistence,Linear>,
+		  D: Device<f32>,
+		  Mse<f32>: BatchLossFunction<f32,D> {
+	type Features = F;
+
+	fn forward_input(&self,input:Arr<f32,N>) -> Result<f32,EvaluateError> {
+		Ok(self.nn.forward_all(input)?[0])
+	}
+
+	fn batch_train(&mut self,expected:Vec<Arr<f32,1>>,input:Vec<Arr<f32,N>>,optimizer:&mut TrainerOptimizer)
+		-> Result<f32,TrainingError> {
+		let lossf = Mse::new();
+
+		self.nn.batch_train(expected.into(),input.into(),optimizer,&lossf)
+	}
+}
+/// Layer configuration of the network, shared by the inference and the training.
+pub trait Architecture {
+	/// Name of the preset specified by architecture in settings.toml.
+	const NAME:&'static str;
+	/// Number of units in the first and the second hidden layers.
+	const UNITS:(usize,usize);
+	/// Whether the network can be converted to the quantized network. (only the presets using ReLU)
+	const QUANTIZABLE:bool;
+	/// Whether the model files without the header are read as this preset. (only the preset of the files written before the header)
+	const LEGACY:bool;
+
+	/// Feature set of the input of the network.
+	type Features: FeatureSet;
+
+	fn create_network(device:DeviceCpu<f32>)
+		-> Result<impl Network<Features=Self::Features> + Persistence<f32,ModelFilePersistence,Linear>,ApplicationError>;
+
+	fn create_trainer_network(device:DeviceGpu<f32>)
+		-> Result<impl TrainerNetwork<DeviceGpu<f32>,Features=Self::Features>,ApplicationError>;
+
+	fn create_cpu_trainer_network(device:DeviceCpu<f32>)
+		-> Result<impl TrainerNetwork<DeviceCpu<f32>,Features=Self::Features>,ApplicationError>;
+
+	fn parameters() -> usize {
+		let (h1,h2) = Self::UNITS;
+
+		(<Self::Features as FeatureSet>::INPUTS + 1) * h1 + (h1 + 1) * h2 + h2 + 1
+	}
+
+	fn load<NN>(nn:&mut NN,file:&str) -> Result<Option<ModelHeader>,ApplicationError>
+		where NN: Persistence<f32,ModelFilePersistence,Linear> {
+		let mut p = ModelFilePersistence::open(file)?;
+
+		let header = p.header().cloned();
+
+		if let Some(ref header) = header {
+			if header.quantization.is_some() {
+				return Err(ApplicationError::StartupError(format!(
+					"{}は量子化されたモデルファイルです。",file
+				)));
+			}
+
+			if p.version() != Some(FORMAT_VERSION) {
+				return Err(ApplicationError::StartupError(format!(
+					"{}は対応していない形式バージョン({})のモデルファイルです。",
+					file, p.version().unwrap_or(0)
+				)));
+			}
+
+			if header.architecture != Self::NAME {
+				return Err(ApplicationError::StartupError(format!(
+					"{}はネットワーク構成{}のモデルファイルです。(設定されているネットワーク構成: {})",
+					file, header.architecture, Self::NAME
+				)));
+			}
+
+			if header.feature_set != <Self::Features as FeatureSet>::NAME {
+				return Err(ApplicationError::StartupError(format!(
+					"{}は特徴量{}のモデルファイルです。(対応している特徴量: {})",
+					file, header.feature_set, <Self::Features as FeatureSet>::NAME
+				)));
+			}
+		} else if !Self::LEGACY {
+			return Err(ApplicationError::StartupError(format!(
+				"{}はヘッダのないモデルファイルです。ヘッダのないモデルファイルはネットワーク構成{}では読み込めません。",
+				file, Self::NAME
+			)));
+		}
+
+		let size = p.weights_size();
+		let expected = (Self::parameters() * 4) as u64;
 
+		if size != expected {
+			return Err(ApplicationError::StartupError(format!(
+				"{}はネットワーク構成{}のモデルファイルではありません。(パラメータのサイズ: {}, 想定されるサイズ: {})",
+				file, Self::NAME, size, expected
+			)));
+		}
+
+		nn.load(&mut p)?;
+
+		Ok(header)
+	}
+}
+macro_rules! cpu_layer {
+	($layer:expr) => { Ok($layer) }
+}
+macro_rules! gpu_layer {
+	($layer:expr) => { Ok($layer?) }
+}
+/// Layer chain of the presets, on the device with the input and the first layer given by the caller.
+macro_rules! network {
+	($device:ident, $d:ty, $lift:ident, $input:ty, $first:ident, $features:ty, $h1:expr, $h2:expr, $activation:ident, $output_activation:ident) => {{
 		let mut rnd = prelude::thread_rng();
 		let rnd_base = Rc::new(RefCell::new(XorShiftRng::from_seed(rnd.gen())));
 
-		let n1 = Normal::<f32>::new(0.0, (2f32/2517f32).sqrt()).unwrap();
-		let n2 = Normal::<f32>::new(0.0, (2f32/256f32).sqrt()).unwrap();
-		let n3 = Normal::<f32>::new(0.0, 1f32/32f32.sqrt()).unwrap();
+		const NI:usize = <$features as FeatureSet>::INPUTS;
 
-		let device = DeviceCpu::new()?;
+		let n1 = Normal::<f32>::new(0.0, (2f32/NI as f32).sqrt()).unwrap();
+		let n2 = Normal::<f32>::new(0.0, (2f32/$h1 as f32).sqrt()).unwrap();
+		let n3 = Normal::<f32>::new(0.0, 1f32/($h2 as f32).sqrt()).unwrap();
 
-		let net:InputLayer<f32,DiffInput<DiffArr<f32,2517>,f32,2517,256>,_> = InputLayer::new();
+		let net:InputLayer<f32,$input,_> = InputLayer::new();
 
 		let rnd = rnd_base.clone();
 
-		let mut nna = net.add_layer(|l| {
+		Ok(FeatureNetwork::new(net.try_add_layer(|l| {
 			let rnd = rnd.clone();
-			DiffLinearLayer::<_,_,_,DeviceCpu<f32>,_,2517,256>::new(l,&device, move || n1.sample(&mut rnd.borrow_mut().deref_mut()), || 0.)
-		}).add_layer(|l| {
-			ActivationLayer::new(l,ReLu::new(&device),&device)
-		}).add_layer(|l| {
+			$lift!($first::<_,_,_,$d,_,NI,$h1>::new(l,&$device, move || n1.sample(&mut rnd.borrow_mut().deref_mut()), || 0.))
+		})?.add_layer(|l| {
+			ActivationLayer::new(l,$activation::new(&$device),&$device)
+		}).try_add_layer(|l| {
 			let rnd = rnd.clone();
-			LinearLayer::<_,_,_,DeviceCpu<f32>,_,256,32>::new(l,&device, move || n2.sample(&mut rnd.borrow_mut().deref_mut()), || 0.)
-		}).add_layer(|l| {
-			ActivationLayer::new(l,ReLu::new(&device),&device)
-		}).add_layer(|l| {
+			$lift!(LinearLayer::<_,_,_,$d,_,$h1,$h2>::new(l,&$device, move || n2.sample(&mut rnd.borrow_mut().deref_mut()), || 0.))
+		})?.add_layer(|l| {
+			ActivationLayer::new(l,$activation::new(&$device),&$device)
+		}).try_add_layer(|l| {
 			let rnd = rnd.clone();
-			LinearLayer::<_,_,_,DeviceCpu<f32>,_,32,1>::new(l,&device, move || n3.sample(&mut rnd.borrow_mut().deref_mut()), || 0.)
-		}).add_layer(|l| {
-			ActivationLayer::new(l,Tanh::new(&device),&device)
+			$lift!(LinearLayer::<_,_,_,$d,_,$h2,1>::new(l,&$device, move || n3.sample(&mut rnd.borrow_mut().deref_mut()), || 0.))
+		})?.add_layer(|l| {
+			ActivationLayer::new(l,$output_activation::new(&$device),&$device)
 		}).add_layer_train(|l| {
-			LinearOutputLayer::new(l,&device)
-		});
+			LinearOutputLayer::new(l,&$device)
+		})))
+	}}
+}
+macro_rules! architecture {
+	($name:ident, $id:expr, $features:ty, $h1:expr, $h2:expr, $activation:ident, $output_activation:ident, $quantizable:expr, $legacy:expr) => {
+		pub struct $name;
+		impl Architecture for $name {
+			const NAME:&'static str = $id;
+			const UNITS:(usize,usize) = ($h1,$h2);
+			const QUANTIZABLE:bool = $quantizable;
+			const LEGACY:bool = $legacy;
+
+			type Features = $features;
+
+			fn create_network(device:DeviceCpu<f32>)
+				-> Result<impl Network<Features=Self::Features> + Persistence<f32,ModelFilePersistence,Linear>,ApplicationError> {
+				network!(device, DeviceCpu<f32>, cpu_layer, DiffInput<DiffArr<f32,NI>,f32,NI,$h1>, DiffLinearLayer,
+						 $features, $h1, $h2, $activation, $output_activation)
+			}
 
-		let mut rnd = prelude::thread_rng();
-		let rnd_base = Rc::new(RefCell::new(XorShiftRng::from_seed(rnd.gen())));
+			fn create_trainer_network(device:DeviceGpu<f32>)
+				-> Result<impl TrainerNetwork<DeviceGpu<f32>,Features=Self::Features>,ApplicationError> {
+				network!(device, DeviceGpu<f32>, gpu_layer, Arr<f32,NI>, LinearLayer,
+						 $features, $h1, $h2, $activation, $output_activation)
+			}
 
-		let n1 = Normal::<f32>::new(0.0, (2f32/2517f32).sqrt()).unwrap();
-		let n2 = Normal::<f32>::new(0.0, (2f32/256f32).sqrt()).unwrap();
-		let n3 = Normal::<f32>::new(0.0, 1f32/32f32.sqrt()).unwrap();
+			fn create_cpu_trainer_network(device:DeviceCpu<f32>)
+				-> Result<impl TrainerNetwork<DeviceCpu<f32>,Features=Self::Features>,ApplicationError> {
+				network!(device, DeviceCpu<f32>, cpu_layer, Arr<f32,NI>, LinearLayer,
+						 $features, $h1, $h2, $activation, $output_activation)
+			}
+		}
+	}
+}
+architecture!(StandardArchitecture, "standard", InputCreator, 256, 32, ReLu, Tanh, true, true);
+architecture!(WideArchitecture, "wide", InputCreator, 512, 32, ReLu, Tanh, true, false);
+architecture!(SwishArchitecture, "swish", InputCreator, 256, 32, Swish, Tanh, false, false);
+architecture!(KingRelativeArchitecture, "kingrelative", KingRelativeInputCreator, 256, 32, ReLu, Tanh, true, false);
+
+pub struct IntelligenceCreator;
+impl IntelligenceCreator {
+	/// Creates the evaluation by the models, or by the material when it is selected or any of the model files is missing.
+	pub fn create<A: Architecture>(savedir:String,ensemble:Ensemble,material:bool)
+		-> Result<Intelligence<impl Network>,ApplicationError> {
+		if material {
+			return Ok(IntelligenceCreator::create_material());
+		}
+
+		if let Some(file) = IntelligenceCreator::missing_file(&savedir,&ensemble.filenames) {
+			return Ok(IntelligenceCreator::create_material().with_fallback(file));
+		}
 
 		let device = DeviceCpu::new()?;
 
-		let net:InputLayer<f32,DiffInput<DiffArr<f32,2517>,f32,2517,256>,_> = InputLayer::new();
+		let mut networks = Vec::with_capacity(ensemble.filenames.len());
 
-		let rnd = rnd_base.clone();
+		for filename in ensemble.filenames.iter() {
+			let mut nn = A::create_network(device.clone())?;
 
-		let mut nnb = net.add_layer(|l| {
-			let rnd = rnd.clone();
-			DiffLinearLayer::<_,_,_,DeviceCpu<f32>,_,2517,256>::new(l,&device, move || n1.sample(&mut rnd.borrow_mut().deref_mut()), || 0.)
-		}).add_layer(|l| {
-			ActivationLayer::new(l,ReLu::new(&device),&device)
-		}).add_layer(|l| {
-			let rnd = rnd.clone();
-			LinearLayer::<_,_,_,DeviceCpu<f32>,_,256,32>::new(l,&device, move || n2.sample(&mut rnd.borrow_mut().deref_mut()), || 0.)
-		}).add_layer(|l| {
-			ActivationLayer::new(l,ReLu::new(&device),&device)
-		}).add_layer(|l| {
-			let rnd = rnd.clone();
-			LinearLayer::<_,_,_,DeviceCpu<f32>,_,32,1>::new(l,&device, move || n3.sample(&mut rnd.borrow_mut().deref_mut()), || 0.)
-		}).add_layer(|l| {
-			ActivationLayer::new(l,Tanh::new(&device),&device)
-		}).add_layer_train(|l| {
-			LinearOutputLayer::new(l,&device)
-		});
+			A::load(&mut nn,&format!("{}/{}", savedir, filename))?;
 
-		if Path::new(&format!("{}/{}",savedir,nna_filename)).exists() {
-			let mut pa = BinFilePersistence::new(
-				&format!("{}/{}", savedir, nna_filename))?;
+			networks.push(FallbackNetwork::Model(nn));
+		}
+
+		Ok(Intelligence::new(networks,ensemble.weights))
+	}
+
+	pub fn create_quantized<A: Architecture>(savedir:String,ensemble:Ensemble,material:bool)
+		-> Result<Intelligence<FallbackNetwork<QuantizedNetwork<A::Features>>>,ApplicationError>
+		where QuantizedNetwork<A::Features>: Network<Features=A::Features> {
+		if material {
+			return Ok(IntelligenceCreator::create_material());
+		}
 
-			nna.load(&mut pa)?;
+		if let Some(file) = IntelligenceCreator::missing_file(&savedir,&ensemble.filenames) {
+			return Ok(IntelligenceCreator::create_material().with_fallback(file));
 		}
 
-		if Path::new(&format!("{}/{}",savedir,nnb_filename)).exists() {
-			let mut pb = BinFilePersistence::new(
-				&format!("{}/{}", savedir, nna_filename))?;
+		if !A::QUANTIZABLE {
+			return Err(ApplicationError::StartupError(format!(
+				"ネットワーク構成{}は量子化に対応していません。",A::NAME
+			)));
+		}
+
+		let mut networks = Vec::with_capacity(ensemble.filenames.len());
+
+		for filename in ensemble.filenames.iter() {
+			let file = format!("{}/{}",savedir,quantized_filename(filename));
+
+			if !Path::new(&file).exists() {
+				return Err(ApplicationError::StartupError(format!(
+					"量子化されたモデルファイル{}が見つかりません。--quantizeで変換してください。",file
+				)));
+			}
 
-			nnb.load(&mut pb)?;
+			networks.push(FallbackNetwork::Model(QuantizedNetwork::load::<A>(&file)?));
 		}
 
-		Ok(Intelligence::new(nna,nnb))
+		Ok(Intelligence::new(networks,ensemble.weights))
+	}
+
+	fn create_material<NN: Network>() -> Intelligence<FallbackNetwork<NN>> {
+		Intelligence::new(vec![FallbackNetwork::Material(MaterialNetwork::new())],vec![1.])
+	}
+
+	fn missing_file(savedir:&str,filenames:&[String]) -> Option<String> {
+		filenames.iter().map(|f| format!("{}/{}",savedir,f)).find(|f| !Path::new(f).exists())
+	}
+
+}
+/// Warning that the evaluation falls back to the material because the model file is missing.
+pub fn fallback_warning(file:&str) -> String {
+	format!("警告: モデルファイル{}が見つからないため、駒割りによる評価関数を使用します。",file)
+}
+impl<NN> Clone for Intelligence<NN>
+	where NN: Network {
+	fn clone(&self) -> Self {
+		Intelligence {
+			networks:self.networks.clone(),
+			weights:self.weights.clone(),
+			eval_scale:self.eval_scale,
+			quited:self.quited,
+			fallback:self.fallback.clone(),
+		}
 	}
 }
 impl<NN> Intelligence<NN>
-	where NN: ForwardAll<Input=DiffInput<DiffArr<f32,2517>,f32,2517,256>,Output=Arr<f32,1>> +
-			  PreTrain<f32> + ForwardDiff<f32> +
-			  AskDiffInput<f32,DiffInput=Arr<f32,256>> + Send + Sync + 'static {
-	pub fn new(nna:NN,nnb:NN) -> Intelligence<NN> {
+	where NN: Network {
+	pub fn new(networks:Vec<NN>,weights:Vec<f32>) -> Intelligence<NN> {
 		Intelligence {
-			nna:nna,
-			nnb:nnb,
+			networks:Arc::new(networks),
+			weights:weights,
+			eval_scale:DEFAULT_EVAL_SCALE,
 			quited:false,
+			fallback:None,
 		}
 	}
 
-	pub fn make_snapshot(&self, is_self:bool, t:Teban, b:&Banmen, mc:&MochigomaCollections) -> Result<(<NN as PreTrain<f32>>::OutStack, <NN as PreTrain<f32>>::OutStack), CommonError>
-	{
+	fn with_fallback(mut self,file:String) -> Intelligence<NN> {
+		self.fallback = Some(file);
+		self
+	}
+
+	/// Missing model file, if the evaluation fell back to the material.
+	pub fn fallback(&self) -> Option<&str> {
+		self.fallback.as_deref()
+	}
 
-		let sa = self.nna.forward_diff(DiffInput::NotDiff(InputCreator::make_input(
-			is_self,t,b,mc
-		) * SCALE))?;
+	pub fn eval_scale(&self) -> f32 {
+		self.eval_scale
+	}
 
-		let sb = self.nnb.forward_diff(DiffInput::NotDiff(InputCreator::make_input(
-			is_self,t,b,mc
-		) * SCALE))?;
+	pub fn set_eval_scale(&mut self,eval_scale:f32) {
+		self.eval_scale = eval_scale;
+	}
 
-		Ok((sa,sb))
+	pub fn weights(&self) -> &[f32] {
+		&self.weights
 	}
 
-	pub fn evalute(&self, is_self:bool, t:Teban, b:&Banmen, mc:&MochigomaCollections) -> Result<i32, CommonError>
+	pub fn networks(&self) -> &[NN] {
+		&self.networks
+	}
+
+	/// Output of the ensemble, the weighted sum of the outputs of the members.
+	pub fn combine(&self,outputs:&[f32]) -> f32 {
+		outputs.iter().zip(self.weights.iter()).map(|(o,w)| o * w).sum()
+	}
+
+	pub fn make_accumulator(&self, is_self:bool, t:Teban, b:&Banmen, mc:&MochigomaCollections) -> Result<Accumulators<NN>, CommonError>
 	{
-		let input = InputCreator::make_input(is_self,t,b,mc);
+		let input = <NN::Features as FeatureSet>::make_input(is_self,t,b,mc);
 
-		let nnaanswera = self.nna.forward_all(DiffInput::NotDiff(input.clone() * SCALE))?;
-		let nnbanswerb = self.nnb.forward_all(DiffInput::NotDiff(input.clone() * SCALE))?;
+		let mut accumulators = Vec::with_capacity(self.networks.len());
 
-		let answer = nnaanswera[0] + nnbanswerb[0];
+		for nn in self.networks.iter() {
+			accumulators.push(nn.accumulate(input.clone() * SCALE)?);
+		}
 
-		Ok((answer * (1 << 29) as f32) as i32)
+		Ok(accumulators)
 	}
 
-	pub fn evalute_by_diff(&self, snapshot:&(<NN as PreTrain<f32>>::OutStack,<NN as PreTrain<f32>>::OutStack), is_self:bool, t:Teban, b:&Banmen, mc:&MochigomaCollections, m:&Move)
-		-> Result<(i32,(<NN as PreTrain<f32>>::OutStack,<NN as PreTrain<f32>>::OutStack)),CommonError> {
-		let (sa,sb) = snapshot;
-
-		let input = InputCreator::make_diff_input(is_self, t, b, mc, m)?;
-		let o = self.nna.ask_diff_input(sa);
+	pub fn evalute(&self, is_self:bool, t:Teban, b:&Banmen, mc:&MochigomaCollections) -> Result<i32, CommonError>
+	{
+		let outputs = self.evalute_by_networks(is_self,t,b,mc)?;
 
-		let sa = self.nna.forward_diff(DiffInput::Diff(input.clone() * SCALE,o))?;
+		Ok(centipawn(self.combine(&outputs),self.eval_scale))
+	}
 
-		let o = self.nnb.ask_diff_input(sb);
+	pub fn evalute_by_networks(&self, is_self:bool, t:Teban, b:&Banmen, mc:&MochigomaCollections) -> Result<Vec<f32>, CommonError>
+	{
+		let input = <NN::Features as FeatureSet>::make_input(is_self,t,b,mc);
 
-		let sb = self.nna.forward_diff(DiffInput::Diff(input.clone() * SCALE,o))?;
+		let mut outputs = Vec::with_capacity(self.networks.len());
 
-		let answer = sa.map(|ans| ans[0].clone()) + sb.map(|ans| ans[0].clone());
+		for nn in self.networks.iter() {
+			outputs.push(nn.forward_input(input.clone() * SCALE)?);
+		}
 
-		Ok(((answer * (1 << 29) as f32) as i32,(sa,sb)))
+		Ok(outputs)
 	}
 
-	pub fn evalute_by_snapshot(&self,snapshot:&(<NN as PreTrain<f32>>::OutStack,<NN as PreTrain<f32>>::OutStack)) -> i32 {
-		match snapshot {
-			(sa,sb) => {
-				let nnaanswera = sa.map(|ans| ans[0].clone());
-				let nnbanswerb = sb.map(|ans| ans[0].clone());
+	pub fn evalute_by_diff(&self, accumulator:&Accumulators<NN>, is_self:bool, t:Teban, b:&Banmen, mc:&MochigomaCollections, m:&Move,
+						   mover:Teban, dst:&mut Accumulators<NN>) -> Result<i32,CommonError> {
+		let input = <NN::Features as FeatureSet>::make_diff_input(is_self, t, b, mc, m, mover)?;
 
-				let answer = nnaanswera + nnbanswerb;
+		if dst.len() != self.networks.len() {
+			dst.clone_from(accumulator);
+		}
 
-				(answer * (1 << 29) as f32) as i32
-			}
+		let mut answer = 0.;
+
+		for (((nn,w),accumulator),dst) in self.networks.iter().zip(self.weights.iter()).zip(accumulator.iter()).zip(dst.iter_mut()) {
+			answer += nn.accumulate_diff(accumulator,input.clone() * SCALE,dst)? * w;
 		}
+
+		Ok(centipawn(answer,self.eval_scale))
 	}
 
 	#[allow(dead_code)]
@@ -298,136 +667,228 @@ impl<NN> Intelligence<NN>
 		Ok(())
 	}
 }
-pub struct Trainer<NN>
-	where NN: BatchTrain<f32,DeviceGpu<f32>> + ForwardAll + Persistence<f32,BinFilePersistence<f32>,Linear> {
+/// Accumulators of the positions on the current search path, pushed on make and popped on unmake.
+///
+/// Each entry keeps the accumulators from the side to move and from the other side.
+/// The entries above the current depth are left in place to reuse their buffers, and are not copied by clone.
+pub struct AccumulatorStack<NN>
+	where NN: Network {
+	entries:Vec<(Accumulators<NN>,Accumulators<NN>)>,
+	len:usize,
+}
+impl<NN> Clone for AccumulatorStack<NN>
+	where NN: Network {
+	fn clone(&self) -> Self {
+		AccumulatorStack {
+			entries:self.entries[..self.len].to_vec(),
+			len:self.len,
+		}
+	}
+}
+impl<NN> AccumulatorStack<NN>
+	where NN: Network {
+	pub fn new() -> AccumulatorStack<NN> {
+		AccumulatorStack {
+			entries:Vec::new(),
+			len:0,
+		}
+	}
+
+	/// Starts the stack from the root position.
+	pub fn reset(&mut self,evalutor:&Intelligence<NN>,teban:Teban,b:&Banmen,mc:&MochigomaCollections) -> Result<(),CommonError> {
+		let sa = evalutor.make_accumulator(true,teban,b,mc)?;
+		let oa = evalutor.make_accumulator(false,teban.opposite(),b,mc)?;
+
+		self.entries.clear();
+		self.entries.push((sa,oa));
+		self.len = 1;
+
+		Ok(())
+	}
 
-	nna:NN,
-	nnb:NN,
-	optimizer:Adam<f32>,
-	nna_filename:String,
-	nnb_filename:String,
+	fn next_entry(&mut self)
+		-> Result<(&(Accumulators<NN>,Accumulators<NN>),&mut (Accumulators<NN>,Accumulators<NN>)),CommonError> {
+		if self.len == 0 {
+			return Err(CommonError::Fail(String::from("accumulator stack is empty!")));
+		}
+
+		if self.entries.len() == self.len {
+			let e = self.entries[self.len - 1].clone();
+			self.entries.push(e);
+		}
+
+		let (current,next) = self.entries.split_at_mut(self.len);
+
+		Ok((&current[self.len - 1],&mut next[0]))
+	}
+
+	/// Pushes the position after the move m, and returns the evaluation from the side to move (teban) there.
+	pub fn push(&mut self,evalutor:&Intelligence<NN>,teban:Teban,b:&Banmen,mc:&MochigomaCollections,m:&Move) -> Result<i32,CommonError> {
+		let s = {
+			let (current,next) = self.next_entry()?;
+
+			let s = evalutor.evalute_by_diff(&current.1,true,teban,b,mc,m,teban.opposite(),&mut next.0)?;
+			evalutor.evalute_by_diff(&current.0,false,teban.opposite(),b,mc,m,teban.opposite(),&mut next.1)?;
+
+			s
+		};
+
+		self.len += 1;
+
+		Ok(s)
+	}
+
+	pub fn pop(&mut self) {
+		if self.len > 1 {
+			self.len -= 1;
+		}
+	}
+
+	/// Evaluates the position after the move m without pushing it.
+	pub fn evalute(&mut self,evalutor:&Intelligence<NN>,teban:Teban,b:&Banmen,mc:&MochigomaCollections,m:&Move) -> Result<i32,CommonError> {
+		let (current,next) = self.next_entry()?;
+
+		evalutor.evalute_by_diff(&current.1,true,teban,b,mc,m,teban.opposite(),&mut next.0)
+	}
+}
+pub struct Trainer<NN,D>
+	where NN: TrainerNetwork<D>,
+		  D: Device<f32> {
+
+	networks:Vec<NN>,
+	optimizer:TrainerOptimizer,
+	scheduler:LrScheduler,
+	metrics:Option<MetricsWriter>,
+	step:usize,
+	decoder:SampleDecoder<NN::Features>,
+	filenames:Vec<String>,
+	weights:Vec<f32>,
+	targets:EnsembleTargets,
 	nnsavedir:String,
-	packed_sfen_reader:PackedSfenReader,
-	hcpe_reader:HcpeReader,
-	bias_shake_shake:bool,
+	header:ModelHeader,
+	eval_scale:f32,
+	rnd:XorShiftRng,
+	device:PhantomData<D>,
 }
 pub struct TrainerCreator;
 
 impl TrainerCreator {
-	pub fn create(savedir:String, nna_filename:String, nnb_filename:String, enable_shake_shake:bool)
-		-> Result<Trainer<impl ForwardAll<Input=Arr<f32,2517>,Output=Arr<f32,1>> +
-						BatchForwardBase<BatchInput=VecArr<f32,Arr<f32,2517>>,BatchOutput=VecArr<f32,Arr<f32,1>>> +
-						BatchTrain<f32,DeviceGpu<f32>> + Persistence<f32,BinFilePersistence<f32>,Linear>>,ApplicationError> {
+	pub fn create<A: Architecture>(savedir:String, ensemble:Ensemble)
+		-> Result<Trainer<impl TrainerNetwork<DeviceGpu<f32>>,DeviceGpu<f32>>,ApplicationError> {
 
-		let mut rnd = prelude::thread_rng();
-		let rnd_base = Rc::new(RefCell::new(XorShiftRng::from_seed(rnd.gen())));
+		let memory_pool = Arc::new(Mutex::new(MemoryPool::with_size(1024 * 1024 * 1024 *  4,Alloctype::Device)?));
 
-		let n1 = Normal::<f32>::new(0.0, (2f32/2517f32).sqrt()).unwrap();
-		let n2 = Normal::<f32>::new(0.0, (2f32/256f32).sqrt()).unwrap();
-		let n3 = Normal::<f32>::new(0.0, 1f32/32f32.sqrt()).unwrap();
+		TrainerCreator::build::<A,_,_,_>(savedir,ensemble,|| {
+			A::create_trainer_network(DeviceGpu::new(&memory_pool)?)
+		})
+	}
 
-		let memory_pool = Arc::new(Mutex::new(MemoryPool::with_size(1024 * 1024 * 1024 *  4,Alloctype::Device)?));
+	pub fn create_cpu<A: Architecture>(savedir:String, ensemble:Ensemble)
+		-> Result<Trainer<impl TrainerNetwork<DeviceCpu<f32>>,DeviceCpu<f32>>,ApplicationError> {
 
-		let device = DeviceGpu::new(&memory_pool)?;
+		let device = DeviceCpu::new()?;
 
-		let net:InputLayer<f32,Arr<f32,2517>,_> = InputLayer::new();
+		TrainerCreator::build::<A,_,_,_>(savedir,ensemble,|| {
+			A::create_cpu_trainer_network(device.clone())
+		})
+	}
 
-		let rnd = rnd_base.clone();
+	/// Creates a member per file of the ensemble on the device and loads the weights of the files that exist.
+	fn build<A,NN,D,F>(savedir:String, ensemble:Ensemble, mut create:F)
+		-> Result<Trainer<NN,D>,ApplicationError>
+		where A: Architecture,
+			  NN: TrainerNetwork<D>,
+			  D: Device<f32>,
+			  F: FnMut() -> Result<NN,ApplicationError> {
 
-		let mut nna = net.try_add_layer(|l| {
-			let rnd = rnd.clone();
-			Ok(LinearLayer::<_,_,_,DeviceGpu<f32>,_,2517,256>::new(l,&device, move || n1.sample(&mut rnd.borrow_mut().deref_mut()), || 0.)?)
-		})?.add_layer(|l| {
-			ActivationLayer::new(l,ReLu::new(&device),&device)
-		}).try_add_layer(|l| {
-			let rnd = rnd.clone();
-			Ok(LinearLayer::<_,_,_,DeviceGpu<f32>,_,256,32>::new(l,&device, move || n2.sample(&mut rnd.borrow_mut().deref_mut()), || 0.)?)
-		})?.add_layer(|l| {
-			ActivationLayer::new(l,ReLu::new(&device),&device)
-		}).try_add_layer(|l| {
-			let rnd = rnd.clone();
-			Ok(LinearLayer::<_,_,_,DeviceGpu<f32>,_,32,1>::new(l,&device, move || n3.sample(&mut rnd.borrow_mut().deref_mut()), || 0.)?)
-		})?.add_layer(|l| {
-			ActivationLayer::new(l,Tanh::new(&device),&device)
-		}).add_layer_train(|l| {
-			LinearOutputLayer::new(l,&device)
-		});
+		let mut networks = Vec::with_capacity(ensemble.filenames.len());
+		let mut header = None;
 
-		let mut rnd = prelude::thread_rng();
-		let rnd_base = Rc::new(RefCell::new(XorShiftRng::from_seed(rnd.gen())));
+		for filename in ensemble.filenames.iter() {
+			let mut nn = create()?;
+
+			if Path::new(&format!("{}/{}",savedir,filename)).exists() {
+				let h = A::load(&mut nn,&format!("{}/{}", savedir, filename))?;
+
+				header = header.or(h);
+			}
 
-		let n1 = Normal::<f32>::new(0.0, (2f32/2517f32).sqrt()).unwrap();
-		let n2 = Normal::<f32>::new(0.0, (2f32/256f32).sqrt()).unwrap();
-		let n3 = Normal::<f32>::new(0.0, 1f32/32f32.sqrt()).unwrap();
+			networks.push(nn);
+		}
 
-		let device = DeviceGpu::new(&memory_pool)?;
+		let mut rnd = rand::thread_rng();
 
-		let net:InputLayer<f32,Arr<f32,2517>,_> = InputLayer::new();
+		Ok(Trainer {
+			networks:networks,
+			optimizer:TrainerOptimizer::default(),
+			scheduler:LrScheduler::new(LrSchedule::Constant,DEFAULT_LEARNING_RATE,0),
+			metrics:None,
+			step:0,
+			decoder:SampleDecoder::new(),
+			filenames:ensemble.filenames,
+			weights:ensemble.weights,
+			targets:ensemble.targets,
+			nnsavedir:savedir,
+			header:header.unwrap_or_else(|| ModelHeader::new(A::NAME,<A::Features as FeatureSet>::NAME)),
+			eval_scale:DEFAULT_EVAL_SCALE,
+			rnd:XorShiftRng::from_seed(rnd.gen()),
+			device:PhantomData::<D>,
+		})
+	}
+}
+impl<NN,D> Trainer<NN,D>
+	where NN: TrainerNetwork<D>,
+		  D: Device<f32> {
+	/// Trains the members on the pairs of the game result from the side to move and the input,
+	/// with the targets given according to EnsembleTargets, and returns the losses of the members.
+	fn train(&mut self,samples:Vec<(f32,<NN::Features as FeatureSet>::Input)>) -> Result<Vec<f32>,CommonError> {
+		let members = self.networks.len();
+		let positions = samples.len();
+
+		let mut batches = (0..members).map(|_| {
+			(Vec::with_capacity(samples.len()),Vec::with_capacity(samples.len()))
+		}).collect::<Vec<(Vec<Arr<f32,1>>,Vec<<NN::Features as FeatureSet>::Input>)>>();
+
+		for (t,input) in samples.into_iter() {
+			for (batch,r) in batches.iter_mut().zip(self.targets.ratios(members,&mut self.rnd).into_iter()) {
+				let mut ans = Arr::<f32,1>::new();
+				ans[0] = t * r;
 
-		let rnd = rnd_base.clone();
+				batch.0.push(ans);
+				batch.1.push(input.clone() * SCALE);
+			}
+		}
 
-		let mut nnb = net.try_add_layer(|l| {
-			let rnd = rnd.clone();
-			Ok(LinearLayer::<_,_,_,DeviceGpu<f32>,_,2517,256>::new(l,&device, move || n1.sample(&mut rnd.borrow_mut().deref_mut()), || 0.)?)
-		})?.add_layer(|l| {
-			ActivationLayer::new(l,ReLu::new(&device),&device)
-		}).try_add_layer(|l| {
-			let rnd = rnd.clone();
-			Ok(LinearLayer::<_,_,_,DeviceGpu<f32>,_,256,32>::new(l,&device, move || n2.sample(&mut rnd.borrow_mut().deref_mut()), || 0.)?)
-		})?.add_layer(|l| {
-			ActivationLayer::new(l,ReLu::new(&device),&device)
-		}).try_add_layer(|l| {
-			let rnd = rnd.clone();
-			Ok(LinearLayer::<_,_,_,DeviceGpu<f32>,_,32,1>::new(l,&device, move || n3.sample(&mut rnd.borrow_mut().deref_mut()), || 0.)?)
-		})?.add_layer(|l| {
-			ActivationLayer::new(l,Tanh::new(&device),&device)
-		}).add_layer_train(|l| {
-			LinearOutputLayer::new(l,&device)
-		});
+		let mut losses = Vec::with_capacity(members);
 
-		if Path::new(&format!("{}/{}",savedir,nna_filename)).exists() {
-			let mut pa = BinFilePersistence::new(
-				&format!("{}/{}", savedir, nna_filename))?;
+		let learning_rate = self.scheduler.next();
 
-			nna.load(&mut pa)?;
+		self.optimizer.set_learning_rate(learning_rate);
+
+		for (nn,(expected,input)) in self.networks.iter_mut().zip(batches.into_iter()) {
+			losses.push(nn.batch_train(expected,input,&mut self.optimizer)?);
 		}
 
-		if Path::new(&format!("{}/{}",savedir,nnb_filename)).exists() {
-			let mut pb = BinFilePersistence::new(
-				&format!("{}/{}", savedir, nna_filename))?;
+		self.step += 1;
 
-			nnb.load(&mut pb)?;
+		if let Some(ref mut metrics) = self.metrics {
+			metrics.train(self.step,self.header.epochs,self.header.positions,positions,learning_rate,&losses).map_err(|e| {
+				CommonError::Fail(format!("{}",e))
+			})?;
 		}
 
-		Ok(Trainer {
-			nna:nna,
-			nnb:nnb,
-			optimizer:Adam::new(),
-			nna_filename:nna_filename,
-			nnb_filename:nnb_filename,
-			nnsavedir:savedir,
-			packed_sfen_reader:PackedSfenReader::new(),
-			hcpe_reader:HcpeReader::new(),
-			bias_shake_shake:enable_shake_shake,
-		})
+		Ok(losses)
 	}
-}
-impl<NN> Trainer<NN>
-	where NN: ForwardAll<Input=Arr<f32,2517>,Output=Arr<f32,1>> +
-			  BatchForwardBase<BatchInput=VecArr<f32,Arr<f32,2517>>,BatchOutput=VecArr<f32,Arr<f32,1>>> +
-			  BatchTrain<f32,DeviceGpu<f32>> + Persistence<f32,BinFilePersistence<f32>,Linear> {
-	pub fn calc_alpha_beta(bias_shake_shake:bool) -> (f32,f32) {
-		if bias_shake_shake {
-			let mut rnd = rand::thread_rng();
-			let mut rnd = XorShiftRng::from_seed(rnd.gen());
 
-			let a = rnd.gen();
-			let b = 1f32 - a ;
+	/// Output of the ensemble, the weighted sum of the outputs of the members.
+	fn forward(&self,input:<NN::Features as FeatureSet>::Input) -> Result<f32,ApplicationError> {
+		let mut answer = 0.;
 
-			(a,b)
-		} else {
-			(0.5f32,0.5f32)
+		for (nn,w) in self.networks.iter().zip(self.weights.iter()) {
+			answer += nn.forward_input(input.clone() * SCALE)? * w;
 		}
+
+		Ok(answer)
 	}
 
 	pub fn learning_by_training_csa<'a>(&mut self,
@@ -435,18 +896,13 @@ impl<NN> Trainer<NN>
 										history:Vec<(Banmen,MochigomaCollections,u64,u64)>,
 										s:&GameEndState,
 										_:&'a Mutex<EventQueue<UserEvent,UserEventKind>>)
-										-> Result<(f32,f32),CommonError> {
-
-		let lossf = Mse::new();
+										-> Result<Vec<f32>,CommonError> {
 
 		let mut teban = last_teban;
-		let bias_shake_shake = self.bias_shake_shake;
-		
-		let batch = history.iter().rev().map(move |(banmen,mc,_,_)| {
-			let (a, b) = Self::calc_alpha_beta(bias_shake_shake);
 
-			let input = InputCreator::make_input(true, teban, banmen, mc);
+		self.header.positions += history.len() as u64;
 
+		let positions = history.iter().rev().map(move |(banmen,mc,_,_)| {
 			let t = match s {
 				GameEndState::Win if teban == last_teban => {
 					1f32
@@ -463,499 +919,217 @@ impl<NN> Trainer<NN>
 				_ => 0f32
 			};
 
-			teban = teban.opposite();
-
-			(t,input,a,b)
-		}).fold(((Vec::new(),Vec::new()),(Vec::new(),Vec::new())), | mut acc, (t,input,a,b) | {
-			let mut ans = Arr::<f32, 1>::new();
-			ans[0] = t * a;
+			let position = (t,teban,banmen,mc);
 
-			(acc.0).0.push(ans);
-			(acc.0).1.push(input.clone() * SCALE);
-
-			let mut ans = Arr::<f32, 1>::new();
-			ans[0] = t * b;
+			teban = teban.opposite();
 
-			(acc.1).0.push(ans);
-			(acc.1).1.push(input * SCALE);
+			position
+		}).collect::<Vec<_>>();
 
-			acc
-		});
+		let samples = self.decoder.make_samples(positions);
 
-		let msa = self.nna.batch_train((batch.0).0.into(),(batch.0).1.into(),&mut self.optimizer,&lossf)?;
-		let msb = self.nna.batch_train((batch.1).0.into(),(batch.1).1.into(),&mut self.optimizer,&lossf)?;
+		let losses = self.train(samples)?;
 
 		self.save()?;
 
-		Ok((msa,msb))
+		Ok(losses)
 	}
 
 	pub fn test_by_csa(&mut self,
 						   teban:Teban,
-						   kyokumen:&(Banmen,MochigomaCollections,u64,u64))
-						   -> Result<f32,ApplicationError> {
+						   kyokumen:&(Banmen,MochigomaCollections,u64,u64),
+						   s:&GameEndState)
+						   -> Result<ValidationSample,ApplicationError> {
 		let (banmen,mc,_,_) = kyokumen;
 
-		let input = InputCreator::make_input(true, teban, &banmen, &mc);
-
-		let ra = self.nna.forward_all(input.clone() * SCALE)?;
-		let rb = self.nnb.forward_all(input * SCALE)?;
+		let input = <NN::Features as FeatureSet>::make_input(true, teban, &banmen, &mc);
 
-		Ok(ra[0] + rb[0])
+		Ok(ValidationSample {
+			result:s.clone(),
+			output:self.forward(input)?,
+			target:SampleDecoder::<NN::Features>::result_value(s),
+			phase:Phase::of(banmen),
+		})
 	}
 
-	pub fn learning_by_packed_sfens<'a>(&mut self,
-										  packed_sfens:Vec<Vec<u8>>,
-										  _:&'a Mutex<EventQueue<UserEvent,UserEventKind>>)
-		-> Result<(f32,f32),CommonError> {
-
-		let lossf = Mse::new();
-		let bias_shake_shake = self.bias_shake_shake;
-
-		let mut sfens_with_extended = Vec::with_capacity(packed_sfens.len());
-
-		for entry in packed_sfens.into_iter() {
-			let ((teban,banmen,mc),yaneuraou::haffman_code::ExtendFields {
-				value: _,
-				best_move: _,
-				end_ply: _,
-				game_result
-			}) = self.packed_sfen_reader.read_sfen_with_extended(entry).map_err(|e| {
-				CommonError::Fail(format!("{}",e))
-			})?;
-
-			sfens_with_extended.push((teban,banmen,mc,game_result));
-		}
-
-		let batch = sfens_with_extended.iter()
-			.map(|(teban,banmen,mc,es)| {
-				let (a,b) = Self::calc_alpha_beta(bias_shake_shake);
-
-				let teban = *teban;
-
-				let input = InputCreator::make_input(true, teban, banmen, mc);
-
-				let t = match es {
-					GameEndState::Win => {
-						1f32
-					}
-					GameEndState::Lose => {
-						-1f32
-					},
-					_ => 0f32
-				};
-
-				(t,input,a,b)
-			}).fold(((Vec::new(),Vec::new()),(Vec::new(),Vec::new())), | mut acc, (t,input,a,b) | {
-				let mut ans = Arr::<f32, 1>::new();
-				ans[0] = t * a;
-
-				(acc.0).0.push(ans);
-				(acc.0).1.push(input.clone() * SCALE);
-
-				let mut ans = Arr::<f32, 1>::new();
-				ans[0] = t * b;
-
-				(acc.1).0.push(ans);
-				(acc.1).1.push(input * SCALE);
-
-				acc
-			});
+	/// Trains the members on the samples decoded from a batch of the teacher phases of the number of positions.
+	pub fn learning_by_samples(&mut self,positions:usize,samples:Samples<NN::Features>) -> Result<Vec<f32>,CommonError> {
+		self.header.positions += positions as u64;
 
-		let msa = self.nna.batch_train((batch.0).0.into(),(batch.0).1.into(),&mut self.optimizer,&lossf)?;
-		let msb = self.nnb.batch_train((batch.1).0.into(),(batch.1).1.into(),&mut self.optimizer,&lossf)?;
+		self.train(samples)
+	}
 
-		Ok((msa,msb))
+	/// Decoder of the teacher phases with the settings of the targets and the augmentation of this trainer.
+	pub fn sample_decoder(&self) -> SampleDecoder<NN::Features> {
+		self.decoder.clone()
 	}
 
-	pub fn test_by_packed_sfens(&mut self,
-										packed_sfen:Vec<u8>)
-										-> Result<(GameEndState,f32),ApplicationError> {
-		let ((teban,banmen,mc),yaneuraou::haffman_code::ExtendFields {
-			value: _,
-			best_move: _,
-			end_ply: _,
-			game_result
-		}) = self.packed_sfen_reader.read_sfen_with_extended(packed_sfen).map_err(|e| {
+	/// Evaluates a record of the held-out set.
+	pub fn test_by_record(&mut self,format:RecordFormat,record:&[u8]) -> Result<ValidationSample,ApplicationError> {
+		let (teban,banmen,mc,eval,s) = self.decoder.read(format,record).map_err(|e| {
 			ApplicationError::LearningError(format!("{}",e))
 		})?;
 
-		let input = InputCreator::make_input(true, teban, &banmen, &mc);
-
-		let ra = self.nna.forward_all(input.clone() * SCALE)?;
-		let rb = self.nnb.forward_all(input * SCALE)?;
+		let input = <NN::Features as FeatureSet>::make_input(true, teban, &banmen, &mc);
 
-		Ok((game_result,ra[0] + rb[0]))
+		Ok(ValidationSample {
+			target:self.decoder.target(eval,&s),
+			result:s,
+			output:self.forward(input)?,
+			phase:Phase::of(&banmen),
+		})
 	}
 
-	pub fn learning_by_hcpe<'a>(&mut self,
-								  hcpes:Vec<Vec<u8>>,
-								  _:&'a Mutex<EventQueue<UserEvent,UserEventKind>>)
-								  -> Result<(f32,f32),CommonError> {
-
-		let lossf = Mse::new();
-		let bias_shake_shake = self.bias_shake_shake;
+	pub fn eval_scale(&self) -> f32 {
+		self.eval_scale
+	}
 
-		let mut sfens_with_extended = Vec::with_capacity(hcpes.len());
+	pub fn set_eval_scale(&mut self,eval_scale:f32) {
+		self.eval_scale = eval_scale;
+	}
 
-		for entry in hcpes.into_iter() {
-			let ((teban,banmen,mc),hcpe::haffman_code::ExtendFields {
-				eval: _,
-				best_move: _,
-				game_result
-			}) = self.hcpe_reader.read_sfen_with_extended(entry).map_err(|e| {
-				CommonError::Fail(format!("{}",e))
-			})?;
+	pub fn set_augmentation(&mut self,augmentation:Augmentation) {
+		self.decoder.set_augmentation(augmentation);
+	}
 
-			sfens_with_extended.push((teban, banmen, mc, game_result));
-		}
-
-		let batch = sfens_with_extended.iter()
-			.map(|(teban,banmen,mc,es)| {
-				let (a,b) = Self::calc_alpha_beta(bias_shake_shake);
-
-				let teban = *teban;
-
-				let input = InputCreator::make_input(true,teban, banmen, mc);
-
-				let es = match (es,teban) {
-					(GameResult::Draw,_) => GameEndState::Draw,
-					(GameResult::SenteWin,Teban::Sente) |
-					(GameResult::GoteWin,Teban::Gote) => {
-						GameEndState::Win
-					},
-					(GameResult::SenteWin,Teban::Gote) |
-					(GameResult::GoteWin,Teban::Sente) => {
-						GameEndState::Lose
-					}
-				};
-
-				let t = match es {
-					GameEndState::Win => {
-						1f32
-					}
-					GameEndState::Lose => {
-						-1f32
-					},
-					_ => 0f32
-				};
-
-				(t,input,a,b)
-			}).fold(((Vec::new(),Vec::new()),(Vec::new(),Vec::new())),| mut acc, (t,input,a,b) | {
-				let mut ans = Arr::<f32,1>::new();
-				ans[0] = t * a;
+	pub fn set_optimizer(&mut self,optimizer:TrainerOptimizer,scheduler:LrScheduler) {
+		self.optimizer = optimizer;
+		self.scheduler = scheduler;
+	}
 
-				(acc.0).0.push(ans);
-				(acc.0).1.push(input.clone() * SCALE);
+	pub fn learning_rate(&self) -> f32 {
+		self.optimizer.learning_rate()
+	}
 
-				let mut ans = Arr::<f32,1>::new();
-				ans[0] = t * b;
+	/// Records the progress of the training to the metrics log.
+	pub fn set_metrics_log(&mut self,file:&str) -> Result<(),ApplicationError> {
+		self.metrics = Some(MetricsWriter::new(file)?);
 
-				(acc.1).0.push(ans);
-				(acc.1).1.push(input * SCALE);
+		Ok(())
+	}
 
-				acc
-			});
+	/// Notifies the result on the held-out set to the learning rate schedule and the metrics log.
+	pub fn on_validation(&mut self,validation:&Validation) -> Result<(),ApplicationError> {
+		self.scheduler.on_validation(validation.loss());
 
-		let msa = self.nna.batch_train((batch.0).0.into(),(batch.0).1.into(),&mut self.optimizer,&lossf)?;
-		let msb = self.nnb.batch_train((batch.1).0.into(),(batch.1).1.into(),&mut self.optimizer,&lossf)?;
+		if let Some(ref mut metrics) = self.metrics {
+			metrics.validation(self.step,self.header.epochs,self.header.positions,
+							   self.optimizer.learning_rate(),validation.loss(),validation.accuracy())?;
+		}
 
-		Ok((msa,msb))
+		Ok(())
 	}
 
-	pub fn test_by_hcpe(&mut self,
-						hcpe:Vec<u8>)
-						-> Result<(GameEndState,f32),ApplicationError> {
-		let ((teban,banmen,mc),hcpe::haffman_code::ExtendFields {
-			eval: _,
-			best_move: _,
-			game_result
-		}) = self.hcpe_reader.read_sfen_with_extended(hcpe).map_err(|e| {
-			ApplicationError::LearningError(format!("{}",e))
-		})?;
-
-		let input = InputCreator::make_input(true, teban, &banmen, &mc);
+	/// Blends the teacher evaluation into the target with the ratio lambda. (0 trains on the game result only)
+	///
+	/// The evaluation is converted to the range of the output by the same sigmoid as centipawn with the eval scale of the teacher.
+	pub fn set_teacher(&mut self,lambda:f32,eval_scale:f32) {
+		self.decoder.set_teacher(lambda,eval_scale);
+	}
 
-		let ra = self.nna.forward_all(input.clone() * SCALE)?;
-		let rb = self.nnb.forward_all(input * SCALE)?;
+	/// Evaluation in centipawns of the value returned by the test functions.
+	pub fn centipawn(&self,answer:f32) -> i32 {
+		centipawn(answer,self.eval_scale)
+	}
 
-		let s = match game_result {
-			GameResult::SenteWin if teban == Teban::Sente => {
-				GameEndState::Win
-			},
-			GameResult::SenteWin => {
-				GameEndState::Lose
-			},
-			GameResult::GoteWin if teban == Teban::Gote => {
-				GameEndState::Win
-			},
-			GameResult::GoteWin => {
-				GameEndState::Lose
-			},
-			_ => GameEndState::Draw
-		};
+	pub fn add_epoch(&mut self) {
+		self.header.epochs += 1;
+	}
 
-		Ok((s,ra[0] + rb[0]))
+	pub fn set_source(&mut self,source:String) {
+		self.header.source = source;
 	}
 
 	pub fn save(&mut self) -> Result<(),ApplicationError> {
-		let mut pa = BinFilePersistence::new(
-			&format!("{}/{}.tmp",self.nnsavedir,self.nna_filename))?;
-		let mut pb = BinFilePersistence::new(
-			&format!("{}/{}.tmp",self.nnsavedir,self.nnb_filename))?;
+		self.header.updated_at = ModelHeader::now();
+
+		for (nn,filename) in self.networks.iter_mut().zip(self.filenames.iter()) {
+			let mut p = ModelFilePersistence::new();
 
-		self.nna.save(&mut pa)?;
-		self.nnb.save(&mut pb)?;
+			nn.save(&mut p)?;
+
+			p.save(&format!("{}/{}.tmp",self.nnsavedir,filename),&self.header)?;
+		}
 
-		pa.save(&format!("{}/{}.tmp",self.nnsavedir,self.nna_filename))?;
-		pb.save(&format!("{}/{}.tmp",self.nnsavedir,self.nnb_filename))?;
+		for filename in self.filenames.iter() {
+			fs::rename(&format!("{}/{}.tmp", self.nnsavedir,filename),
+					   &format!("{}/{}", self.nnsavedir,filename))?;
+		}
 
-		fs::rename(&format!("{}/{}.tmp", self.nnsavedir,self.nna_filename),
-				   &format!("{}/{}", self.nnsavedir,self.nna_filename))?;
-		fs::rename(&format!("{}/{}.tmp", self.nnsavedir,self.nnb_filename),
-				   &format!("{}/{}", self.nnsavedir,self.nnb_filename))?;
 		Ok(())
 	}
 }
-pub struct InputCreator;
-
-impl InputCreator {
-	pub fn make_input(is_self:bool,t:Teban,b:&Banmen,mc:&MochigomaCollections) -> Arr<f32,2517> {
-		let mut inputs = Arr::new();
-
-		let index = if is_self {
-			SELF_TEBAN_INDEX
-		} else {
-			OPPONENT_TEBAN_INDEX
-		};
+#[cfg(test)]
+mod tests {
+	use super::*;
 
-		inputs[index] = 1f32;
+	use usiagent::rule::Rule;
 
-		match b {
-			&Banmen(ref kinds) => {
-				for y in 0..9 {
-					for x in 0..9 {
-						let kind = kinds[y][x];
+	use perft::Perft;
 
-						if kind != KomaKind::Blank {
-							let index = InputCreator::input_index_of_banmen(t,kind,x as u32,y as u32).unwrap();
+	fn intelligence<A: Architecture>() -> Intelligence<impl Network> {
+		let networks = (0..2).map(|_| A::create_network(DeviceCpu::new().unwrap()).unwrap()).collect();
 
-							inputs[index] = 1f32;
-						}
-					}
-				}
-			}
-		}
-
-		let ms = Mochigoma::new();
-		let mg = Mochigoma::new();
-		let (ms,mg) = match mc {
-			&MochigomaCollections::Pair(ref ms,ref mg) => (ms,mg),
-			&MochigomaCollections::Empty => (&ms,&mg),
-		};
+		Intelligence::new(networks,vec![0.7,0.3])
+	}
 
-		let (ms,mg) = match t {
-			Teban::Sente => (ms,mg),
-			Teban::Gote => (mg,ms),
-		};
+	/// Walks random moves pushing, popping and evaluating through the stack,
+	/// and compares every evaluation with the one recomputed from the position.
+	fn assert_stack_matches_the_full_evaluation<NN: Network>(evalutor:&Intelligence<NN>) {
+		let perft = Perft::new(true);
 
-		for &k in &MOCHIGOMA_KINDS {
-			let c = ms.get(k);
+		let mut rnd = XorShiftRng::from_seed([5; 16]);
 
-			for i in 0..c {
-				let offset = SELF_INDEX_MAP[k as usize];
+		let (teban,state,mc) = Perft::parse_position("startpos").unwrap();
 
-				let offset = offset as usize;
+		let mut stack = AccumulatorStack::new();
 
-				inputs[offset + i as usize] = 1f32;
-			}
+		stack.reset(evalutor,teban,state.get_banmen(),&mc).unwrap();
 
-			let c = mg.get(k);
+		let mut path = vec![(teban,state,mc)];
 
-			for i in 0..c {
-				let offset = OPPONENT_INDEX_MAP[k as usize];
+		for step in 0..200 {
+			let (teban,state,mc) = path.last().cloned().unwrap();
 
-				let offset = offset as usize;
+			let mvs = perft.moves(teban,&state,&mc);
 
-				inputs[offset + i as usize] = 1f32;
+			if path.len() > 1 && (mvs.len() == 0 || rnd.gen_range(0..3) == 0) {
+				stack.pop();
+				path.pop();
+				continue;
+			} else if mvs.len() == 0 {
+				break;
 			}
-		}
-		inputs
-	}
 
-	pub fn make_diff_input(is_self:bool, t:Teban, b:&Banmen, mc:&MochigomaCollections, m:&Move) -> Result<DiffArr<f32,2517>,CommonError> {
-		let mut d = DiffArr::new();
+			let m = mvs[rnd.gen_range(0..mvs.len())].to_move();
 
-		let (addi,subi) = if is_self {
-			(SELF_TEBAN_INDEX,OPPONENT_TEBAN_INDEX)
-		} else {
-			(OPPONENT_TEBAN_INDEX,SELF_TEBAN_INDEX)
-		};
+			let (next,nmc,_) = Rule::apply_move_none_check(&state,teban,&mc,m.to_applied_move());
 
-		d.push(subi,-1.)?;
-		d.push(addi,1.)?;
+			let expected = evalutor.evalute(true,teban.opposite(),next.get_banmen(),&nmc).unwrap();
 
-		match m {
-			&Move::To(KomaSrcPosition(sx,sy),KomaDstToPosition(dx,dy,n)) => {
-				match b {
-					&Banmen(ref kinds) => {
-						let (sx,sy) = (9-sx,sy-1);
-						let (dx,dy) = (9-dx,dy-1);
+			assert!((stack.evalute(evalutor,teban.opposite(),state.get_banmen(),&mc,&m).unwrap() - expected).abs() <= 1);
+			assert!((stack.push(evalutor,teban.opposite(),state.get_banmen(),&mc,&m).unwrap() - expected).abs() <= 1);
 
-						let sk = kinds[sy as usize][sx as usize];
+			path.push((teban.opposite(),next,nmc));
 
-						d.push(InputCreator::input_index_of_banmen(t, sk, sx, sy)?, -1.)?;
+			if step % 50 == 49 {
+				let cloned = stack.clone();
 
-						if n {
-							d.push(InputCreator::input_index_of_banmen(t,sk.to_nari(),dx,dy)?,1.)?;
-						} else {
-							d.push(InputCreator::input_index_of_banmen(t,sk,dx,dy)?,1.)?;
-						}
+				assert_eq!(cloned.len,stack.len);
+				assert_eq!(cloned.entries.len(),stack.len);
 
-						let dk = kinds[dy as usize][dx as usize];
-
-						if dk != KomaKind::Blank {
-							d.push(InputCreator::input_index_of_banmen(t,dk,dx,dy)?,-1.)?;
-						}
-
-						if dk != KomaKind::Blank && dk != KomaKind::SOu && dk != KomaKind::GOu {
-							let offset = InputCreator::input_index_with_of_mochigoma_get(t, MochigomaKind::try_from(dk)?, mc)?;
-
-							d.push(offset+1, 1.)?;
-						}
-					}
-				}
-			},
-			&Move::Put(kind,KomaDstPutPosition(dx,dy))  => {
-				let (dx,dy) = (9-dx,dy-1);
-				let offset = InputCreator::input_index_with_of_mochigoma_get(t, kind, mc)?;
-
-				if offset < 1 {
-					return Err(CommonError::Fail(
-						String::from(
-							"Calculation of index of difference input data of neural network failed. (The number of holding pieces is 0)"
-						)))
-				} else {
-					d.push(offset, -1.)?;
-
-					d.push(InputCreator::input_index_of_banmen(t, KomaKind::from((t, kind)), dx, dy)?, 1.)?;
-				}
+				stack = cloned;
 			}
 		}
-
-		Ok(d)
-	}
-
-	#[inline]
-	fn input_index_of_banmen(teban:Teban,kind:KomaKind,x:u32,y:u32) -> Result<usize,CommonError> {
-		const SENTE_INDEX_MAP:[usize; 28] = [
-			FU_INDEX,
-			KYOU_INDEX,
-			KEI_INDEX,
-			GIN_INDEX,
-			KIN_INDEX,
-			KAKU_INDEX,
-			HISHA_INDEX,
-			OU_INDEX,
-			NARIFU_INDEX,
-			NARIKYOU_INDEX,
-			NARIKEI_INDEX,
-			NARIGIN_INDEX,
-			NARIKAKU_INDEX,
-			NARIHISHA_INDEX,
-			OPPONENT_FU_INDEX,
-			OPPONENT_KYOU_INDEX,
-			OPPONENT_KEI_INDEX,
-			OPPONENT_GIN_INDEX,
-			OPPONENT_KIN_INDEX,
-			OPPONENT_KAKU_INDEX,
-			OPPONENT_HISHA_INDEX,
-			OPPONENT_OU_INDEX,
-			OPPONENT_NARIFU_INDEX,
-			OPPONENT_NARIKYOU_INDEX,
-			OPPONENT_NARIKEI_INDEX,
-			OPPONENT_NARIGIN_INDEX,
-			OPPONENT_NARIKAKU_INDEX,
-			OPPONENT_NARIHISHA_INDEX
-		];
-
-		const GOTE_INDEX_MAP:[usize; 28] = [
-			OPPONENT_FU_INDEX,
-			OPPONENT_KYOU_INDEX,
-			OPPONENT_KEI_INDEX,
-			OPPONENT_GIN_INDEX,
-			OPPONENT_KIN_INDEX,
-			OPPONENT_KAKU_INDEX,
-			OPPONENT_HISHA_INDEX,
-			OPPONENT_OU_INDEX,
-			OPPONENT_NARIFU_INDEX,
-			OPPONENT_NARIKYOU_INDEX,
-			OPPONENT_NARIKEI_INDEX,
-			OPPONENT_NARIGIN_INDEX,
-			OPPONENT_NARIKAKU_INDEX,
-			OPPONENT_NARIHISHA_INDEX,
-			FU_INDEX,
-			KYOU_INDEX,
-			KEI_INDEX,
-			GIN_INDEX,
-			KIN_INDEX,
-			KAKU_INDEX,
-			HISHA_INDEX,
-			OU_INDEX,
-			NARIFU_INDEX,
-			NARIKYOU_INDEX,
-			NARIKEI_INDEX,
-			NARIGIN_INDEX,
-			NARIKAKU_INDEX,
-			NARIHISHA_INDEX
-		];
-
-		let index = match teban {
-			Teban::Sente | Teban::Gote if kind == KomaKind::Blank => {
-				return Err(CommonError::Fail(
-					String::from(
-						"Calculation of index of difference input data of neural network failed. (KomaKind is 'Blank')"
-					)));
-			},
-			Teban::Sente => {
-				SENTE_INDEX_MAP[kind as usize] + y as usize * 9 + x as usize
-			},
-			Teban::Gote => {
-				let (x,y) = (8-x,8-y);
-
-				GOTE_INDEX_MAP[kind as usize] + y as usize * 9 + x as usize
-			}
-		};
-
-		Ok(index as usize)
 	}
 
-	#[inline]
-	fn input_index_with_of_mochigoma_get(teban:Teban, kind:MochigomaKind, mc:&MochigomaCollections)
-										 -> Result<usize,CommonError> {
-
-		let ms = Mochigoma::new();
-		let mg = Mochigoma::new();
-
-		let (ms,mg) = match mc {
-			&MochigomaCollections::Pair(ref ms,ref mg) => (ms,mg),
-			&MochigomaCollections::Empty => (&ms,&mg),
-		};
-
-		let mc = match teban {
-			Teban::Sente => ms,
-			Teban::Gote => mg,
-		};
-
-		let offset = if teban == Teban::Sente {
-			SELF_INDEX_MAP[kind as usize]
-		} else {
-			OPPONENT_INDEX_MAP[kind as usize]
-		};
-
-		let c = mc.get(kind);
-		let offset = offset as usize;
+	#[test]
+	fn accumulator_stack_matches_the_full_evaluation() {
+		assert_stack_matches_the_full_evaluation(&intelligence::<StandardArchitecture>());
+	}
 
-		Ok(offset + c as usize)
+	#[test]
+	fn accumulator_stack_matches_the_full_evaluation_with_king_relative_features() {
+		assert_stack_matches_the_full_evaluation(&intelligence::<KingRelativeArchitecture>());
 	}
-}
\ No newline at end of file
+}
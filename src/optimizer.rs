@@ -0,0 +1,325 @@
+use std::collections::HashMap;
+use std::f32::consts::PI;
+
+use nncombinator::optimizer::Optimizer;
+
+use error::ApplicationError;
+
+pub const DEFAULT_LEARNING_RATE:f32 = 0.001;
+pub const DEFAULT_MOMENTUM:f32 = 0.9;
+pub const DEFAULT_BETA1:f32 = 0.9;
+pub const DEFAULT_BETA2:f32 = 0.999;
+const EPS:f32 = 1e-8;
+
+/// [optimizer] table of settings.toml.
+#[derive(Debug, Deserialize)]
+pub struct OptimizerSettings {
+	kind:Option<String>,
+	learning_rate:Option<f32>,
+	momentum:Option<f32>,
+	beta1:Option<f32>,
+	beta2:Option<f32>,
+	weight_decay:Option<f32>,
+}
+/// [lr_schedule] table of settings.toml.
+#[derive(Debug, Deserialize)]
+pub struct LrScheduleSettings {
+	kind:Option<String>,
+	warmup_steps:Option<usize>,
+	step_size:Option<usize>,
+	gamma:Option<f32>,
+	total_steps:Option<usize>,
+	min_learning_rate:Option<f32>,
+	factor:Option<f32>,
+	patience:Option<usize>,
+}
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum OptimizerKind {
+	/// SGD with the momentum. (0 for the plain SGD)
+	Sgd { momentum:f32 },
+	Adam { beta1:f32, beta2:f32 },
+}
+/// Optimizer of the trainer, whose learning rate is changed by the schedule during the training.
+///
+/// The weight decay is added to the gradient for SGD, and decoupled from the gradient for Adam. (AdamW)
+pub struct TrainerOptimizer {
+	kind:OptimizerKind,
+	learning_rate:f32,
+	weight_decay:f32,
+	state:HashMap<*const f32,(f32,f32,i32)>,
+}
+impl TrainerOptimizer {
+	pub fn new(kind:OptimizerKind,learning_rate:f32,weight_decay:f32) -> TrainerOptimizer {
+		TrainerOptimizer {
+			kind:kind,
+			learning_rate:learning_rate,
+			weight_decay:weight_decay,
+			state:HashMap::new(),
+		}
+	}
+
+	pub fn from_settings(settings:Option<&OptimizerSettings>) -> Result<TrainerOptimizer,ApplicationError> {
+		let settings = match settings {
+			Some(settings) => settings,
+			None => return Ok(TrainerOptimizer::default()),
+		};
+
+		let kind = match settings.kind.as_ref().map(|k| k.as_str()).unwrap_or("adam") {
+			"sgd" => OptimizerKind::Sgd {
+				momentum:settings.momentum.unwrap_or(DEFAULT_MOMENTUM)
+			},
+			"adam" => OptimizerKind::Adam {
+				beta1:settings.beta1.unwrap_or(DEFAULT_BETA1),
+				beta2:settings.beta2.unwrap_or(DEFAULT_BETA2)
+			},
+			_ => {
+				return Err(ApplicationError::StartupError(String::from(
+					"optimizerの指定が不正です。(sgd, adamのいずれかを指定してください)"
+				)));
+			}
+		};
+
+		let learning_rate = settings.learning_rate.unwrap_or(DEFAULT_LEARNING_RATE);
+
+		if learning_rate <= 0. {
+			return Err(ApplicationError::StartupError(String::from(
+				"learning_rateには正の値を指定してください。"
+			)));
+		}
+
+		Ok(TrainerOptimizer::new(kind,learning_rate,settings.weight_decay.unwrap_or(0.)))
+	}
+
+	pub fn learning_rate(&self) -> f32 {
+		self.learning_rate
+	}
+
+	pub fn set_learning_rate(&mut self,learning_rate:f32) {
+		self.learning_rate = learning_rate;
+	}
+}
+impl Default for TrainerOptimizer {
+	fn default() -> TrainerOptimizer {
+		TrainerOptimizer::new(OptimizerKind::Adam { beta1:DEFAULT_BETA1, beta2:DEFAULT_BETA2 },DEFAULT_LEARNING_RATE,0.)
+	}
+}
+impl Optimizer<f32> for TrainerOptimizer {
+	#[inline]
+	fn update(&mut self, e: f32, w: &mut f32) {
+		let a = self.learning_rate;
+		let lambda = self.weight_decay;
+
+		let state = self.state.entry(w as *const f32).or_insert((0.,0.,0));
+
+		match self.kind {
+			OptimizerKind::Sgd { momentum } => {
+				state.0 = momentum * state.0 - a * (e + lambda * *w);
+				*w = *w + state.0;
+			},
+			OptimizerKind::Adam { beta1, beta2 } => {
+				state.0 = beta1 * state.0 + (1. - beta1) * e;
+				state.1 = beta2 * state.1 + (1. - beta2) * e * e;
+				state.2 += 1;
+
+				let mt = state.0 / (1. - beta1.powi(state.2));
+				let vt = state.1 / (1. - beta2.powi(state.2));
+
+				*w = *w - a * (mt / (vt.sqrt() + EPS) + lambda * *w);
+			}
+		}
+	}
+}
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum LrSchedule {
+	Constant,
+	/// Multiplies the learning rate by gamma every step_size steps.
+	Step { step_size:usize, gamma:f32 },
+	/// Decreases the learning rate to min along the cosine curve over total_steps steps.
+	Cosine { total_steps:usize, min:f32 },
+	/// Multiplies the learning rate by factor when the validation loss has not improved for patience validations.
+	ReduceOnPlateau { factor:f32, patience:usize, min:f32 },
+}
+/// Learning rate of each step of the training, increased linearly from 0 during the warmup steps.
+pub struct LrScheduler {
+	schedule:LrSchedule,
+	base:f32,
+	warmup_steps:usize,
+	step:usize,
+	best_loss:Option<f64>,
+	bad_validations:usize,
+	plateau_scale:f32,
+}
+impl LrScheduler {
+	pub fn new(schedule:LrSchedule,base:f32,warmup_steps:usize) -> LrScheduler {
+		LrScheduler {
+			schedule:schedule,
+			base:base,
+			warmup_steps:warmup_steps,
+			step:0,
+			best_loss:None,
+			bad_validations:0,
+			plateau_scale:1.,
+		}
+	}
+
+	pub fn from_settings(settings:Option<&LrScheduleSettings>,base:f32) -> Result<LrScheduler,ApplicationError> {
+		let settings = match settings {
+			Some(settings) => settings,
+			None => return Ok(LrScheduler::new(LrSchedule::Constant,base,0)),
+		};
+
+		let min = settings.min_learning_rate.unwrap_or(0.);
+
+		let schedule = match settings.kind.as_ref().map(|k| k.as_str()).unwrap_or("constant") {
+			"constant" => LrSchedule::Constant,
+			"step" => LrSchedule::Step {
+				step_size:settings.step_size.unwrap_or(1000).max(1),
+				gamma:settings.gamma.unwrap_or(0.1)
+			},
+			"cosine" => {
+				match settings.total_steps {
+					Some(total_steps) if total_steps > 0 => LrSchedule::Cosine { total_steps:total_steps, min:min },
+					_ => {
+						return Err(ApplicationError::StartupError(String::from(
+							"cosineスケジュールにはtotal_stepsを指定してください。"
+						)));
+					}
+				}
+			},
+			"plateau" => LrSchedule::ReduceOnPlateau {
+				factor:settings.factor.unwrap_or(0.5),
+				patience:settings.patience.unwrap_or(2),
+				min:min
+			},
+			_ => {
+				return Err(ApplicationError::StartupError(String::from(
+					"lr_scheduleの指定が不正です。(constant, step, cosine, plateauのいずれかを指定してください)"
+				)));
+			}
+		};
+
+		Ok(LrScheduler::new(schedule,base,settings.warmup_steps.unwrap_or(0)))
+	}
+
+	/// Advances the schedule by one step and returns the learning rate of the step.
+	pub fn next(&mut self) -> f32 {
+		self.step += 1;
+
+		let lr = match self.schedule {
+			LrSchedule::Constant => self.base,
+			LrSchedule::Step { step_size, gamma } => {
+				self.base * gamma.powi((self.step.saturating_sub(self.warmup_steps + 1) / step_size) as i32)
+			},
+			LrSchedule::Cosine { total_steps, min } => {
+				let t = (self.step.saturating_sub(self.warmup_steps + 1) as f32 / total_steps as f32).min(1.);
+
+				min + (self.base - min) * (1. + (PI * t).cos()) / 2.
+			},
+			LrSchedule::ReduceOnPlateau { min, .. } => {
+				(self.base * self.plateau_scale).max(min)
+			}
+		};
+
+		if self.step <= self.warmup_steps {
+			lr * self.step as f32 / self.warmup_steps as f32
+		} else {
+			lr
+		}
+	}
+
+	/// Notifies the loss on the held-out set, which drives ReduceOnPlateau.
+	pub fn on_validation(&mut self,loss:f64) {
+		if let LrSchedule::ReduceOnPlateau { factor, patience, .. } = self.schedule {
+			match self.best_loss {
+				Some(best) if loss >= best => {
+					self.bad_validations += 1;
+
+					if self.bad_validations > patience {
+						self.plateau_scale *= factor;
+						self.bad_validations = 0;
+					}
+				},
+				_ => {
+					self.best_loss = Some(loss);
+					self.bad_validations = 0;
+				}
+			}
+		}
+	}
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn rates(scheduler:&mut LrScheduler,steps:usize) -> Vec<f32> {
+		(0..steps).map(|_| scheduler.next()).collect()
+	}
+
+	fn assert_rates(actual:&[f32],expected:&[f32]) {
+		assert_eq!(actual.len(),expected.len());
+
+		for (a,e) in actual.iter().zip(expected.iter()) {
+			assert!((a - e).abs() < 1e-6,"{:?} != {:?}",actual,expected);
+		}
+	}
+
+	#[test]
+	fn constant_schedule_warms_up_linearly() {
+		let mut scheduler = LrScheduler::new(LrSchedule::Constant,1.,4);
+
+		assert_rates(&rates(&mut scheduler,6),&[0.25,0.5,0.75,1.,1.,1.]);
+	}
+
+	#[test]
+	fn step_schedule_decays_every_step_size_steps_after_the_warmup() {
+		let mut scheduler = LrScheduler::new(LrSchedule::Step { step_size:2, gamma:0.5 },1.,0);
+
+		assert_rates(&rates(&mut scheduler,6),&[1.,1.,0.5,0.5,0.25,0.25]);
+
+		let mut scheduler = LrScheduler::new(LrSchedule::Step { step_size:2, gamma:0.5 },1.,2);
+
+		assert_rates(&rates(&mut scheduler,6),&[0.5,1.,1.,1.,0.5,0.5]);
+	}
+
+	#[test]
+	fn cosine_schedule_reaches_min_at_total_steps() {
+		let mut scheduler = LrScheduler::new(LrSchedule::Cosine { total_steps:4, min:0.1 },1.,0);
+
+		assert_rates(&rates(&mut scheduler,7),&[1.,0.1 + 0.9 * 0.85355339,0.55,0.1 + 0.9 * 0.14644661,0.1,0.1,0.1]);
+	}
+
+	#[test]
+	fn plateau_schedule_reduces_after_patience_validations_without_improvement() {
+		let mut scheduler = LrScheduler::new(LrSchedule::ReduceOnPlateau { factor:0.5, patience:1, min:0.2 },1.,0);
+
+		scheduler.on_validation(1.);
+		assert_rates(&rates(&mut scheduler,1),&[1.]);
+
+		scheduler.on_validation(1.);
+		assert_rates(&rates(&mut scheduler,1),&[1.]);
+
+		scheduler.on_validation(1.5);
+		assert_rates(&rates(&mut scheduler,1),&[0.5]);
+
+		scheduler.on_validation(0.5);
+		scheduler.on_validation(0.6);
+		assert_rates(&rates(&mut scheduler,1),&[0.5]);
+
+		scheduler.on_validation(0.6);
+		scheduler.on_validation(0.6);
+		scheduler.on_validation(0.6);
+		scheduler.on_validation(0.6);
+		assert_rates(&rates(&mut scheduler,1),&[0.2]);
+	}
+
+	#[test]
+	fn cosine_schedule_requires_total_steps() {
+		let settings:LrScheduleSettings = toml::from_str("kind = \"cosine\"").unwrap();
+
+		assert!(LrScheduler::from_settings(Some(&settings),1.).is_err());
+
+		let settings:LrScheduleSettings = toml::from_str("kind = \"cosine\"\ntotal_steps = 10").unwrap();
+
+		assert!(LrScheduler::from_settings(Some(&settings),1.).is_ok());
+	}
+}
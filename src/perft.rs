@@ -0,0 +1,192 @@
+use std::time::Instant;
+
+use rand::Rng;
+
+use usiagent::shogi::*;
+use usiagent::rule::*;
+use usiagent::protocol::*;
+
+use error::ApplicationError;
+
+pub struct Perft {
+	strict:bool,
+}
+impl Perft {
+	pub fn new(strict:bool) -> Perft {
+		Perft {
+			strict:strict
+		}
+	}
+
+	pub fn parse_position(position:&str) -> Result<(Teban,State,MochigomaCollections),ApplicationError> {
+		let position_parser = PositionParser::new();
+
+		let (mut teban, banmen, mut mc, _, mvs) = match position_parser.parse(&position.trim().split(" ").collect::<Vec<&str>>()) {
+			Ok(position) => {
+				position.extract()
+			},
+			Err(_) => {
+				return Err(ApplicationError::StartupError(String::from(
+					"局面のパースでエラーが発生しました。"
+				)));
+			}
+		};
+
+		let mut state = State::new(banmen);
+
+		for m in &mvs {
+			match Rule::apply_move_none_check(&state,teban,&mc,m.to_applied_move()) {
+				(s,nmc,_) => {
+					state = s;
+					mc = nmc;
+					teban = teban.opposite();
+				}
+			}
+		}
+
+		Ok((teban,state,mc))
+	}
+
+	pub fn run(&self,position:&str,depth:u32) -> Result<u64,ApplicationError> {
+		let (teban,state,mc) = Perft::parse_position(position)?;
+
+		let start_time = Instant::now();
+
+		let mut total = 0;
+
+		for (m,count) in self.divide(teban,&state,&mc,depth) {
+			print!("{}: {}\n",m.to_move().to_sfen()?,count);
+			total += count;
+		}
+
+		// At depth 0 the root itself is the only leaf.
+		if depth == 0 {
+			total = 1;
+		}
+
+		let elapsed = Instant::now() - start_time;
+		let millis = elapsed.as_millis() as u64;
+
+		print!("depth = {}, nodes = {}, time = {}ms",depth,total,millis);
+
+		if millis > 0 {
+			print!(", nps = {}",total * 1000 / millis);
+		}
+
+		print!("\n");
+
+		Ok(total)
+	}
+
+	pub fn divide(&self,teban:Teban,state:&State,mc:&MochigomaCollections,depth:u32) -> Vec<(LegalMove,u64)> {
+		if depth == 0 {
+			return Vec::new();
+		}
+
+		self.moves(teban,state,mc).into_iter().map(|m| {
+			let (next,nmc,_) = Rule::apply_move_none_check(state,teban,mc,m.to_applied_move());
+
+			(m,self.perft(teban.opposite(),&next,&nmc,depth - 1))
+		}).collect::<Vec<(LegalMove,u64)>>()
+	}
+
+	pub fn perft(&self,teban:Teban,state:&State,mc:&MochigomaCollections,depth:u32) -> u64 {
+		if depth == 0 {
+			return 1;
+		}
+
+		let mvs = self.moves(teban,state,mc);
+
+		if depth == 1 {
+			return mvs.len() as u64;
+		}
+
+		mvs.into_iter().map(|m| {
+			let (next,nmc,_) = Rule::apply_move_none_check(state,teban,mc,m.to_applied_move());
+
+			self.perft(teban.opposite(),&next,&nmc,depth - 1)
+		}).sum()
+	}
+
+	pub fn moves(&self,teban:Teban,state:&State,mc:&MochigomaCollections) -> Vec<LegalMove> {
+		// Same generation as Search::negascout.
+		let mvs = if Rule::is_mate(teban.opposite(),state) {
+			Rule::respond_oute_only_moves_all(teban,state,mc)
+		} else {
+			Rule::legal_moves_all(teban,state,mc)
+		};
+
+		if !self.strict {
+			return mvs;
+		}
+
+		// The search leaves self-check and putting fu mate to the child node,
+		// reference numbers do not count them.
+		mvs.into_iter().filter(|m| {
+			let m = m.to_applied_move();
+
+			if Rule::is_put_fu_and_mate(state,teban,mc,m) {
+				return false;
+			}
+
+			let (next,_,_) = Rule::apply_move_none_check(state,teban,mc,m);
+
+			!Rule::is_mate(teban.opposite(),&next)
+		}).collect::<Vec<LegalMove>>()
+	}
+
+	/// Moves chosen at random among the legal moves from the initial position, and the position after them.
+	///
+	/// Stops early if the side to move has no legal move.
+	pub fn random_opening<R: Rng>(&self,count:u32,rnd:&mut R) -> (Teban,State,MochigomaCollections,Vec<Move>) {
+		let mut teban = Teban::Sente;
+		let mut state = State::new(BANMEN_START_POS.clone());
+		let mut mc = MochigomaCollections::Pair(Mochigoma::new(),Mochigoma::new());
+
+		let mut mvs = Vec::with_capacity(count as usize);
+
+		for _ in 0..count {
+			let legal_moves = self.moves(teban,&state,&mc);
+
+			if legal_moves.is_empty() {
+				break;
+			}
+
+			let m = legal_moves[rnd.gen_range(0..legal_moves.len())].to_applied_move();
+
+			let (next,nmc,_) = Rule::apply_move_none_check(&state,teban,&mc,m);
+
+			state = next;
+			mc = nmc;
+			teban = teban.opposite();
+
+			mvs.push(m.to_move());
+		}
+
+		(teban,state,mc,mvs)
+	}
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn perft_startpos_depth_0_counts_the_root() {
+		let perft = Perft::new(true);
+		let (teban,state,mc) = Perft::parse_position("startpos").unwrap();
+
+		assert_eq!(perft.perft(teban,&state,&mc,0),1);
+		assert_eq!(perft.run("startpos",0).unwrap(),1);
+	}
+
+	#[test]
+	fn perft_startpos_matches_the_known_counts() {
+		let perft = Perft::new(true);
+		let (teban,state,mc) = Perft::parse_position("startpos").unwrap();
+
+		assert_eq!(perft.perft(teban,&state,&mc,1),30);
+		assert_eq!(perft.run("startpos",1).unwrap(),30);
+		assert_eq!(perft.perft(teban,&state,&mc,2),900);
+		assert_eq!(perft.perft(teban,&state,&mc,3),25470);
+	}
+}
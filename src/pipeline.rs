@@ -0,0 +1,394 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::marker::PhantomData;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use rand::{Rng, SeedableRng};
+use rand_xorshift::XorShiftRng;
+
+use usiagent::event::GameEndState;
+use usiagent::shogi::*;
+
+use packedsfen::yaneuraou::reader::PackedSfenReader;
+use packedsfen::traits::Reader;
+use packedsfen::hcpe::reader::HcpeReader;
+
+use error::{ApplicationError, CommonError};
+use features::{FeatureSet, mirror_banmen};
+use nn::{Augmentation, DEFAULT_EVAL_SCALE};
+use records::RecordFormat;
+
+/// Number of the batches decoded ahead of the batch being trained.
+pub const PREFETCH_BATCHES:usize = 1;
+/// Interval in milliseconds to check that the decoding threads are alive while waiting for the results.
+const WORKER_CHECK_INTERVAL:u64 = 1000;
+
+/// Pairs of the target and the input of the positions of a batch.
+pub type Samples<F> = Vec<(f32,<F as FeatureSet>::Input)>;
+
+/// Makes the training samples from the records of the teacher phases.
+///
+/// Holds only the settings of the targets and the augmentation, so that it can be cloned to the decoding threads.
+pub struct SampleDecoder<F> where F: FeatureSet {
+	teacher_lambda:f32,
+	teacher_eval_scale:f32,
+	augmentation:Augmentation,
+	features:PhantomData<F>,
+}
+impl<F> SampleDecoder<F> where F: FeatureSet {
+	pub fn new() -> SampleDecoder<F> {
+		SampleDecoder {
+			teacher_lambda:0.,
+			teacher_eval_scale:DEFAULT_EVAL_SCALE,
+			augmentation:Augmentation::None,
+			features:PhantomData::<F>,
+		}
+	}
+
+	pub fn set_teacher(&mut self,lambda:f32,eval_scale:f32) {
+		self.teacher_lambda = lambda;
+		self.teacher_eval_scale = eval_scale;
+	}
+
+	pub fn set_augmentation(&mut self,augmentation:Augmentation) {
+		self.augmentation = augmentation;
+	}
+
+	/// Position, evaluation of the teacher and game result from the side to move of a record.
+	pub fn read(&self,format:RecordFormat,record:&[u8])
+		-> Result<(Teban,Banmen,MochigomaCollections,i16,GameEndState),CommonError> {
+		if record.len() != format.item_size() {
+			return Err(CommonError::Fail(format!(
+				"The size of the record is invalid. (actual size = {})",record.len()
+			)));
+		}
+
+		let eval = i16::from_le_bytes([record[32],record[33]]);
+
+		match format {
+			RecordFormat::PackedSfen => {
+				let (teban,banmen,mc) = PackedSfenReader::new().read_sfen(&record[..32]).map_err(|e| {
+					CommonError::Fail(format!("{}",e))
+				})?;
+
+				let es = match record[38] as i8 {
+					1 => GameEndState::Win,
+					-1 => GameEndState::Lose,
+					_ => GameEndState::Draw,
+				};
+
+				Ok((teban,banmen,mc,eval,es))
+			},
+			RecordFormat::Hcpe => {
+				let (teban,banmen,mc) = HcpeReader::new().read_sfen(&record[..32]).map_err(|e| {
+					CommonError::Fail(format!("{}",e))
+				})?;
+
+				let es = match (record[36],teban) {
+					(0,_) => GameEndState::Draw,
+					(1,Teban::Sente) | (2,Teban::Gote) => GameEndState::Win,
+					(1,Teban::Gote) | (2,Teban::Sente) => GameEndState::Lose,
+					(r,_) => {
+						return Err(CommonError::Fail(format!("unknown game_result {}.",r)));
+					}
+				};
+
+				Ok((teban,banmen,mc,eval,es))
+			}
+		}
+	}
+
+	/// Samples of a batch of the records laid out back to back.
+	pub fn decode(&self,format:RecordFormat,records:&[u8]) -> Result<Samples<F>,CommonError> {
+		let mut positions = Vec::with_capacity(records.len() / format.item_size());
+
+		for record in records.chunks(format.item_size()) {
+			positions.push(self.read(format,record)?);
+		}
+
+		Ok(self.make_samples(positions.iter().map(|(teban,banmen,mc,eval,es)| {
+			(self.target(*eval,es),*teban,banmen,mc)
+		}).collect()))
+	}
+
+	/// Inputs of the positions from the side to move paired with the targets,
+	/// with the positions mirrored left and right according to the augmentation.
+	pub fn make_samples(&self,positions:Vec<(f32,Teban,&Banmen,&MochigomaCollections)>) -> Samples<F> {
+		let mut rnd = rand::thread_rng();
+		let mut rnd = XorShiftRng::from_seed(rnd.gen());
+
+		let mut samples = Vec::with_capacity(match self.augmentation {
+			Augmentation::Double => positions.len() * 2,
+			_ => positions.len(),
+		});
+
+		for (t,teban,banmen,mc) in positions.into_iter() {
+			match self.augmentation {
+				Augmentation::None => {
+					samples.push((t,F::make_input(true, teban, banmen, mc)));
+				},
+				Augmentation::Double => {
+					samples.push((t,F::make_input(true, teban, banmen, mc)));
+					samples.push((t,F::make_input(true, teban, &mirror_banmen(banmen), mc)));
+				},
+				Augmentation::Random if rnd.gen::<bool>() => {
+					samples.push((t,F::make_input(true, teban, &mirror_banmen(banmen), mc)));
+				},
+				Augmentation::Random => {
+					samples.push((t,F::make_input(true, teban, banmen, mc)));
+				}
+			}
+		}
+
+		samples
+	}
+
+	/// Target of the training from the evaluation of the teacher and the game result, both from the side to move.
+	pub fn target(&self,eval:i16,es:&GameEndState) -> f32 {
+		let teacher = (eval as f32 / (2. * self.teacher_eval_scale)).tanh();
+
+		self.teacher_lambda * teacher + (1. - self.teacher_lambda) * Self::result_value(es)
+	}
+
+	pub fn result_value(es:&GameEndState) -> f32 {
+		match es {
+			&GameEndState::Win => {
+				1f32
+			}
+			&GameEndState::Lose => {
+				-1f32
+			},
+			_ => 0f32
+		}
+	}
+}
+impl<F> Clone for SampleDecoder<F> where F: FeatureSet {
+	fn clone(&self) -> SampleDecoder<F> {
+		SampleDecoder {
+			teacher_lambda:self.teacher_lambda,
+			teacher_eval_scale:self.teacher_eval_scale,
+			augmentation:self.augmentation,
+			features:PhantomData::<F>,
+		}
+	}
+}
+/// Chunk of a batch for a decoding thread, the sequence number of the batch, the index of the chunk and the range of the records.
+type DecodeJob = (usize,usize,Arc<Vec<u8>>,Range<usize>);
+type DecodeResult<F> = Result<(usize,Samples<F>),CommonError>;
+
+/// Decodes the batches of the records into the samples on worker threads while the training thread trains.
+///
+/// Each batch is split into a chunk per thread, and the samples of the chunks are joined in the order of the records,
+/// so the batches are returned by next in the order they were submitted.
+/// At most PREFETCH_BATCHES batches are decoded ahead of the batch being trained, whatever the number of the threads.
+pub struct DecodePipeline<F> where F: FeatureSet {
+	sender:Option<Sender<DecodeJob>>,
+	receiver:Receiver<(usize,usize,DecodeResult<F>)>,
+	workers:Vec<JoinHandle<()>>,
+	item_size:usize,
+	/// Number of the chunks of each pending batch.
+	chunks:VecDeque<usize>,
+	completed:BTreeMap<(usize,usize),DecodeResult<F>>,
+	submitted:usize,
+	received:usize,
+}
+impl<F> DecodePipeline<F> where F: FeatureSet {
+	pub fn new(decoder:SampleDecoder<F>,format:RecordFormat,threads:usize) -> Result<DecodePipeline<F>,ApplicationError> {
+		let (sender,jobs) = mpsc::channel::<DecodeJob>();
+		let (results,receiver) = mpsc::channel();
+
+		let jobs = Arc::new(Mutex::new(jobs));
+
+		let mut workers = Vec::with_capacity(threads);
+
+		for i in 0..threads.max(1) {
+			let jobs = jobs.clone();
+			let results = results.clone();
+			let decoder = decoder.clone();
+
+			workers.push(thread::Builder::new().name(format!("decode-{}",i)).spawn(move || {
+				loop {
+					let job = match jobs.lock() {
+						Ok(jobs) => jobs.recv(),
+						Err(_) => break,
+					};
+
+					let (seq,chunk,records,range) = match job {
+						Ok(job) => job,
+						Err(_) => break,
+					};
+
+					let records = &records[range];
+					let positions = records.len() / format.item_size();
+
+					if results.send((seq,chunk,decoder.decode(format,records).map(|samples| (positions,samples)))).is_err() {
+						break;
+					}
+				}
+			})?);
+		}
+
+		Ok(DecodePipeline {
+			sender:Some(sender),
+			receiver:receiver,
+			workers:workers,
+			item_size:format.item_size(),
+			chunks:VecDeque::new(),
+			completed:BTreeMap::new(),
+			submitted:0,
+			received:0,
+		})
+	}
+
+	/// Number of the batches submitted and not yet taken out by next.
+	pub fn pending(&self) -> usize {
+		self.submitted - self.received
+	}
+
+	/// Whether enough batches are decoded ahead, so that the oldest one should be taken out before the next submission.
+	pub fn is_full(&self) -> bool {
+		self.pending() > PREFETCH_BATCHES
+	}
+
+	/// Submits a batch of the records laid out back to back.
+	pub fn submit(&mut self,records:Vec<u8>) -> Result<(),ApplicationError> {
+		let sender = self.sender.as_ref().ok_or(ApplicationError::LearningError(String::from(
+			"The decoding pipeline has already been closed."
+		)))?;
+
+		let count = records.len() / self.item_size;
+		let chunk_size = (count + self.workers.len() - 1) / self.workers.len();
+		let chunks = if chunk_size == 0 { 0 } else { (count + chunk_size - 1) / chunk_size };
+
+		let records = Arc::new(records);
+
+		for chunk in 0..chunks {
+			let start = chunk * chunk_size * self.item_size;
+			let end = (start + chunk_size * self.item_size).min(count * self.item_size);
+
+			sender.send((self.submitted,chunk,records.clone(),start..end)).map_err(|_| ApplicationError::LearningError(String::from(
+				"The decoding thread has terminated unexpectedly."
+			)))?;
+		}
+
+		self.chunks.push_back(chunks);
+		self.submitted += 1;
+
+		Ok(())
+	}
+
+	/// Number of the positions and the samples of the oldest pending batch, or None if there is no pending batch.
+	pub fn next(&mut self) -> Result<Option<(usize,Samples<F>)>,ApplicationError> {
+		let chunks = match self.chunks.front() {
+			Some(&chunks) => chunks,
+			None => return Ok(None),
+		};
+
+		while (0..chunks).any(|chunk| !self.completed.contains_key(&(self.received,chunk))) {
+			match self.receiver.recv_timeout(Duration::from_millis(WORKER_CHECK_INTERVAL)) {
+				Ok((seq,chunk,r)) => {
+					self.completed.insert((seq,chunk),r);
+				},
+				Err(RecvTimeoutError::Timeout) => {
+					// The workers do not stop while the pipeline is open, unless they have panicked.
+					if self.workers.iter().any(|w| w.is_finished()) {
+						return Err(ApplicationError::LearningError(String::from(
+							"The decoding thread has terminated unexpectedly."
+						)));
+					}
+				},
+				Err(RecvTimeoutError::Disconnected) => {
+					return Err(ApplicationError::LearningError(String::from(
+						"The decoding thread has terminated unexpectedly."
+					)));
+				}
+			}
+		}
+
+		let mut positions = 0;
+		let mut samples = Vec::new();
+
+		for chunk in 0..chunks {
+			let r = self.completed.remove(&(self.received,chunk)).ok_or(ApplicationError::LogicError(String::from(
+				"The decoded chunk is missing."
+			)))?;
+
+			let (p,s) = r.map_err(|e| ApplicationError::LearningError(format!(
+				"An error occurred while decoding the teacher phases. {}",e
+			)))?;
+
+			positions += p;
+			samples.extend(s);
+		}
+
+		self.chunks.pop_front();
+		self.received += 1;
+
+		Ok(Some((positions,samples)))
+	}
+}
+impl<F> Drop for DecodePipeline<F> where F: FeatureSet {
+	fn drop(&mut self) {
+		self.sender.take();
+
+		for worker in self.workers.drain(..) {
+			let _ = worker.join();
+		}
+	}
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use usiagent::rule::BANMEN_START_POS;
+
+	use features::InputCreator;
+	use records::TeacherPosition;
+
+	fn records(format:RecordFormat,evals:std::ops::Range<i16>) -> Vec<u8> {
+		let mut records = Vec::new();
+
+		for eval in evals {
+			records.extend(format.encode(&TeacherPosition {
+				teban:Teban::Sente,
+				banmen:BANMEN_START_POS.clone(),
+				mc:MochigomaCollections::Empty,
+				eval:eval * 100,
+				best_move:None,
+				ply:0,
+				result:GameEndState::Draw,
+			}).unwrap());
+		}
+
+		records
+	}
+
+	#[test]
+	fn decode_pipeline_returns_the_batches_in_the_order_of_the_records() {
+		let format = RecordFormat::Hcpe;
+		let mut decoder = SampleDecoder::<InputCreator>::new();
+
+		decoder.set_teacher(1.,DEFAULT_EVAL_SCALE);
+
+		let mut pipeline = DecodePipeline::new(decoder.clone(),format,3).unwrap();
+
+		let batches = vec![0..10,10..11,11..18,18..20,20..50];
+
+		for b in batches.iter() {
+			pipeline.submit(records(format,b.clone())).unwrap();
+		}
+
+		for b in batches.iter() {
+			let (positions,samples) = pipeline.next().unwrap().unwrap();
+
+			assert_eq!(positions,b.len());
+			assert_eq!(samples.iter().map(|&(t,_)| t).collect::<Vec<f32>>(),
+					   b.clone().map(|eval| decoder.target(eval * 100,&GameEndState::Draw)).collect::<Vec<f32>>());
+		}
+
+		assert_eq!(pipeline.pending(),0);
+		assert!(pipeline.next().unwrap().is_none());
+	}
+}
@@ -27,12 +27,15 @@ use usiagent::error::PlayerError;
 use usiagent::error::UsiProtocolError;
 use usiagent::error::EventHandlerError;
 
-use nn::{Intelligence};
+use nn::{AccumulatorStack, DEFAULT_EVAL_SCALE, DRAW_VALUE, EVAL_MAX, fallback_warning, Intelligence, Network};
+use debug::DebugInfo;
+use evalcache::EvalCache;
+use trace::{SearchTracer, TraceReason, mate_result};
 use solver::*;
 use std::sync::mpsc::Receiver;
-use nncombinator::arr::{Arr, DiffArr};
-use nncombinator::layer::{AskDiffInput, DiffInput, ForwardAll, ForwardDiff, PreTrain};
 use usiagent::output::USIOutputWriter;
+use usiagent::output::USIStdErrorWriter;
+use usiagent::protocol::ToSfen;
 
 #[derive(Clone, Copy, PartialEq, Debug)]
 enum Evaluation {
@@ -57,6 +60,29 @@ impl Neg for Score {
 		}
 	}
 }
+impl fmt::Display for Score {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Score::NEGINFINITE => write!(f, "-inf"),
+			Score::Value(v) => write!(f, "{}",v),
+			Score::INFINITE => write!(f, "inf"),
+		}
+	}
+}
+impl fmt::Display for Evaluation {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let mv = |m:&Option<AppliedMove>| {
+			m.and_then(|m| m.to_move().to_sfen().ok()).unwrap_or(String::from("-"))
+		};
+
+		match *self {
+			Evaluation::Result(s,ref m) => write!(f, "result {} {}",s,mv(m)),
+			Evaluation::Timeout(Some(s),ref m) => write!(f, "timeout {} {}",s,mv(m)),
+			Evaluation::Timeout(None,ref m) => write!(f, "timeout - {}",mv(m)),
+			Evaluation::Error => write!(f, "error"),
+		}
+	}
+}
 impl Add<i32> for Score {
 	type Output = Self;
 
@@ -90,12 +116,16 @@ const MAX_PLY:u32 = 200;
 const MAX_PLY_TIMELIMIT:u64 = 0;
 const TURN_COUNT:u32 = 50;
 const MIN_TURN_COUNT:u32 = 5;
+const EVAL_CACHE_SIZE:usize = 16;
+const RESIGN_VALUE:i32 = 0;
+/// Width of the null window of the principal variation search in centipawns.
+const NULL_WINDOW:i32 = 1;
+const EVAL_DIR:&'static str = "data";
 
-type Strategy<L,S,NN,ST> = fn (&Arc<Search<NN>>,
+type Strategy<L,S,NN> = fn (&Arc<Search<NN>>,
 						&mut Environment<L,S,NN>,
 						&mut UserEventDispatcher<Search<NN>,CommonError,L>,
 						&mut UserEventDispatcher<Solver<CommonError,NN>,CommonError,L>,
-						&Arc<(ST,ST)>,&Arc<(ST,ST)>,
 						Teban,&Arc<State>,
 						&Vec<AppliedMove>,
 						Score,Score,
@@ -108,8 +138,7 @@ type Strategy<L,S,NN,ST> = fn (&Arc<Search<NN>>,
 						u32,u32,u32,u64,
 						&Vec<(u32,LegalMove)>,bool) -> Evaluation;
 pub struct Environment<L,S,NN> where L: Logger, S: InfoSender,
-										NN: ForwardAll<Input=DiffInput<DiffArr<f32,2517>,f32,2517,256>,Output=Arr<f32,1>> +
-											PreTrain<f32> +	ForwardDiff<f32> + AskDiffInput<f32,DiffInput=Arr<f32,256>> + Send + Sync + 'static {
+										NN: Network {
 	solver:Solver<CommonError,NN>,
 	event_queue:Arc<Mutex<UserEventQueue>>,
 	evalutor:Arc<Intelligence<NN>>,
@@ -121,13 +150,15 @@ pub struct Environment<L,S,NN> where L: Logger, S: InfoSender,
 	quited:Arc<AtomicBool>,
 	kyokumen_score_map:KyokumenMap<u64,(Score,u32)>,
 	nodes:Arc<AtomicU64>,
-	think_start_time:Instant
+	think_start_time:Instant,
+	tracer:Option<SearchTracer>,
+	accumulator:AccumulatorStack<NN>,
+	eval_cache:Arc<EvalCache>
 }
 impl<L,S,NN> Clone for Environment<L,S,NN>
 	where L: Logger,
 		  S: InfoSender,
-	      NN: ForwardAll<Input=DiffInput<DiffArr<f32,2517>,f32,2517,256>,Output=Arr<f32,1>> +
-		  	  PreTrain<f32> + ForwardDiff<f32> + AskDiffInput<f32,DiffInput=Arr<f32,256>> + Send + Sync + 'static {
+	      NN: Network {
 	fn clone(&self) -> Self {
 		Environment {
 			solver:Solver::new(),
@@ -141,20 +172,24 @@ impl<L,S,NN> Clone for Environment<L,S,NN>
 			quited:self.quited.clone(),
 			kyokumen_score_map:self.kyokumen_score_map.clone(),
 			nodes:self.nodes.clone(),
-			think_start_time:self.think_start_time.clone()
+			think_start_time:self.think_start_time.clone(),
+			tracer:self.tracer.clone(),
+			accumulator:self.accumulator.clone(),
+			eval_cache:self.eval_cache.clone()
 		}
 	}
 }
 impl<L,S,NN> Environment<L,S,NN> where L: Logger, S: InfoSender,
-									   NN: ForwardAll<Input=DiffInput<DiffArr<f32,2517>,f32,2517,256>,Output=Arr<f32,1>> +
-									   	   PreTrain<f32> + ForwardDiff<f32> + AskDiffInput<f32,DiffInput=Arr<f32,256>> + Send + Sync + 'static {
+									   NN: Network {
 	pub fn new(event_queue:Arc<Mutex<UserEventQueue>>,
 			   evalutor:Arc<Intelligence<NN>>,
 			   info_sender:S,
 			   on_error_handler:Arc<Mutex<OnErrorHandler<L>>>,
 			   think_start_time:Instant,
 			   limit:Option<Instant>,
-			   current_limit:Option<Instant>) -> Environment<L,S,NN> {
+			   current_limit:Option<Instant>,
+			   tracer:Option<SearchTracer>,
+			   eval_cache:Arc<EvalCache>) -> Environment<L,S,NN> {
 		let stop = Arc::new(AtomicBool::new(false));
 		let quited = Arc::new(AtomicBool::new(false));
 
@@ -170,13 +205,15 @@ impl<L,S,NN> Environment<L,S,NN> where L: Logger, S: InfoSender,
 			stop:stop,
 			quited:quited,
 			kyokumen_score_map:KyokumenMap::new(),
-			nodes:Arc::new(AtomicU64::new(0))
+			nodes:Arc::new(AtomicU64::new(0)),
+			tracer:tracer,
+			accumulator:AccumulatorStack::new(),
+			eval_cache:eval_cache
 		}
 	}
 }
 pub struct Search<NN>
-	where NN: ForwardAll<Input=DiffInput<DiffArr<f32,2517>,f32,2517,256>,Output=Arr<f32,1>> +
-			  PreTrain<f32> + ForwardDiff<f32> + AskDiffInput<f32,DiffInput=Arr<f32,256>> + Send + Sync + 'static {
+	where NN: Network {
 	kyokumenhash:KyokumenHash<u64>,
 	base_depth:u32,
 	max_depth:u32,
@@ -189,11 +226,21 @@ pub struct Search<NN>
 	min_turn_count:u32,
 	display_evalute_score:bool,
 	adjust_depth:bool,
+	trace_file:Option<String>,
+	trace_max_depth:Option<u32>,
+	trace_max_nodes:Option<u64>,
+	max_nodes:Option<u64>,
+	eval_cache_size:usize,
+	eval_scale:f32,
+	resign_value:Option<i32>,
+	draw_value:i32,
+	material_evaluation:bool,
+	eval_dir:String,
+	eval_files:Vec<String>,
 	nn_type:PhantomData<NN>
 }
 impl<NN> Search<NN>
-	where NN: ForwardAll<Input=DiffInput<DiffArr<f32,2517>,f32,2517,256>,Output=Arr<f32,1>> +
-			  PreTrain<f32> + ForwardDiff<f32> + AskDiffInput<f32,DiffInput=Arr<f32,256>> + Send + Sync + 'static {
+	where NN: Network {
 	pub fn new() -> Search<NN> {
 		let max_ply_timelimit = if MAX_PLY_TIMELIMIT >  0 {
 			Some(Duration::from_millis(MAX_PLY_TIMELIMIT))
@@ -214,6 +261,17 @@ impl<NN> Search<NN>
 			min_turn_count:MIN_TURN_COUNT,
 			display_evalute_score:DEFALUT_DISPLAY_EVALUTE_SCORE,
 			adjust_depth:DEFAULT_ADJUST_DEPTH,
+			trace_file:None,
+			trace_max_depth:None,
+			trace_max_nodes:None,
+			max_nodes:None,
+			eval_cache_size:EVAL_CACHE_SIZE,
+			eval_scale:DEFAULT_EVAL_SCALE,
+			resign_value:None,
+			draw_value:DRAW_VALUE,
+			material_evaluation:false,
+			eval_dir:String::from(EVAL_DIR),
+			eval_files:Vec::new(),
 			nn_type:PhantomData::<NN>
 		}
 	}
@@ -315,7 +373,7 @@ impl<NN> Search<NN>
 	}
 
 	fn send_info<L,S>(&self, env:&mut Environment<L,S,NN>,
-					  depth:u32, seldepth:u32, pv:&Vec<AppliedMove>)
+					  depth:u32, seldepth:u32, pv:&Vec<AppliedMove>, s:Score)
 		where L: Logger, S: InfoSender, Arc<Mutex<OnErrorHandler<L>>>: Send + 'static {
 
 		let mut commands: Vec<UsiInfoSubCommand> = Vec::new();
@@ -325,6 +383,18 @@ impl<NN> Search<NN>
 			commands.push(UsiInfoSubCommand::SelDepth(seldepth));
 		}
 
+		// s is the score seen from the side to move of the last move of pv, so it is converted to the root side.
+		let s = if pv.len() % 2 == 1 {
+			s
+		} else {
+			-s
+		};
+
+		commands.push(UsiInfoSubCommand::Score(match s {
+			Score::INFINITE => UsiScore::Mate(UsiScoreMate::Plus),
+			Score::NEGINFINITE => UsiScore::Mate(UsiScoreMate::Minus),
+			Score::Value(s) => UsiScore::Cp(s as i64),
+		}));
 		commands.push(UsiInfoSubCommand::CurrMove(pv[0].to_move()));
 		commands.push(UsiInfoSubCommand::Pv(pv.clone().into_iter().map(|m| m.to_move()).collect()));
 		commands.push(UsiInfoSubCommand::Time((Instant::now() - env.think_start_time).as_millis() as u64));
@@ -362,22 +432,12 @@ impl<NN> Search<NN>
 		}
 	}
 
-	fn make_snapshot(&self,is_self:bool,evalutor:&Arc<Intelligence<NN>>,teban:Teban,state:&State,mc:&MochigomaCollections)
-		-> Result<(<NN as PreTrain<f32>>::OutStack,<NN as PreTrain<f32>>::OutStack),CommonError> {
-
-		let r = evalutor.make_snapshot(is_self,teban,state.get_banmen(),mc)?;
-
-		Ok(r)
-	}
-
 	fn evalute_by_diff<L,S>(&self,evalutor:&Arc<Intelligence<NN>>,
-								self_snapshot:&Arc<(<NN as PreTrain<f32>>::OutStack,<NN as PreTrain<f32>>::OutStack)>,
-								opponent_snapshot:&Arc<(<NN as PreTrain<f32>>::OutStack,<NN as PreTrain<f32>>::OutStack)>,
+								accumulator:&mut AccumulatorStack<NN>,
 								teban:Teban,state:&Option<&Arc<State>>,
 								mc:&Option<&Arc<MochigomaCollections>>,m:Option<AppliedMove>,
 					info_sender:&mut S,on_error_handler:&Arc<Mutex<OnErrorHandler<L>>>)
-		-> Result<(Evaluation,(<NN as PreTrain<f32>>::OutStack,<NN as PreTrain<f32>>::OutStack),
-				   			  (<NN as PreTrain<f32>>::OutStack,<NN as PreTrain<f32>>::OutStack)),CommonError>
+		-> Result<Evaluation,CommonError>
 		where L: Logger, S: InfoSender,
 			  Arc<Mutex<OnErrorHandler<L>>>: Send + 'static {
 
@@ -407,12 +467,7 @@ impl<NN> Search<NN>
 			}
 		};
 
-		let (s,self_snapshot,opponent_snapshot) = {
-			let m = m.to_move();
-			let (s, self_snapshot) = evalutor.evalute_by_diff(&self_snapshot, true, teban, state.get_banmen(), mc, &m)?;
-			let (_, opponent_snapshot) = evalutor.evalute_by_diff(&opponent_snapshot, false, teban.opposite(), state.get_banmen(), mc, &m)?;
-			(s,self_snapshot,opponent_snapshot)
-		};
+		let s = accumulator.push(evalutor, teban, state.get_banmen(), mc, &m.to_move())?;
 
 		let teban_str = match teban {
 			Teban::Sente => "sente",
@@ -421,13 +476,13 @@ impl<NN> Search<NN>
 
 		self.send_message(info_sender, on_error_handler, &format!("original nn evalute score =  {0: >17} ({1})",s,teban_str));
 
-		Ok((Evaluation::Result(Score::Value(s),Some(m)),self_snapshot,opponent_snapshot))
+		Ok(Evaluation::Result(Score::Value(s),Some(m)))
 	}
 
 	#[allow(unused)]
 	fn evalute_score_by_diff<L,S>(&self,evalutor:&Arc<Intelligence<NN>>,
-							is_self:bool,
-							self_snapshot:&Arc<(<NN as PreTrain<f32>>::OutStack,<NN as PreTrain<f32>>::OutStack)>,
+							accumulator:&mut AccumulatorStack<NN>,
+							eval_cache:&EvalCache,mhash:u64,shash:u64,
 							teban:Teban,state:&Option<&Arc<State>>,
 							mc:&Option<&Arc<MochigomaCollections>>,m:Option<AppliedMove>,
 							info_sender:&mut S,on_error_handler:&Arc<Mutex<OnErrorHandler<L>>>)
@@ -459,11 +514,13 @@ impl<NN> Search<NN>
 			}
 		};
 
-		let s = {
-			let m = m.to_move();
-			let (s, _) = evalutor.evalute_by_diff(&self_snapshot, is_self, teban, state.get_banmen(), mc, &m)?;
-			s
-		};
+		if let Some(s) = eval_cache.get(teban,mhash,shash) {
+			return Ok(Score::Value(s));
+		}
+
+		let s = accumulator.evalute(evalutor, teban, state.get_banmen(), mc, &m.to_move())?;
+
+		eval_cache.insert(teban,mhash,shash,s);
 
 		let teban_str = match teban {
 			Teban::Sente => "sente",
@@ -479,8 +536,6 @@ impl<NN> Search<NN>
 								env:&mut Environment<L,S,NN>,
 					  			event_dispatcher:&mut UserEventDispatcher<Search<NN>,CommonError,L>,
 					  			solver_event_dispatcher:&mut UserEventDispatcher<Solver<CommonError,NN>,CommonError,L>,
-								self_nn_snapshot:&Arc<(<NN as PreTrain<f32>>::OutStack,<NN as PreTrain<f32>>::OutStack)>,
-								opponent_nn_snapshot:&Arc<(<NN as PreTrain<f32>>::OutStack,<NN as PreTrain<f32>>::OutStack)>,
 								teban:Teban,state:&Arc<State>,
 								alpha:Score,beta:Score,
 								m:Option<AppliedMove>,mc:&Arc<MochigomaCollections>,
@@ -495,39 +550,94 @@ impl<NN> Search<NN>
 								mhash:u64,shash:u64,
 								depth:u32,current_depth:u32,base_depth:u32,
 								node_count:u64,
-								strategy:Strategy<L,S,NN,<NN as PreTrain<f32>>::OutStack>,
+								strategy:Strategy<L,S,NN>,
 	) -> Evaluation where L: Logger, S: InfoSender,
 						  Arc<Mutex<OnErrorHandler<L>>>: Send + 'static {
+		let mut mate = None;
+
+		let (r,reason) = self.negascout_node(env,event_dispatcher,solver_event_dispatcher,
+												teban,state,alpha,beta,m,mc,pv,
+												prev_state,prev_mc,obtained,
+												current_kyokumen_map,
+												self_already_oute_map,opponent_already_oute_map,
+												oute_kyokumen_map,
+												mhash,shash,
+												depth,current_depth,base_depth,
+												node_count,strategy,&mut mate);
+
+		if let Some(ref tracer) = env.tracer {
+			let reason = match (reason,r) {
+				(TraceReason::Exact,Evaluation::Result(s,_)) if s >= beta => TraceReason::BetaCutoff,
+				(TraceReason::Exact,Evaluation::Result(s,_)) if s <= alpha => TraceReason::FailLow,
+				(TraceReason::Exact,Evaluation::Timeout(_,_)) => TraceReason::Timeout,
+				(TraceReason::Exact,Evaluation::Error) => TraceReason::Error,
+				(reason,_) => reason
+			};
+
+			if let Err(ref e) = tracer.write(current_depth,depth,alpha,beta,r,reason,mate,pv) {
+				let _ = env.on_error_handler.lock().map(|h| h.call(e));
+			}
+		}
+
+		r
+	}
+
+	fn negascout_node<L,S>(self:&Arc<Self>,
+								env:&mut Environment<L,S,NN>,
+					  			event_dispatcher:&mut UserEventDispatcher<Search<NN>,CommonError,L>,
+					  			solver_event_dispatcher:&mut UserEventDispatcher<Solver<CommonError,NN>,CommonError,L>,
+								teban:Teban,state:&Arc<State>,
+								alpha:Score,beta:Score,
+								m:Option<AppliedMove>,mc:&Arc<MochigomaCollections>,
+								pv:&Vec<AppliedMove>,
+								prev_state:&Option<Arc<State>>,
+								prev_mc:&Option<Arc<MochigomaCollections>>,
+								obtained:Option<ObtainKind>,
+								current_kyokumen_map:&KyokumenMap<u64,u32>,
+								self_already_oute_map:&mut Option<KyokumenMap<u64,bool>>,
+								opponent_already_oute_map:&mut Option<KyokumenMap<u64,bool>>,
+								oute_kyokumen_map:&KyokumenMap<u64,()>,
+								mhash:u64,shash:u64,
+								depth:u32,current_depth:u32,base_depth:u32,
+								node_count:u64,
+								strategy:Strategy<L,S,NN>,
+								mate:&mut Option<&'static str>,
+	) -> (Evaluation,TraceReason) where L: Logger, S: InfoSender,
+						  Arc<Mutex<OnErrorHandler<L>>>: Send + 'static {
 		if let None = env.kyokumen_score_map.get(teban,&mhash,&shash) {
-			env.nodes.fetch_add(1,atomic::Ordering::Release);
+			let nodes = env.nodes.fetch_add(1,atomic::Ordering::Release) + 1;
+
+			if self.max_nodes.map_or(false,|n| nodes >= n) {
+				env.stop.store(true,atomic::Ordering::Release);
+			}
 		}
 
 		if let Some(ObtainKind::Ou) = obtained {
-			return Evaluation::Result(Score::NEGINFINITE, None);
+			return (Evaluation::Result(Score::NEGINFINITE, None),TraceReason::ObtainedOu);
 		}
 
 		if Rule::is_mate(teban,&*state) {
-			return Evaluation::Result(Score::INFINITE,None);
+			return (Evaluation::Result(Score::INFINITE,None),TraceReason::OuCapturable);
 		}
 
 		if self.timelimit_reached(&env.limit) || env.stop.load(atomic::Ordering::Acquire) {
 			self.send_message(&mut env.info_sender, &env.on_error_handler, "think timeout!");
-			return Evaluation::Timeout(None, None);
+			return (Evaluation::Timeout(None, None),TraceReason::Timeout);
 		}
 
 		if let Some(&(s,d)) = env.kyokumen_score_map.get(teban, &mhash, &shash) {
 			match s {
 				Score::INFINITE => {
 					self.send_message(&mut env.info_sender, &env.on_error_handler, "score corresponding to the hash was found in the map. value is infinite.");
-					return Evaluation::Result(s, None);
+					return (Evaluation::Result(s, None),TraceReason::HashHit);
 				},
 				Score::NEGINFINITE => {
 					self.send_message(&mut env.info_sender, &env.on_error_handler, "score corresponding to the hash was found in the map. value is neginfinite.");
-					return Evaluation::Result(s, None);
+					return (Evaluation::Result(s, None),TraceReason::HashHit);
 				},
 				Score::Value(s) if d >= depth => {
 					self.send_message(&mut env.info_sender, &env.on_error_handler, &format!("score corresponding to the hash was found in the map. value is {}.",s));
-					return Evaluation::Result(Score::Value(s), None);
+					return (Evaluation::Result(Score::Value(s), None),TraceReason::HashHit);
 				},
 				_ => ()
 			}
@@ -556,7 +666,7 @@ impl<NN> Search<NN>
 				this.send_seldepth(&mut info_sender, &on_error_handler, base_depth, current_depth + depth);
 			};
 
-			match env.solver.checkmate(false, teban, state, mc,
+			let r = env.solver.checkmate(false, teban, state, mc,
 									   self.max_ply,
 									   None,
 									   &mut oute_kyokumen_map.clone(),
@@ -569,12 +679,16 @@ impl<NN> Search<NN>
 									   &env.nodes,
 									   &mut on_searchstart,
 									   &env.event_queue,
-									   solver_event_dispatcher) {
+									   solver_event_dispatcher);
+
+			*mate = Some(mate_result(&r));
+
+			match r {
 				MaybeMate::MateMoves(_, ref mvs) if mvs.len() > 0 => {
-					return Evaluation::Result(Score::INFINITE, Some(mvs[0].to_applied_move()));
+					return (Evaluation::Result(Score::INFINITE, Some(mvs[0].to_applied_move())),TraceReason::SolverMate);
 				},
 				MaybeMate::MateMoves(_, _) => {
-					return Evaluation::Result(Score::INFINITE, None);
+					return (Evaluation::Result(Score::INFINITE, None),TraceReason::SolverMate);
 				},
 				_ => ()
 			}
@@ -582,19 +696,19 @@ impl<NN> Search<NN>
 
 		if (depth == 0 || current_depth > self.max_depth) && !Rule::is_mate(teban.opposite(),&*state) {
 			let r = self.evalute_score_by_diff(&env.evalutor,
-											   true,
-											   &self_nn_snapshot,
+											   &mut env.accumulator,
+											   &env.eval_cache,mhash,shash,
 											   teban,
 											   &prev_state.as_ref(), &prev_mc.as_ref(),
 											   m, &mut env.info_sender, &env.on_error_handler);
 
 			match r {
 				Ok(s) => {
-					return Evaluation::Result(s, None);
+					return (Evaluation::Result(s, None),TraceReason::Evalute);
 				},
 				Err(ref e) => {
 					let _ = env.on_error_handler.lock().map(|h| h.call(e));
-					return Evaluation::Error;
+					return (Evaluation::Error,TraceReason::Error);
 				}
 			}
 		}
@@ -603,35 +717,36 @@ impl<NN> Search<NN>
 
 		if self.timelimit_reached(&env.limit) || env.stop.load(atomic::Ordering::Acquire) {
 			self.send_message(&mut env.info_sender, &env.on_error_handler, "think timeout!");
-			return Evaluation::Timeout(None,None);
+			return (Evaluation::Timeout(None,None),TraceReason::Timeout);
 		}
 
 		let (mvs,responded_oute) = if Rule::is_mate(teban.opposite(),&*state) {
 			if depth == 0 || current_depth == self.max_depth {
 				if self.timelimit_reached(&env.limit) || env.stop.load(atomic::Ordering::Acquire) {
 					self.send_message(&mut env.info_sender, &env.on_error_handler, "think timeout!");
-					return Evaluation::Timeout(None,None);
+					return (Evaluation::Timeout(None,None),TraceReason::Timeout);
 				}
 			}
 
 			let mvs = Rule::respond_oute_only_moves_all(teban, &*state, &*mc);
 
 			if mvs.len() == 0 {
-				return Evaluation::Result(Score::NEGINFINITE,None);
+				return (Evaluation::Result(Score::NEGINFINITE,None),TraceReason::NoMoves);
 			} else if depth == 0 || current_depth == self.max_depth {
-				let r = self.evalute_score_by_diff(&env.evalutor,true,
-										   &self_nn_snapshot,
+				let r = self.evalute_score_by_diff(&env.evalutor,
+										   &mut env.accumulator,
+										   &env.eval_cache,mhash,shash,
 										   teban,
 										   &prev_state.as_ref(), &prev_mc.as_ref(),
 										   m, &mut env.info_sender, &env.on_error_handler);
 
 				match r {
 					Ok(s) => {
-						return Evaluation::Result(s, None);
+						return (Evaluation::Result(s, None),TraceReason::Evalute);
 					},
 					Err(ref e) => {
 						let _ = env.on_error_handler.lock().map(|h| h.call(e));
-						return Evaluation::Error;
+						return (Evaluation::Error,TraceReason::Error);
 					}
 				}
 			} else {
@@ -640,7 +755,7 @@ impl<NN> Search<NN>
 		} else {
 			if self.timelimit_reached(&env.limit) || env.stop.load(atomic::Ordering::Acquire) {
 				self.send_message(&mut env.info_sender, &env.on_error_handler, "think timeout!");
-				return Evaluation::Timeout(None,None);
+				return (Evaluation::Timeout(None,None),TraceReason::Timeout);
 			}
 
 			let mvs:Vec<LegalMove> = Rule::legal_moves_all(teban, &*state, &*mc);
@@ -649,47 +764,28 @@ impl<NN> Search<NN>
 		};
 
 		if mvs.len() == 0 {
-			return Evaluation::Result(Score::NEGINFINITE,None);
+			return (Evaluation::Result(Score::NEGINFINITE,None),TraceReason::NoMoves);
 		} else if self.timelimit_reached(&env.limit) || env.stop.load(atomic::Ordering::Acquire) {
 			self.send_message(&mut env.info_sender, &env.on_error_handler, "think timeout!");
-			return Evaluation::Timeout(None,Some(mvs[0].to_applied_move()));
+			return (Evaluation::Timeout(None,Some(mvs[0].to_applied_move())),TraceReason::Timeout);
 		}
 
-		let (current_self_nn_ss,current_opponent_nn_ss) = if prev_state.is_some() {
-			let (self_nn_snapshot,opponent_nn_snapshot) = match self.evalute_by_diff(&env.evalutor,
-																					  &self_nn_snapshot,
-																					  &opponent_nn_snapshot,
-																					  teban,
-																					  &prev_state.as_ref(), &prev_mc.as_ref(),
-																					  m, &mut env.info_sender, &env.on_error_handler) {
-				Ok((_, sss, oss)) => {
-					(Arc::new(sss), Arc::new(oss))
-				},
-				Err(ref e) => {
-					let _ = env.on_error_handler.lock().map(|h| h.call(e));
-					return Evaluation::Error;
-				}
-			};
-			(Some(self_nn_snapshot), Some(opponent_nn_snapshot))
-		} else {
-			(None, None)
-		};
-
-		let self_nn_snapshot = match current_self_nn_ss {
-			Some(ref ss) => ss,
-			None => self_nn_snapshot,
-		};
-
-		let opponent_nn_snapshot = match current_opponent_nn_ss {
-			Some(ref ss) => ss,
-			None => opponent_nn_snapshot,
-		};
-
 		let _ = event_dispatcher.dispatch_events(self,&*env.event_queue);
 
 		if self.timelimit_reached(&env.limit) || env.stop.load(atomic::Ordering::Acquire) {
 			self.send_message(&mut env.info_sender, &env.on_error_handler, "think timeout!");
-			return Evaluation::Timeout(None,Some(mvs[0].to_applied_move()));
+			return (Evaluation::Timeout(None,Some(mvs[0].to_applied_move())),TraceReason::Timeout);
+		}
+
+		if prev_state.is_some() {
+			if let Err(ref e) = self.evalute_by_diff(&env.evalutor,
+													  &mut env.accumulator,
+													  teban,
+													  &prev_state.as_ref(), &prev_mc.as_ref(),
+													  m, &mut env.info_sender, &env.on_error_handler) {
+				let _ = env.on_error_handler.lock().map(|h| h.call(e));
+				return (Evaluation::Error,TraceReason::Error);
+			}
 		}
 
 		let mut mvs = mvs.into_iter().map(|m| {
@@ -729,12 +825,10 @@ impl<NN> Search<NN>
 
 		mvs.sort_by(|a,b| b.0.cmp(&a.0));
 
-		strategy(self,
+		let r = strategy(self,
 					env,
 					event_dispatcher,
 					solver_event_dispatcher,
-					&self_nn_snapshot,
-					&opponent_nn_snapshot,
 					teban,state,pv,
 					alpha,beta,mc,
 					current_kyokumen_map,
@@ -746,7 +840,13 @@ impl<NN> Search<NN>
 					current_depth,base_depth,
 					node_count,
 					&mvs,
-					responded_oute)
+					responded_oute);
+
+		if prev_state.is_some() {
+			env.accumulator.pop();
+		}
+
+		(r,TraceReason::Exact)
 	}
 
 	fn startup_strategy(&self,teban:Teban,state:&State,mc:&MochigomaCollections,
@@ -814,8 +914,6 @@ impl<NN> Search<NN>
 								env:&mut Environment<L,S,NN>,
 						  		event_dispatcher:&mut UserEventDispatcher<Search<NN>,CommonError,L>,
 						  		solver_event_dispatcher:&mut UserEventDispatcher<Solver<CommonError,NN>,CommonError,L>,
-								self_nn_snapshot:&Arc<(<NN as PreTrain<f32>>::OutStack,<NN as PreTrain<f32>>::OutStack)>,
-								opponent_nn_snapshot:&Arc<(<NN as PreTrain<f32>>::OutStack,<NN as PreTrain<f32>>::OutStack)>,
 								teban:Teban,state:&Arc<State>,pv:&Vec<AppliedMove>,
 								mut alpha:Score,beta:Score,
 								mc:&Arc<MochigomaCollections>,
@@ -867,7 +965,7 @@ impl<NN> Search<NN>
 								let s = if Rule::is_mate(teban.opposite(),&state) {
 									Score::NEGINFINITE
 								} else {
-									Score::Value(0)
+									search.draw_score(current_depth)
 								};
 
 								if s > scoreval {
@@ -894,7 +992,7 @@ impl<NN> Search<NN>
 
 							for i in 0..repeat {
 								let b = match (i,repeat) {
-									(0,2) => alpha + 1,
+									(0,2) => alpha + NULL_WINDOW,
 									(1,2) | (0,1) => beta,
 									_ => {
 										return Evaluation::Error;
@@ -905,8 +1003,6 @@ impl<NN> Search<NN>
 									env,
 									event_dispatcher,
 									solver_event_dispatcher,
-									opponent_nn_snapshot,
-									self_nn_snapshot,
 									teban.opposite(),&state,
 									-b,-alpha,Some(m.to_applied_move()),&mc,
 									&pv,
@@ -952,7 +1048,7 @@ impl<NN> Search<NN>
 										}
 
 										if -s > scoreval {
-											search.send_info(env, base_depth,current_depth,&pv);
+											search.send_info(env, base_depth,current_depth,&pv,-s);
 											search.send_score(&mut env.info_sender,&env.on_error_handler,teban,-s);
 
 											scoreval = -s;
@@ -999,8 +1095,6 @@ impl<NN> Search<NN>
 								env:&mut Environment<L,S,NN>,
 								event_dispatcher:&mut UserEventDispatcher<Search<NN>,CommonError,L>,
 								_:&mut UserEventDispatcher<Solver<CommonError,NN>,CommonError,L>,
-								self_nn_snapshot:&Arc<(<NN as PreTrain<f32>>::OutStack,<NN as PreTrain<f32>>::OutStack)>,
-								opponent_nn_snapshot:&Arc<(<NN as PreTrain<f32>>::OutStack,<NN as PreTrain<f32>>::OutStack)>,
 								teban:Teban,state:&Arc<State>,pv:&Vec<AppliedMove>,
 								mut alpha:Score,beta:Score,
 								mc:&Arc<MochigomaCollections>,
@@ -1083,7 +1177,7 @@ impl<NN> Search<NN>
 							let mut pv = pv.clone();
 							pv.push(m);
 
-							search.send_info(env, base_depth,current_depth,&pv);
+							search.send_info(env, base_depth,current_depth,&pv,-s);
 							search.send_score(&mut env.info_sender,&env.on_error_handler,teban,-s);
 
 							scoreval = -s;
@@ -1134,7 +1228,7 @@ impl<NN> Search<NN>
 									let s = if Rule::is_mate(teban.opposite(),&state) {
 										Score::NEGINFINITE
 									} else {
-										Score::Value(0)
+										search.draw_score(current_depth)
 									};
 									if s > scoreval {
 										scoreval = s;
@@ -1150,8 +1244,6 @@ impl<NN> Search<NN>
 								}
 
 								let search = search.clone();
-								let opponent_nn_snapshot = opponent_nn_snapshot.clone();
-								let self_nn_snapshot = self_nn_snapshot.clone();
 								let state = Arc::new(state);
 								let mc = Arc::new(mc);
 								let mut self_already_oute_map = self_already_oute_map.clone();
@@ -1175,7 +1267,7 @@ impl<NN> Search<NN>
 
 									for i in 0..repeat {
 										let b = match (i,repeat) {
-											(0,2) => alpha + 1,
+											(0,2) => alpha + NULL_WINDOW,
 											(1,2) | (0,1) => beta,
 											_ => {
 												let _ = sender.send((Evaluation::Error,m));
@@ -1190,8 +1282,6 @@ impl<NN> Search<NN>
 											&mut env,
 											&mut event_dispatcher,
 											&mut solver_event_dispatcher,
-											&opponent_nn_snapshot,
-											&self_nn_snapshot,
 											teban.opposite(),&state,
 											-b,-a,Some(m),&mc,
 											&pv,
@@ -1364,6 +1454,23 @@ impl<NN> Search<NN>
 		self.kyokumenhash.calc_sub_hash(h,t,b,mc,m,obtained)
 	}
 
+	/// Whether the evaluation of the best move in centipawns is low enough to resign.
+	fn resign(&self,s:i32) -> bool {
+		self.resign_value.map(|v| s <= -v).unwrap_or(false)
+	}
+
+	/// Score of the repetition draw for the side to move at current_depth (the root is 1).
+	///
+	/// The draw is worth -draw_value centipawns to the side to move at the root,
+	/// so the draw is accepted only when the other moves evaluate below that.
+	fn draw_score(&self,current_depth:u32) -> Score {
+		if current_depth % 2 == 1 {
+			Score::Value(-self.draw_value)
+		} else {
+			Score::Value(self.draw_value)
+		}
+	}
+
 	#[inline]
 	fn calc_initial_hash(&self,b:&Banmen,
 		ms:&Mochigoma,mg:&Mochigoma) -> (u64,u64) {
@@ -1371,8 +1478,7 @@ impl<NN> Search<NN>
 	}
 }
 pub struct NNShogiPlayer<NN>
-	where NN: ForwardAll<Input=DiffInput<DiffArr<f32,2517>,f32,2517,256>,Output=Arr<f32,1>> +
-			  PreTrain<f32> + ForwardDiff<f32> + AskDiffInput<f32,DiffInput=Arr<f32,256>> + Send + Sync + 'static {
+	where NN: Network {
 	search:Arc<Search<NN>>,
 	kyokumen:Option<Kyokumen>,
 	mhash:u64,
@@ -1382,25 +1488,28 @@ pub struct NNShogiPlayer<NN>
 	remaining_turns:u32,
 	evalutor:Option<Arc<Intelligence<NN>>>,
 	evalutor_creator: Box<dyn
-	Fn() -> Result<Intelligence<NN>,ApplicationError> + Send + 'static>,
+	Fn(&str,&[String],bool) -> Result<Intelligence<NN>,ApplicationError> + Send + 'static>,
+	evalutor_source:(String,Vec<String>,bool),
 	pub history:Vec<(Banmen,MochigomaCollections,u64,u64)>,
 	count_of_move_started:u32,
 	moved:bool,
+	debug_info:Arc<DebugInfo<NN>>,
+	eval_cache:Arc<EvalCache>,
+	last_score:Option<UsiScore>,
+	logger:Option<Arc<Mutex<FileLogger>>>,
 }
 impl<NN> fmt::Debug for NNShogiPlayer<NN>
-	where NN: ForwardAll<Input=DiffInput<DiffArr<f32,2517>,f32,2517,256>,Output=Arr<f32,1>> +
-			  PreTrain<f32> + ForwardDiff<f32> + AskDiffInput<f32,DiffInput=Arr<f32,256>> + Send + Sync + 'static
+	where NN: Network
 	{
 		fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		write!(f, "NNShogiPlayer")
 	}
 }
 impl<NN> NNShogiPlayer<NN>
-	where NN: ForwardAll<Input=DiffInput<DiffArr<f32,2517>,f32,2517,256>,Output=Arr<f32,1>> +
-			  PreTrain<f32> + ForwardDiff<f32> + AskDiffInput<f32,DiffInput=Arr<f32,256>> + Send + Sync + 'static
+	where NN: Network
 	{
 
-	pub fn new<C: Fn() -> Result<Intelligence<NN>,ApplicationError> + Send + 'static>(evalutor_creator:C)
+	pub fn new<C: Fn(&str,&[String],bool) -> Result<Intelligence<NN>,ApplicationError> + Send + 'static>(evalutor_creator:C)
 		-> NNShogiPlayer<NN> {
 
 		NNShogiPlayer {
@@ -1413,15 +1522,44 @@ impl<NN> NNShogiPlayer<NN>
 			remaining_turns:TURN_COUNT,
 			evalutor:None,
 			evalutor_creator:Box::new(evalutor_creator),
+			evalutor_source:(String::from(EVAL_DIR),Vec::new(),false),
 			history:Vec::new(),
 			count_of_move_started:0,
 			moved:false,
+			debug_info:Arc::new(DebugInfo::new()),
+			eval_cache:Arc::new(EvalCache::new(0)),
+			last_score:None,
+			logger:None,
+		}
+	}
+
+	/// Logger receiving the warnings of the player, which are written to the standard error without it.
+	pub fn set_logger(&mut self,logger:Arc<Mutex<FileLogger>>) {
+		self.logger = Some(logger);
+	}
+
+	fn warn(&self,message:String) {
+		match self.logger {
+			Some(ref logger) => {
+				let _ = logger.lock().map(|mut logger| logger.logging(&message));
+			},
+			None => {
+				let _ = USIStdErrorWriter::write(&format!("{}\n",message));
+			}
 		}
 	}
+
+	pub fn debug_info(&self) -> Arc<DebugInfo<NN>> {
+		self.debug_info.clone()
+	}
+
+	/// Score of the root position from the side to move found by the last think, if the search reached a score.
+	pub fn last_score(&self) -> Option<UsiScore> {
+		self.last_score.clone()
+	}
 }
 impl<NN> USIPlayer<CommonError> for NNShogiPlayer<NN>
-	where NN: ForwardAll<Input=DiffInput<DiffArr<f32,2517>,f32,2517,256>,Output=Arr<f32,1>> +
-			  PreTrain<f32> + ForwardDiff<f32> + AskDiffInput<f32,DiffInput=Arr<f32,256>> + Send + Sync + 'static
+	where NN: Network
 	{
 
 	const ID: &'static str = "nnshogi";
@@ -1441,6 +1579,17 @@ impl<NN> USIPlayer<CommonError> for NNShogiPlayer<NN>
 		kinds.insert(String::from("NetworkDelay"),SysEventOptionKind::Num);
 		kinds.insert(String::from("DispEvaluteScore"),SysEventOptionKind::Bool);
 		kinds.insert(String::from("AdjustDepth"),SysEventOptionKind::Bool);
+		kinds.insert(String::from("TraceFile"),SysEventOptionKind::Str);
+		kinds.insert(String::from("TraceMaxDepth"),SysEventOptionKind::Num);
+		kinds.insert(String::from("TraceMaxNodes"),SysEventOptionKind::Num);
+		kinds.insert(String::from("MaxNodes"),SysEventOptionKind::Num);
+		kinds.insert(String::from("EvalCacheSize"),SysEventOptionKind::Num);
+		kinds.insert(String::from("EvalScale"),SysEventOptionKind::Num);
+		kinds.insert(String::from("ResignValue"),SysEventOptionKind::Num);
+		kinds.insert(String::from("DrawValue"),SysEventOptionKind::Num);
+		kinds.insert(String::from("MaterialEvaluation"),SysEventOptionKind::Bool);
+		kinds.insert(String::from("EvalDir"),SysEventOptionKind::Str);
+		kinds.insert(String::from("EvalFiles"),SysEventOptionKind::Str);
 
 		Ok(kinds)
 	}
@@ -1457,6 +1606,17 @@ impl<NN> USIPlayer<CommonError> for NNShogiPlayer<NN>
 		options.insert(String::from("NetworkDelay"),UsiOptType::Spin(0,10000,Some(NETWORK_DELAY as i64)));
 		options.insert(String::from("DispEvaluteScore"),UsiOptType::Check(Some(DEFALUT_DISPLAY_EVALUTE_SCORE)));
 		options.insert(String::from("AdjustDepth"),UsiOptType::Check(Some(DEFAULT_ADJUST_DEPTH)));
+		options.insert(String::from("TraceFile"),UsiOptType::FileName(Some(String::from(""))));
+		options.insert(String::from("TraceMaxDepth"),UsiOptType::Spin(0,100,Some(0)));
+		options.insert(String::from("TraceMaxNodes"),UsiOptType::Spin(0,100000000,Some(0)));
+		options.insert(String::from("MaxNodes"),UsiOptType::Spin(0,i64::MAX,Some(0)));
+		options.insert(String::from("EvalCacheSize"),UsiOptType::Spin(0,4096,Some(EVAL_CACHE_SIZE as i64)));
+		options.insert(String::from("EvalScale"),UsiOptType::Spin(1,10000,Some(DEFAULT_EVAL_SCALE as i64)));
+		options.insert(String::from("ResignValue"),UsiOptType::Spin(0,EVAL_MAX as i64,Some(RESIGN_VALUE as i64)));
+		options.insert(String::from("DrawValue"),UsiOptType::Spin(-EVAL_MAX as i64,EVAL_MAX as i64,Some(DRAW_VALUE as i64)));
+		options.insert(String::from("MaterialEvaluation"),UsiOptType::Check(Some(false)));
+		options.insert(String::from("EvalDir"),UsiOptType::String(Some(String::from(EVAL_DIR))));
+		options.insert(String::from("EvalFiles"),UsiOptType::String(Some(String::from(""))));
 
 		Ok(options)
 	}
@@ -1464,11 +1624,44 @@ impl<NN> USIPlayer<CommonError> for NNShogiPlayer<NN>
 		-> Result<(),CommonError> where W: USIOutputWriter + Send + 'static,
 							  L: Logger + Send + 'static {
 		match self.evalutor {
-			Some(_) => (),
-			None => {
-				self.evalutor = Some(Arc::new((self.evalutor_creator)()?));
+			Some(ref mut evalutor) if self.evalutor_source.0 == self.search.eval_dir &&
+									  self.evalutor_source.1 == self.search.eval_files &&
+									  self.evalutor_source.2 == self.search.material_evaluation => {
+				if evalutor.eval_scale() != self.search.eval_scale {
+					// The networks are shared with the previous evalutor, only the scale is replaced.
+					Arc::make_mut(evalutor).set_eval_scale(self.search.eval_scale);
+
+					self.debug_info.set_evalutor(evalutor.clone());
+					self.eval_cache.clear();
+				}
+			},
+			_ => {
+				let mut evalutor = (self.evalutor_creator)(&self.search.eval_dir,
+															&self.search.eval_files,
+															self.search.material_evaluation)?;
+
+				self.evalutor_source = (self.search.eval_dir.clone(),
+										self.search.eval_files.clone(),
+										self.search.material_evaluation);
+
+				evalutor.set_eval_scale(self.search.eval_scale);
+
+				if let Some(file) = evalutor.fallback() {
+					self.warn(fallback_warning(file));
+				}
+
+				let evalutor = Arc::new(evalutor);
+
+				self.debug_info.set_evalutor(evalutor.clone());
+				self.evalutor = Some(evalutor);
+				self.eval_cache.clear();
 			}
 		}
+
+		if self.eval_cache.size() != self.search.eval_cache_size {
+			self.eval_cache = Arc::new(EvalCache::new(self.search.eval_cache_size));
+		}
+
 		Ok(())
 	}
 	fn set_option(&mut self,name:String,value:SysEventOption) -> Result<(),CommonError> {
@@ -1523,6 +1716,50 @@ impl<NN> USIPlayer<CommonError> for NNShogiPlayer<NN>
 							_ => DEFAULT_ADJUST_DEPTH,
 						}
 					},
+					"TraceFile" => {
+						search.trace_file = match value {
+							SysEventOption::Str(ref file) if file.is_empty() || file == "<empty>" => {
+								None
+							},
+							SysEventOption::Str(file) => {
+								Some(file)
+							},
+							_ => None,
+						};
+					},
+					"TraceMaxDepth" => {
+						search.trace_max_depth = match value {
+							SysEventOption::Num(0) => {
+								None
+							},
+							SysEventOption::Num(depth) => {
+								Some(depth as u32)
+							},
+							_ => None,
+						};
+					},
+					"TraceMaxNodes" => {
+						search.trace_max_nodes = match value {
+							SysEventOption::Num(0) => {
+								None
+							},
+							SysEventOption::Num(nodes) => {
+								Some(nodes as u64)
+							},
+							_ => None,
+						};
+					},
+					"MaxNodes" => {
+						search.max_nodes = match value {
+							SysEventOption::Num(0) => {
+								None
+							},
+							SysEventOption::Num(nodes) => {
+								Some(nodes as u64)
+							},
+							_ => None,
+						};
+					},
 					"MAX_PLY" => {
 						search.max_ply = match value {
 							SysEventOption::Num(0) => {
@@ -1573,6 +1810,71 @@ impl<NN> USIPlayer<CommonError> for NNShogiPlayer<NN>
 							_ => MIN_TURN_COUNT,
 						};
 					},
+					"EvalCacheSize" => {
+						search.eval_cache_size = match value {
+							SysEventOption::Num(size) => {
+								size as usize
+							},
+							_ => EVAL_CACHE_SIZE,
+						};
+					},
+					"EvalScale" => {
+						search.eval_scale = match value {
+							SysEventOption::Num(scale) => {
+								scale as f32
+							},
+							_ => DEFAULT_EVAL_SCALE,
+						};
+					},
+					"ResignValue" => {
+						search.resign_value = match value {
+							SysEventOption::Num(0) => {
+								None
+							},
+							SysEventOption::Num(v) => {
+								Some(v as i32)
+							},
+							_ => None,
+						};
+					},
+					"DrawValue" => {
+						search.draw_value = match value {
+							SysEventOption::Num(v) => {
+								v as i32
+							},
+							_ => DRAW_VALUE,
+						};
+					},
+					"MaterialEvaluation" => {
+						search.material_evaluation = match value {
+							SysEventOption::Bool(b) => {
+								b
+							},
+							_ => false,
+						};
+					},
+					"EvalDir" => {
+						search.eval_dir = match value {
+							SysEventOption::Str(ref dir) if dir.is_empty() || dir == "<empty>" => {
+								String::from(EVAL_DIR)
+							},
+							SysEventOption::Str(dir) => {
+								dir
+							},
+							_ => String::from(EVAL_DIR),
+						};
+					},
+					"EvalFiles" => {
+						search.eval_files = match value {
+							SysEventOption::Str(ref files) if files == "<empty>" => {
+								Vec::new()
+							},
+							SysEventOption::Str(files) => {
+								files.split(',').map(|f| f.trim()).filter(|f| !f.is_empty()).map(|f| f.to_string()).collect()
+							},
+							_ => Vec::new(),
+						};
+					},
 					_ => (),
 				}
 				Ok(())
@@ -1586,6 +1888,7 @@ impl<NN> USIPlayer<CommonError> for NNShogiPlayer<NN>
 	}
 	fn newgame(&mut self) -> Result<(),CommonError> {
 		self.kyokumen = None;
+		self.debug_info.clear_position();
 		self.history.clear();
 		self.count_of_move_started = 0;
 		self.remaining_turns = self.search.turn_count;
@@ -1663,11 +1966,15 @@ impl<NN> USIPlayer<CommonError> for NNShogiPlayer<NN>
 			current_teban = current_teban.opposite();
 		}
 
-		self.kyokumen = Some(Kyokumen {
+		let kyokumen = Kyokumen {
 			state:state,
 			mc:mc,
 			teban:t
-		});
+		};
+
+		self.debug_info.set_position(kyokumen.clone(),mhash,shash);
+
+		self.kyokumen = Some(kyokumen);
 		self.mhash = mhash;
 		self.shash = shash;
 		self.oute_kyokumen_map = oute_kyokumen_map;
@@ -1699,9 +2006,6 @@ impl<NN> USIPlayer<CommonError> for NNShogiPlayer<NN>
 
 		match self.evalutor {
 			Some(ref evalutor) => {
-				let self_nn_snapshot = self.search.make_snapshot(true,evalutor,teban,state,mc)?;
-				let opponent_nn_snapshot = self.search.make_snapshot(false,evalutor,teban.opposite(),state,mc)?;
-
 				let prev_state:Option<Arc<State>> = None;
 				let prev_mc:Option<Arc<MochigomaCollections>> = None;
 
@@ -1711,19 +2015,40 @@ impl<NN> USIPlayer<CommonError> for NNShogiPlayer<NN>
 					Search::single_search
 				};
 
+				let tracer = match self.search.trace_file {
+					Some(ref file) => {
+						let tracer = SearchTracer::new(file,
+													   self.search.trace_max_depth,
+													   self.search.trace_max_nodes)
+							.map_err(|e| CommonError::Fail(e.to_string()))?;
+
+						tracer.start(teban,state.get_banmen(),mc).map_err(|e| CommonError::Fail(e.to_string()))?;
+
+						Some(tracer)
+					},
+					None => None
+				};
+
 				let mut env = Environment::new(
 													event_queue,
 													evalutor.clone(),
 													info_sender.clone(),
 													on_error_handler.clone(),
 																think_start_time.clone(),
-													limit,current_limit);
+													limit,current_limit,
+													tracer,
+													self.eval_cache.clone());
+
+				env.accumulator.reset(evalutor,teban,state.get_banmen(),mc)?;
 
 				let mut event_dispatcher = self.search.create_event_dispatcher(&on_error_handler,&env.stop,&env.quited);
 				let mut solver_event_dispatcher = self.search.create_event_dispatcher(&on_error_handler,&env.stop,&env.quited);
 
+				env.eval_cache.reset_stats();
+
 				let _pinfo_sender = {
 					let nodes = env.nodes.clone();
+					let eval_cache = env.eval_cache.clone();
 					let think_start_time = think_start_time.clone();
 					let on_error_handler = env.on_error_handler.clone();
 
@@ -1737,15 +2062,22 @@ impl<NN> USIPlayer<CommonError> for NNShogiPlayer<NN>
 							commands.push(UsiInfoSubCommand::Nps(nodes.load(Ordering::Acquire) / sec));
 						}
 
+						let (hits,probes) = eval_cache.stats();
+
+						if probes > 0 {
+							commands.push(UsiInfoSubCommand::Str(format!(
+								"evalcache hits {} probes {} hitrate {:.1}%",hits,probes,hits as f64 * 100. / probes as f64
+							)));
+						}
+
 						commands
 					}, &on_error_handler)
 				};
 
-				let result = match self.search.negascout(
+				let evaluation = self.search.negascout(
 							&mut env,
 							&mut event_dispatcher,
 							&mut solver_event_dispatcher,
-							&Arc::new(self_nn_snapshot),&Arc::new(opponent_nn_snapshot),
 							teban,&Arc::new(state.clone()), Score::NEGINFINITE,
 							Score::INFINITE, None,&Arc::new(mc.clone()),
 							&Vec::new(),
@@ -1758,19 +2090,38 @@ impl<NN> USIPlayer<CommonError> for NNShogiPlayer<NN>
 							mhash,shash,
 							base_depth, 1, base_depth,
 							1,
-							strategy) {
+							strategy);
+
+				self.last_score = match evaluation {
+					Evaluation::Result(s,_) | Evaluation::Timeout(Some(s),_) => {
+						Some(match s {
+							Score::INFINITE => UsiScore::Mate(UsiScoreMate::Plus),
+							Score::NEGINFINITE => UsiScore::Mate(UsiScoreMate::Minus),
+							Score::Value(s) => UsiScore::Cp(s as i64),
+						})
+					},
+					_ => None
+				};
+
+				let result = match evaluation {
 					Evaluation::Result(_,None) => {
 						BestMove::Resign
 					},
 					Evaluation::Result(Score::NEGINFINITE,_) => {
 						BestMove::Resign
 					},
+					Evaluation::Result(Score::Value(s),_) if self.search.resign(s) => {
+						BestMove::Resign
+					},
 					Evaluation::Result(_,Some(m)) => {
 						BestMove::Move(m.to_move(),None)
 					},
 					Evaluation::Timeout(Some(Score::NEGINFINITE),_) => {
 						BestMove::Resign
 					}
+					Evaluation::Timeout(Some(Score::Value(s)),_) if self.search.resign(s) => {
+						BestMove::Resign
+					}
 					Evaluation::Timeout(_,Some(m)) => {
 						BestMove::Move(m.to_move(),None)
 					}
@@ -1785,6 +2136,10 @@ impl<NN> USIPlayer<CommonError> for NNShogiPlayer<NN>
 					}
 				};
 
+				if let Some(ref tracer) = env.tracer {
+					tracer.flush().map_err(|e| CommonError::Fail(e.to_string()))?;
+				}
+
 				if self.remaining_turns > self.search.min_turn_count {
 					self.remaining_turns -= 1;
 				}
@@ -0,0 +1,444 @@
+use std::marker::PhantomData;
+use std::path::Path;
+
+use nncombinator::arr::{Arr, DiffArr};
+use nncombinator::device::DeviceCpu;
+use nncombinator::error::EvaluateError;
+use nncombinator::persistence::Persistence;
+use rand::{Rng, SeedableRng};
+use rand_xorshift::XorShiftRng;
+
+use usiagent::rule::*;
+use usiagent::output::USIStdErrorWriter;
+
+use error::ApplicationError;
+use model::{FORMAT_VERSION, ModelFilePersistence, ModelHeader};
+use features::FeatureSet;
+use nn::{Architecture, Ensemble, fallback_warning, IntelligenceCreator, Network};
+use perft::Perft;
+
+pub const QUANTIZATION:&'static str = "int16";
+
+/// Scale of the weights of the first layer and the outputs of the hidden layers. (1.0 is represented by 8128)
+const QA:i32 = 127 << 6;
+/// Upper limit of the outputs of the hidden layers, independent of the scale. (8.0)
+const QA_MAX:i32 = QA * 8;
+/// Scale of the weights of the second and the output layers. (1.0 is represented by 4096)
+const QB:i32 = 1 << QB_SHIFT;
+const QB_SHIFT:u32 = 12;
+
+const CHECK_POSITIONS:usize = 1000;
+const CHECK_MAX_PLY:usize = 200;
+
+pub fn quantized_filename(filename:&str) -> String {
+	match filename.strip_suffix(".bin") {
+		Some(name) => format!("{}.q.bin",name),
+		None => format!("{}.q",filename),
+	}
+}
+/// Integer version of the network, the first layer has int16 weights accumulated in int32
+/// and the later layers have int16 weights summed in int64.
+///
+/// The outputs of the hidden layers are clipped to 0..QA_MAX, so it only approximates the networks using ReLU.
+pub struct QuantizedNetwork<F> where F: FeatureSet {
+	h1:usize,
+	h2:usize,
+	b1:Vec<i16>,
+	/// INPUTS x h1
+	w1:Vec<i16>,
+	b2:Vec<i32>,
+	/// h2 x h1
+	w2:Vec<i16>,
+	b3:i32,
+	w3:Vec<i16>,
+	features:PhantomData<F>,
+}
+impl<F> QuantizedNetwork<F> where F: FeatureSet {
+	/// Quantizes the weights in the layout written by the Linear persistence of nncombinator.
+	pub fn from_weights(h1:usize,h2:usize,weights:&[f32]) -> Result<QuantizedNetwork<F>,ApplicationError> {
+		if weights.len() != (F::INPUTS + 1) * h1 + (h1 + 1) * h2 + h2 + 1 {
+			return Err(ApplicationError::LogicError(String::from(
+				"量子化するパラメータの数が不正です。"
+			)));
+		}
+
+		let (b1,weights) = weights.split_at(h1);
+		let (u1,weights) = weights.split_at(F::INPUTS * h1);
+		let (b2,weights) = weights.split_at(h2);
+		let (u2,weights) = weights.split_at(h1 * h2);
+		let (b3,u3) = weights.split_at(1);
+
+		let mut w2 = vec![0; h2 * h1];
+
+		for i in 0..h1 {
+			for o in 0..h2 {
+				w2[o * h1 + i] = QuantizedNetwork::<F>::to_i16(u2[i * h2 + o] * QB as f32);
+			}
+		}
+
+		Ok(QuantizedNetwork {
+			h1:h1,
+			h2:h2,
+			b1:b1.iter().map(|&b| QuantizedNetwork::<F>::to_i16(b * QA as f32)).collect(),
+			w1:u1.iter().map(|&w| QuantizedNetwork::<F>::to_i16(w * QA as f32)).collect(),
+			b2:b2.iter().map(|&b| (b * (QA * QB) as f32).round() as i32).collect(),
+			w2:w2,
+			b3:(b3[0] * (QA * QB) as f32).round() as i32,
+			w3:u3.iter().map(|&w| QuantizedNetwork::<F>::to_i16(w * QB as f32)).collect(),
+			features:PhantomData::<F>,
+		})
+	}
+
+	fn to_i16(v:f32) -> i16 {
+		v.round().max(i16::MIN as f32).min(i16::MAX as f32) as i16
+	}
+
+	pub fn load<A: Architecture<Features=F>>(file:&str) -> Result<QuantizedNetwork<F>,ApplicationError> {
+		let mut p = ModelFilePersistence::open(file)?;
+
+		let header = match p.header() {
+			Some(header) if p.version() == Some(FORMAT_VERSION) => header.clone(),
+			_ => {
+				return Err(ApplicationError::StartupError(format!(
+					"{}は量子化されたモデルファイルではありません。",file
+				)));
+			}
+		};
+
+		if header.quantization.as_ref().map(|q| q != QUANTIZATION).unwrap_or(true) {
+			return Err(ApplicationError::StartupError(format!(
+				"{}は量子化されたモデルファイルではありません。",file
+			)));
+		}
+
+		if header.architecture != A::NAME || header.feature_set != F::NAME {
+			return Err(ApplicationError::StartupError(format!(
+				"{}はネットワーク構成{}、特徴量{}のモデルファイルです。(設定されているネットワーク構成: {}, 対応している特徴量: {})",
+				file, header.architecture, header.feature_set, A::NAME, F::NAME
+			)));
+		}
+
+		let (h1,h2) = A::UNITS;
+
+		let expected = (h1 * 2 + F::INPUTS * h1 * 2 + h2 * 4 + h2 * h1 * 2 + 4 + h2 * 2) as u64;
+
+		if p.weights_size() != expected {
+			return Err(ApplicationError::StartupError(format!(
+				"{}のパラメータのサイズが不正です。(パラメータのサイズ: {}, 想定されるサイズ: {})",
+				file, p.weights_size(), expected
+			)));
+		}
+
+		let mut buf = vec![0; expected as usize];
+
+		p.read_bytes(&mut buf)?;
+
+		let (b1,buf) = buf.split_at(h1 * 2);
+		let (w1,buf) = buf.split_at(F::INPUTS * h1 * 2);
+		let (b2,buf) = buf.split_at(h2 * 4);
+		let (w2,buf) = buf.split_at(h2 * h1 * 2);
+		let (b3,w3) = buf.split_at(4);
+
+		Ok(QuantizedNetwork {
+			h1:h1,
+			h2:h2,
+			b1:b1.chunks(2).map(|b| i16::from_be_bytes([b[0],b[1]])).collect(),
+			w1:w1.chunks(2).map(|b| i16::from_be_bytes([b[0],b[1]])).collect(),
+			b2:b2.chunks(4).map(|b| i32::from_be_bytes([b[0],b[1],b[2],b[3]])).collect(),
+			w2:w2.chunks(2).map(|b| i16::from_be_bytes([b[0],b[1]])).collect(),
+			b3:i32::from_be_bytes([b3[0],b3[1],b3[2],b3[3]]),
+			w3:w3.chunks(2).map(|b| i16::from_be_bytes([b[0],b[1]])).collect(),
+			features:PhantomData::<F>,
+		})
+	}
+
+	pub fn save(&self,file:&str,header:&ModelHeader) -> Result<(),ApplicationError> {
+		let mut buf = Vec::new();
+
+		for b in &self.b1 {
+			buf.extend_from_slice(&b.to_be_bytes());
+		}
+
+		for w in &self.w1 {
+			buf.extend_from_slice(&w.to_be_bytes());
+		}
+
+		for b in &self.b2 {
+			buf.extend_from_slice(&b.to_be_bytes());
+		}
+
+		for w in &self.w2 {
+			buf.extend_from_slice(&w.to_be_bytes());
+		}
+
+		buf.extend_from_slice(&self.b3.to_be_bytes());
+
+		for w in &self.w3 {
+			buf.extend_from_slice(&w.to_be_bytes());
+		}
+
+		ModelFilePersistence::save_bytes(file,header,&buf)
+	}
+
+	/// The weights are at most ±4.0 at the scale of QA (clipped by to_i16),
+	/// so the int32 accumulator holds the sum of tens of thousands of the features at the limit before it saturates,
+	/// far beyond the range clipped to 0..QA_MAX by the activation.
+	fn add_feature(&self,accumulator:&mut [i32],index:usize,v:i32) {
+		let w = &self.w1[index * self.h1..(index + 1) * self.h1];
+
+		for (a,&w) in accumulator.iter_mut().zip(w.iter()) {
+			*a = a.saturating_add((w as i32).saturating_mul(v));
+		}
+	}
+
+	fn propagate(&self,accumulator:&[i32]) -> f32 {
+		let hidden = accumulator.iter().map(|&a| a.max(0).min(QA_MAX)).collect::<Vec<i32>>();
+
+		let mut output = self.b3 as i64;
+
+		for o in 0..self.h2 {
+			let w = &self.w2[o * self.h1..(o + 1) * self.h1];
+
+			let s = hidden.iter().zip(w.iter()).fold(self.b2[o] as i64, |acc,(&h,&w)| acc + h as i64 * w as i64);
+
+			output += ((s + (1 << (QB_SHIFT - 1))) >> QB_SHIFT).max(0).min(QA_MAX as i64) * self.w3[o] as i64;
+		}
+
+		(output as f32 / (QA * QB) as f32).tanh()
+	}
+}
+impl<F,const N:usize> Network for QuantizedNetwork<F>
+	where F: FeatureSet<Input=Arr<f32,N>,DiffInput=DiffArr<f32,N>> {
+	type Features = F;
+	type Accumulator = Vec<i32>;
+
+	fn forward_input(&self,input:Arr<f32,N>) -> Result<f32,EvaluateError> {
+		Ok(self.propagate(&self.accumulate(input)?))
+	}
+
+	fn accumulate(&self,input:Arr<f32,N>) -> Result<Self::Accumulator,EvaluateError> {
+		let mut accumulator = self.b1.iter().map(|&b| b as i32).collect::<Vec<i32>>();
+
+		for (i,&v) in input.iter().enumerate() {
+			if v != 0. {
+				self.add_feature(&mut accumulator,i,v as i32);
+			}
+		}
+
+		Ok(accumulator)
+	}
+
+	fn accumulate_diff(&self,accumulator:&Self::Accumulator,input:DiffArr<f32,N>,dst:&mut Self::Accumulator)
+		-> Result<f32,EvaluateError> {
+		dst.clone_from(accumulator);
+
+		for &(i,v) in input.iter() {
+			self.add_feature(dst,i,v as i32);
+		}
+
+		Ok(self.propagate(dst))
+	}
+}
+pub struct Quantizer;
+impl Quantizer {
+	pub fn run<A: Architecture>(savedir:String,ensemble:Ensemble) -> Result<(),ApplicationError>
+		where QuantizedNetwork<A::Features>: Network<Features=A::Features> {
+		if !A::QUANTIZABLE {
+			return Err(ApplicationError::StartupError(format!(
+				"ネットワーク構成{}は量子化に対応していません。",A::NAME
+			)));
+		}
+
+		for filename in ensemble.filenames.iter() {
+			let src = format!("{}/{}",savedir,filename);
+			let dst = format!("{}/{}",savedir,quantized_filename(filename));
+
+			if !Path::new(&src).exists() {
+				return Err(ApplicationError::StartupError(format!(
+					"モデルファイル{}が見つかりません。",src
+				)));
+			}
+
+			let mut nn = A::create_network(DeviceCpu::new()?)?;
+			let header = A::load(&mut nn,&src)?;
+
+			let mut p = ModelFilePersistence::new();
+
+			nn.save(&mut p)?;
+
+			let (h1,h2) = A::UNITS;
+
+			let q = QuantizedNetwork::<A::Features>::from_weights(h1,h2,p.weights())?;
+
+			let mut header = header.unwrap_or_else(|| ModelHeader::new(A::NAME,<A::Features as FeatureSet>::NAME));
+
+			header.quantization = Some(String::from(QUANTIZATION));
+			header.updated_at = ModelHeader::now();
+
+			q.save(&dst,&header)?;
+
+			print!("{} -> {}\n",src,dst);
+		}
+
+		Quantizer::check::<A>(savedir,ensemble)
+	}
+
+	/// Compares the evaluations of the quantized model with the float model on positions of random games.
+	pub fn check<A: Architecture>(savedir:String,ensemble:Ensemble) -> Result<(),ApplicationError>
+		where QuantizedNetwork<A::Features>: Network<Features=A::Features> {
+		let float = IntelligenceCreator::create::<A>(savedir.clone(),ensemble.clone(),false)?;
+		let quantized = IntelligenceCreator::create_quantized::<A>(savedir,ensemble,false)?;
+
+		if let Some(file) = float.fallback() {
+			let _ = USIStdErrorWriter::write(&format!("{}\n",fallback_warning(file)));
+		}
+
+		let mut rnd = rand::thread_rng();
+		let mut rnd = XorShiftRng::from_seed(rnd.gen());
+
+		let perft = Perft::new(true);
+
+		let mut count = 0;
+		let mut max_deviation:i64 = 0;
+		let mut total_deviation:i64 = 0;
+		let mut max_output_deviation:f32 = 0.;
+		let mut max_diff_deviation:f32 = 0.;
+
+		while count < CHECK_POSITIONS {
+			let (mut teban,mut state,mut mc) = Perft::parse_position("startpos")?;
+
+			for _ in 0..CHECK_MAX_PLY {
+				if count >= CHECK_POSITIONS {
+					break;
+				}
+
+				let e = float.evalute(true,teban,state.get_banmen(),&mc)? as i64;
+				let q = quantized.evalute(true,teban,state.get_banmen(),&mc)? as i64;
+
+				max_deviation = max_deviation.max((e - q).abs());
+				total_deviation += (e - q).abs();
+
+				let fo = float.combine(&float.evalute_by_networks(true,teban,state.get_banmen(),&mc)?);
+				let qo = quantized.combine(&quantized.evalute_by_networks(true,teban,state.get_banmen(),&mc)?);
+
+				max_output_deviation = max_output_deviation.max((fo - qo).abs());
+				count += 1;
+
+				let mvs = perft.moves(teban,&state,&mc);
+
+				if mvs.len() == 0 {
+					break;
+				}
+
+				let m = mvs[rnd.gen_range(0..mvs.len())].to_move();
+
+				let (next,nmc,_) = Rule::apply_move_none_check(&state,teban,&mc,m.to_applied_move());
+
+				let input = <A::Features as FeatureSet>::make_input(false,teban.opposite(),state.get_banmen(),&mc);
+				let d = <A::Features as FeatureSet>::make_diff_input(true,teban.opposite(),state.get_banmen(),&mc,&m,teban)?;
+				let applied = <A::Features as FeatureSet>::make_input(true,teban.opposite(),next.get_banmen(),&nmc);
+
+				for nn in quantized.networks().iter() {
+					let accumulator = nn.accumulate(input.clone())?;
+					let mut dst = accumulator.clone();
+					let s = nn.accumulate_diff(&accumulator,d.clone(),&mut dst)?;
+
+					max_diff_deviation = max_diff_deviation.max((s - nn.forward_input(applied.clone())?).abs());
+				}
+
+				state = next;
+				mc = nmc;
+				teban = teban.opposite();
+			}
+		}
+
+		print!("局面数: {}\n",count);
+		print!("最大誤差: {}cp (出力 {})\n",max_deviation,max_output_deviation);
+		print!("平均誤差: {}cp\n",total_deviation as f64 / count as f64);
+		print!("差分計算の最大誤差: {}\n",max_diff_deviation);
+
+		Ok(())
+	}
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use features::InputCreator;
+	use nn::StandardArchitecture;
+
+	/// Maximum difference of the outputs (before converted to centipawns) allowed between the float and the quantized networks.
+	const TOLERANCE:f32 = 0.005;
+
+	#[test]
+	fn from_weights_reads_the_layout_of_the_linear_persistence() {
+		let (h1,h2) = (2,3);
+		let inputs = InputCreator::INPUTS;
+
+		let mut weights = vec![0.; (inputs + 1) * h1 + (h1 + 1) * h2 + h2 + 1];
+
+		// b1[1], w1 of the input 3 to the unit 1, b2[2], w2 of the unit 1 to the unit 2, b3 and w3[2]
+		weights[1] = 0.5;
+		weights[h1 + 3 * h1 + 1] = 0.25;
+		weights[h1 + inputs * h1 + 2] = 0.125;
+		weights[h1 + inputs * h1 + h2 + h2 + 2] = -0.5;
+		weights[h1 + inputs * h1 + h2 + h1 * h2] = 0.25;
+		weights[h1 + inputs * h1 + h2 + h1 * h2 + 1 + 2] = 1.;
+
+		let q = QuantizedNetwork::<InputCreator>::from_weights(h1,h2,&weights).unwrap();
+
+		assert_eq!(q.b1,vec![0,QA as i16 / 2]);
+		assert_eq!(q.w1.iter().enumerate().filter(|&(_,&w)| w != 0).collect::<Vec<_>>(),vec![(3 * h1 + 1,&(QA as i16 / 4))]);
+		assert_eq!(q.b2,vec![0,0,QA * QB / 8]);
+		// h2 x h1
+		assert_eq!(q.w2,vec![0,0,0,0,0,-QB as i16 / 2]);
+		assert_eq!(q.b3,QA * QB / 4);
+		assert_eq!(q.w3,vec![0,0,QB as i16]);
+
+		assert!(QuantizedNetwork::<InputCreator>::from_weights(h1,h2,&weights[1..]).is_err());
+	}
+
+	#[test]
+	fn quantized_network_agrees_with_the_float_network() {
+		let mut nn = StandardArchitecture::create_network(DeviceCpu::new().unwrap()).unwrap();
+
+		let mut p = ModelFilePersistence::new();
+
+		nn.save(&mut p).unwrap();
+
+		let (h1,h2) = StandardArchitecture::UNITS;
+
+		let q = QuantizedNetwork::<InputCreator>::from_weights(h1,h2,p.weights()).unwrap();
+
+		let mut rnd = XorShiftRng::from_seed([7; 16]);
+
+		let perft = Perft::new(true);
+
+		for _ in 0..4 {
+			let (mut teban,mut state,mut mc) = Perft::parse_position("startpos").unwrap();
+
+			for _ in 0..100 {
+				let input = InputCreator::make_input(true,teban,state.get_banmen(),&mc);
+
+				let f = nn.forward_input(input.clone()).unwrap();
+				let e = q.forward_input(input).unwrap();
+
+				assert!((f - e).abs() < TOLERANCE,"float {} quantized {}",f,e);
+
+				let mvs = perft.moves(teban,&state,&mc);
+
+				if mvs.len() == 0 {
+					break;
+				}
+
+				let m = mvs[rnd.gen_range(0..mvs.len())].to_move();
+
+				let (next,nmc,_) = Rule::apply_move_none_check(&state,teban,&mc,m.to_applied_move());
+
+				state = next;
+				mc = nmc;
+				teban = teban.opposite();
+			}
+		}
+	}
+}
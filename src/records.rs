@@ -0,0 +1,537 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+use rand::{Rng, SeedableRng};
+use rand_xorshift::XorShiftRng;
+
+use usiagent::event::GameEndState;
+use usiagent::shogi::*;
+
+use error::ApplicationError;
+
+/// Size of a record of the packed sfen format of YaneuraOu.
+pub const PACKED_SFEN_SIZE:usize = 40;
+/// Size of a record of the hcpe format.
+pub const HCPE_SIZE:usize = 38;
+const BLOCK_RECORDS:usize = 65536;
+/// Kinds of the pieces in hand in the order of the numbers of the pieces in the records.
+const HAND_KINDS:[MochigomaKind; 7] = [
+	MochigomaKind::Fu,
+	MochigomaKind::Kyou,
+	MochigomaKind::Kei,
+	MochigomaKind::Gin,
+	MochigomaKind::Kaku,
+	MochigomaKind::Hisha,
+	MochigomaKind::Kin,
+];
+
+/// Reads the fixed-size records of a file in blocks of BLOCK_RECORDS records.
+pub struct RecordReader {
+	file:File,
+	item_size:usize,
+	buf:Vec<u8>,
+	start:usize,
+	end:usize,
+}
+impl RecordReader {
+	pub fn new<P: AsRef<Path>>(path:P,item_size:usize) -> Result<RecordReader,ApplicationError> {
+		Ok(RecordReader {
+			file:File::open(path)?,
+			item_size:item_size,
+			buf:vec![0; item_size * BLOCK_RECORDS],
+			start:0,
+			end:0,
+		})
+	}
+
+	/// The next record, or None at the end of the file. (an error if the file ends in the middle of a record)
+	pub fn next(&mut self) -> Result<Option<&[u8]>,ApplicationError> {
+		if self.end - self.start < self.item_size {
+			self.buf.copy_within(self.start..self.end,0);
+			self.end -= self.start;
+			self.start = 0;
+
+			while self.end < self.buf.len() {
+				let n = self.file.read(&mut self.buf[self.end..])?;
+
+				if n == 0 {
+					break;
+				}
+
+				self.end += n;
+			}
+
+			if self.end == 0 {
+				return Ok(None);
+			} else if self.end < self.item_size {
+				return Err(ApplicationError::LearningError(String::from(
+					"The data size of the teacher phase is invalid."
+				)));
+			}
+		}
+
+		let record = &self.buf[self.start..self.start + self.item_size];
+
+		self.start += self.item_size;
+
+		Ok(Some(record))
+	}
+}
+/// Shuffles a stream of records with a buffer of a fixed number of records kept in one allocation.
+///
+/// Once the buffer is full, each record pushed replaces a record chosen at random, which is appended to the batch,
+/// so the records are mixed across the files as far as the buffer reaches.
+/// The batches are the records laid out back to back in one buffer.
+pub struct ShuffleBuffer {
+	item_size:usize,
+	capacity:usize,
+	data:Vec<u8>,
+	rng:XorShiftRng,
+}
+impl ShuffleBuffer {
+	pub fn new(item_size:usize,capacity:usize) -> ShuffleBuffer {
+		let mut rng = rand::thread_rng();
+
+		ShuffleBuffer {
+			item_size:item_size,
+			capacity:capacity,
+			data:Vec::with_capacity(item_size * capacity),
+			rng:XorShiftRng::from_seed(rng.gen()),
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.data.len() / self.item_size
+	}
+
+	/// Adds the record, and appends the record taken out in exchange to the batch once the buffer is full.
+	pub fn push(&mut self,record:&[u8],batch:&mut Vec<u8>) {
+		if self.capacity == 0 {
+			batch.extend_from_slice(record);
+		} else if self.len() < self.capacity {
+			self.data.extend_from_slice(record);
+		} else {
+			let i = self.rng.gen_range(0..self.capacity) * self.item_size;
+
+			batch.extend_from_slice(&self.data[i..i + self.item_size]);
+
+			self.data[i..i + self.item_size].copy_from_slice(record);
+		}
+	}
+
+	/// Takes out a record chosen at random into the batch, or returns false when the buffer is empty.
+	pub fn pop(&mut self,batch:&mut Vec<u8>) -> bool {
+		let len = self.len();
+
+		if len == 0 {
+			return false;
+		}
+
+		let i = self.rng.gen_range(0..len) * self.item_size;
+		let last = (len - 1) * self.item_size;
+
+		batch.extend_from_slice(&self.data[i..i + self.item_size]);
+
+		self.data.copy_within(last..last + self.item_size,i);
+		self.data.truncate(last);
+
+		true
+	}
+}
+/// Format of the records of the teacher phases.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum RecordFormat {
+	/// Packed sfen of YaneuraOu.
+	PackedSfen,
+	Hcpe,
+}
+impl RecordFormat {
+	pub fn item_size(&self) -> usize {
+		match self {
+			&RecordFormat::PackedSfen => PACKED_SFEN_SIZE,
+			&RecordFormat::Hcpe => HCPE_SIZE,
+		}
+	}
+
+	/// Extension of the files of the records.
+	pub fn ext(&self) -> &'static str {
+		match self {
+			&RecordFormat::PackedSfen => "bin",
+			&RecordFormat::Hcpe => "hcpe",
+		}
+	}
+}
+/// Position of a game with the fields of a record of the teacher phases.
+pub struct TeacherPosition {
+	pub teban:Teban,
+	pub banmen:Banmen,
+	pub mc:MochigomaCollections,
+	/// Evaluation in centipawns from the side to move.
+	pub eval:i16,
+	pub best_move:Option<Move>,
+	/// Number of the moves made before the position.
+	pub ply:u16,
+	/// Game result from the side to move.
+	pub result:GameEndState,
+}
+/// Writes the bits from the least significant bit of each byte, the order read by packedsfen.
+struct BitStreamWriter {
+	buf:[u8; 32],
+	cursor:usize,
+}
+impl BitStreamWriter {
+	fn new() -> BitStreamWriter {
+		BitStreamWriter {
+			buf:[0; 32],
+			cursor:0,
+		}
+	}
+
+	fn put(&mut self,value:u32,bits:usize) -> Result<(),ApplicationError> {
+		if self.cursor + bits > 256 {
+			return Err(ApplicationError::LearningError(String::from(
+				"The position can not be packed into 256 bits."
+			)));
+		}
+
+		for i in 0..bits {
+			if value & (1 << i) != 0 {
+				self.buf[self.cursor / 8] |= 1 << (self.cursor % 8);
+			}
+
+			self.cursor += 1;
+		}
+
+		Ok(())
+	}
+}
+impl RecordFormat {
+	/// Encodes the position into a record.
+	///
+	/// The squares are numbered file by file from 9一 to 1九, the order the readers of packedsfen read them,
+	/// so that the records are read back as they were written.
+	pub fn encode(&self,position:&TeacherPosition) -> Result<Vec<u8>,ApplicationError> {
+		let mut bs = BitStreamWriter::new();
+
+		bs.put(if position.teban == Teban::Sente { 0 } else { 1 },1)?;
+
+		let &Banmen(ref kinds) = &position.banmen;
+
+		for &ou in &[KomaKind::SOu,KomaKind::GOu] {
+			let sq = (0..81).find(|&sq| kinds[sq % 9][sq / 9] == ou).ok_or(
+				ApplicationError::LearningError(String::from("The position without the king can not be encoded."))
+			)?;
+
+			bs.put(sq as u32,7)?;
+		}
+
+		for sq in 0..81 {
+			let kind = kinds[sq % 9][sq / 9];
+
+			match kind {
+				KomaKind::SOu | KomaKind::GOu => (),
+				KomaKind::Blank => bs.put(0,1)?,
+				kind => {
+					let teban = if kind < KomaKind::GFu { Teban::Sente } else { Teban::Gote };
+					let base = MochigomaKind::try_from(kind).map_err(|e| ApplicationError::LearningError(format!("{}",e)))?;
+
+					match self {
+						&RecordFormat::PackedSfen => {
+							let (value,bits) = Self::packed_sfen_code(base);
+
+							bs.put(value,bits)?;
+
+							if base != MochigomaKind::Kin {
+								bs.put(if kind.is_nari() { 1 } else { 0 },1)?;
+							}
+
+							bs.put(if teban == Teban::Sente { 0 } else { 1 },1)?;
+						},
+						&RecordFormat::Hcpe => {
+							let (value,bits) = Self::hcpe_code(base);
+							let value = match (teban,base) {
+								(Teban::Sente,_) => value,
+								(Teban::Gote,MochigomaKind::Kin) => value | 1 << (bits - 1),
+								(Teban::Gote,_) => value | 1 << (bits - 2),
+							};
+							let value = if kind.is_nari() { value | 1 << (bits - 1) } else { value };
+
+							bs.put(value,bits)?;
+						}
+					}
+				}
+			}
+		}
+
+		let (ms,mg) = match position.mc {
+			MochigomaCollections::Pair(ref ms,ref mg) => (ms.clone(),mg.clone()),
+			MochigomaCollections::Empty => (Mochigoma::new(),Mochigoma::new()),
+		};
+
+		for &(teban,ref mochigoma) in &[(Teban::Sente,&ms),(Teban::Gote,&mg)] {
+			for &kind in HAND_KINDS.iter() {
+				for _ in 0..mochigoma.get(kind) {
+					match self {
+						&RecordFormat::PackedSfen => {
+							let (value,bits) = Self::packed_sfen_code(kind);
+
+							bs.put(value >> 1,bits - 1)?;
+
+							if kind != MochigomaKind::Kin {
+								bs.put(0,1)?;
+							}
+
+							bs.put(if teban == Teban::Sente { 0 } else { 1 },1)?;
+						},
+						&RecordFormat::Hcpe => {
+							let (value,bits) = Self::hcpe_hand_code(kind);
+							let value = if teban == Teban::Sente { value } else { value | 1 << (bits - 1) };
+
+							bs.put(value,bits)?;
+						}
+					}
+				}
+			}
+		}
+
+		if bs.cursor != 256 {
+			return Err(ApplicationError::LearningError(String::from(
+				"The position without all the pieces can not be encoded."
+			)));
+		}
+
+		let mut record = bs.buf.to_vec();
+
+		record.extend_from_slice(&position.eval.to_le_bytes());
+		record.extend_from_slice(&self.encode_move(position.best_move.as_ref())?.to_le_bytes());
+
+		match self {
+			&RecordFormat::PackedSfen => {
+				record.extend_from_slice(&position.ply.to_le_bytes());
+				record.push(match position.result {
+					GameEndState::Win => 1i8,
+					GameEndState::Lose => -1i8,
+					_ => 0i8,
+				} as u8);
+			},
+			&RecordFormat::Hcpe => {
+				record.push(match (&position.result,position.teban) {
+					(&GameEndState::Win,Teban::Sente) | (&GameEndState::Lose,Teban::Gote) => 1,
+					(&GameEndState::Win,Teban::Gote) | (&GameEndState::Lose,Teban::Sente) => 2,
+					_ => 0,
+				});
+			}
+		}
+
+		record.push(0);
+
+		Ok(record)
+	}
+
+	/// Huffman code of the piece on the board of an unpromoted piece of the sente, without the flags for the packed sfen.
+	fn packed_sfen_code(kind:MochigomaKind) -> (u32,usize) {
+		match kind {
+			MochigomaKind::Fu => (0b1,2),
+			MochigomaKind::Kyou => (0b11,4),
+			MochigomaKind::Kei => (0b1011,4),
+			MochigomaKind::Gin => (0b111,4),
+			MochigomaKind::Kaku => (0b11111,6),
+			MochigomaKind::Hisha => (0b111111,6),
+			MochigomaKind::Kin => (0b1111,5),
+		}
+	}
+
+	/// Huffman code of the piece on the board of an unpromoted piece of the sente for hcpe.
+	/// (the highest bit is the promotion flag and the next is the gote flag, or the highest is the gote flag for the kin)
+	fn hcpe_code(kind:MochigomaKind) -> (u32,usize) {
+		match kind {
+			MochigomaKind::Fu => (0b1,4),
+			MochigomaKind::Kyou => (0b11,6),
+			MochigomaKind::Kei => (0b111,6),
+			MochigomaKind::Gin => (0b1011,6),
+			MochigomaKind::Kaku => (0b11111,8),
+			MochigomaKind::Hisha => (0b111111,8),
+			MochigomaKind::Kin => (0b1111,6),
+		}
+	}
+
+	/// Huffman code of the piece in hand of the sente for hcpe. (the highest bit is the gote flag)
+	fn hcpe_hand_code(kind:MochigomaKind) -> (u32,usize) {
+		match kind {
+			MochigomaKind::Fu => (0b0,3),
+			MochigomaKind::Kyou => (0b1,5),
+			MochigomaKind::Kei => (0b11,5),
+			MochigomaKind::Gin => (0b101,5),
+			MochigomaKind::Kin => (0b111,5),
+			MochigomaKind::Kaku => (0b11111,7),
+			MochigomaKind::Hisha => (0b111111,7),
+		}
+	}
+
+	fn encode_move(&self,m:Option<&Move>) -> Result<u16,ApplicationError> {
+		let square = |x:u32,y:u32| ((9 - x) * 9 + (y - 1)) as u16;
+
+		let hand_index = |kind:MochigomaKind| HAND_KINDS.iter().position(|&k| k == kind).unwrap_or(0) as u16;
+
+		Ok(match (self,m) {
+			(_,None) => 0,
+			(&RecordFormat::PackedSfen,Some(&Move::To(KomaSrcPosition(sx,sy),KomaDstToPosition(dx,dy,n)))) => {
+				square(sx,sy) << 7 | square(dx,dy) | if n { 1 << 15 } else { 0 }
+			},
+			(&RecordFormat::PackedSfen,Some(&Move::Put(kind,KomaDstPutPosition(dx,dy)))) => {
+				1 << 14 | (hand_index(kind) + 1) << 7 | square(dx,dy)
+			},
+			(&RecordFormat::Hcpe,Some(&Move::To(KomaSrcPosition(sx,sy),KomaDstToPosition(dx,dy,n)))) => {
+				square(sx,sy) << 7 | square(dx,dy) | if n { 1 << 14 } else { 0 }
+			},
+			(&RecordFormat::Hcpe,Some(&Move::Put(kind,KomaDstPutPosition(dx,dy)))) => {
+				(81 + hand_index(kind)) << 7 | square(dx,dy)
+			}
+		})
+	}
+}
+/// Appends the records of the positions to a file.
+pub struct RecordWriter {
+	writer:BufWriter<File>,
+	format:RecordFormat,
+	count:usize,
+}
+impl RecordWriter {
+	pub fn new<P: AsRef<Path>>(path:P,format:RecordFormat) -> Result<RecordWriter,ApplicationError> {
+		Ok(RecordWriter {
+			writer:BufWriter::new(File::options().append(true).create(true).open(path)?),
+			format:format,
+			count:0,
+		})
+	}
+
+	pub fn write(&mut self,position:&TeacherPosition) -> Result<(),ApplicationError> {
+		self.writer.write_all(&self.format.encode(position)?)?;
+		self.count += 1;
+
+		Ok(())
+	}
+
+	/// Number of the records written by this writer.
+	pub fn count(&self) -> usize {
+		self.count
+	}
+
+	pub fn flush(&mut self) -> Result<(),ApplicationError> {
+		self.writer.flush()?;
+
+		Ok(())
+	}
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use perft::Perft;
+	use pipeline::SampleDecoder;
+	use features::InputCreator;
+
+	/// Gote to move, with a promoted bishop on the board and a bishop and a lance in the hand of sente.
+	const POSITION:&'static str = "startpos moves 7g7f 3c3d 8h2b+ 4a4b 2b1a";
+
+	fn position(result:GameEndState) -> TeacherPosition {
+		let (teban,state,mc) = Perft::parse_position(POSITION).unwrap();
+
+		TeacherPosition {
+			teban:teban,
+			banmen:state.get_banmen().clone(),
+			mc:mc,
+			eval:-1234,
+			best_move:None,
+			ply:5,
+			result:result,
+		}
+	}
+
+	fn hands(mc:&MochigomaCollections) -> Vec<(usize,usize)> {
+		let (ms,mg) = match mc {
+			&MochigomaCollections::Pair(ref ms,ref mg) => (ms.clone(),mg.clone()),
+			&MochigomaCollections::Empty => (Mochigoma::new(),Mochigoma::new()),
+		};
+
+		HAND_KINDS.iter().map(|&k| (ms.get(k) as usize,mg.get(k) as usize)).collect()
+	}
+
+	fn assert_round_trip(format:RecordFormat,result:GameEndState) {
+		let p = position(result);
+		let record = format.encode(&p).unwrap();
+
+		assert_eq!(record.len(),format.item_size());
+
+		let (teban,banmen,mc,eval,es) = SampleDecoder::<InputCreator>::new().read(format,&record).unwrap();
+
+		assert_eq!(teban,p.teban);
+		assert!(banmen == p.banmen);
+		assert_eq!(hands(&mc),hands(&p.mc));
+		assert_eq!(eval,p.eval);
+		assert_eq!(es,p.result);
+	}
+
+	#[test]
+	fn packed_sfen_records_are_read_back() {
+		for result in vec![GameEndState::Win,GameEndState::Lose,GameEndState::Draw] {
+			assert_round_trip(RecordFormat::PackedSfen,result);
+		}
+
+		let record = RecordFormat::PackedSfen.encode(&position(GameEndState::Win)).unwrap();
+
+		assert_eq!(u16::from_le_bytes([record[36],record[37]]),5);
+	}
+
+	#[test]
+	fn hcpe_records_are_read_back() {
+		for result in vec![GameEndState::Win,GameEndState::Lose,GameEndState::Draw] {
+			assert_round_trip(RecordFormat::Hcpe,result);
+		}
+	}
+
+	fn records(count:u8,item_size:usize) -> Vec<Vec<u8>> {
+		(0..count).map(|i| vec![i; item_size]).collect()
+	}
+
+	#[test]
+	fn shuffle_buffer_without_capacity_passes_the_records_through() {
+		let mut buffer = ShuffleBuffer::new(4,0);
+		let mut batch = Vec::new();
+
+		for r in records(3,4).iter() {
+			buffer.push(r,&mut batch);
+		}
+
+		assert_eq!(batch,vec![0,0,0,0,1,1,1,1,2,2,2,2]);
+		assert!(!buffer.pop(&mut batch));
+	}
+
+	#[test]
+	fn shuffle_buffer_takes_out_every_record_exactly_once() {
+		let mut buffer = ShuffleBuffer::new(3,8);
+		let mut batch = Vec::new();
+
+		for (i,r) in records(20,3).iter().enumerate() {
+			buffer.push(r,&mut batch);
+
+			assert_eq!(buffer.len(),(i + 1).min(8));
+			assert_eq!(batch.len(),(i + 1).saturating_sub(8) * 3);
+		}
+
+		while buffer.pop(&mut batch) {}
+
+		assert_eq!(buffer.len(),0);
+
+		let mut taken = batch.chunks(3).map(|r| {
+			assert!(r.iter().all(|&b| b == r[0]));
+			r[0]
+		}).collect::<Vec<u8>>();
+
+		taken.sort();
+
+		assert_eq!(taken,(0..20).collect::<Vec<u8>>());
+	}
+}
@@ -0,0 +1,504 @@
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use rand::{Rng, SeedableRng};
+use rand_xorshift::XorShiftRng;
+
+use usiagent::selfmatch::*;
+use usiagent::event::*;
+use usiagent::shogi::*;
+use usiagent::rule::*;
+use usiagent::protocol::*;
+use usiagent::error::*;
+use usiagent::player::{ConsoleInfoSender, ConsolePeriodicallyInfo};
+
+use error::ApplicationError;
+use nn::{Ensemble, Intelligence, Network};
+use player::NNShogiPlayer;
+use perft::Perft;
+use records::{RecordFormat, RecordWriter, TeacherPosition};
+
+pub const REINFORCEMENT_DIR:&'static str = "rl";
+pub const SELFPLAY_GAMES:u32 = 100;
+pub const GATING_GAMES:u32 = 40;
+pub const GATING_WIN_RATE:f64 = 0.55;
+pub const OPENING_MOVES:u32 = 8;
+const EVAL_DIR:&'static str = "data";
+
+/// [reinforcement] table of settings.toml.
+#[derive(Debug, Deserialize)]
+pub struct ReinforcementSettings {
+	dir:Option<String>,
+	games:Option<u32>,
+	gating_games:Option<u32>,
+	gating_win_rate:Option<f64>,
+	opening_moves:Option<u32>,
+}
+/// Result of a generation written to result.toml in the directory of the generation.
+#[derive(Debug,Serialize)]
+pub struct GenerationResult {
+	games:usize,
+	positions:usize,
+	wins:usize,
+	losses:usize,
+	draws:usize,
+	win_rate:f64,
+	promoted:bool,
+}
+/// Game of the self-match.
+struct GameRecord {
+	/// Initial position given to the players, including the random opening moves.
+	sfen:String,
+	/// Moves from the position before the opening moves.
+	moves:Vec<Move>,
+	/// Side of the player 1.
+	player1:Teban,
+	/// None for a draw.
+	winner:Option<Teban>,
+}
+impl GameRecord {
+	/// Winner of the game that ended in the state, None for a draw.
+	///
+	/// A repetition is a draw, only the perpetual check and the other fouls lose.
+	fn winner(state:&SelfMatchGameEndState) -> Option<Teban> {
+		match *state {
+			SelfMatchGameEndState::Win(t) |
+			SelfMatchGameEndState::NyuGyokuWin(t) => Some(t),
+			SelfMatchGameEndState::Draw |
+			SelfMatchGameEndState::Foul(_,FoulKind::Sennichite) => None,
+			SelfMatchGameEndState::Resign(t) |
+			SelfMatchGameEndState::NyuGyokuLose(t) |
+			SelfMatchGameEndState::Foul(t,_) |
+			SelfMatchGameEndState::Timeover(t) => Some(t.opposite()),
+		}
+	}
+
+	/// Positions after the opening moves labelled with the game result, without the evaluation of the search.
+	fn positions(&self) -> Result<Vec<TeacherPosition>,ApplicationError> {
+		let (mut teban,mut state,mut mc) = Perft::parse_position(&self.sfen)?;
+
+		let opening = self.sfen.split(" ").skip_while(|&s| s != "moves").skip(1).count();
+
+		let mut positions = Vec::with_capacity(self.moves.len().saturating_sub(opening));
+
+		for (ply,m) in self.moves.iter().enumerate().skip(opening) {
+			let result = match self.winner {
+				None => GameEndState::Draw,
+				Some(winner) if winner == teban => GameEndState::Win,
+				Some(_) => GameEndState::Lose,
+			};
+
+			positions.push(TeacherPosition {
+				teban:teban,
+				banmen:state.get_banmen().clone(),
+				mc:mc.clone(),
+				eval:0,
+				best_move:Some(*m),
+				ply:ply as u16 + 1,
+				result:result,
+			});
+
+			let (next,nmc,_) = Rule::apply_move_none_check(&state,teban,&mc,m.to_applied_move());
+
+			state = next;
+			mc = nmc;
+			teban = teban.opposite();
+		}
+
+		Ok(positions)
+	}
+}
+/// Self-play reinforcement learning.
+///
+/// Each generation plays the self-play games with the current model, writes the positions labelled with the game results as hcpe,
+/// trains a candidate from the current model on them, and plays the candidate against the incumbent (the _opponent files).
+/// The candidate replaces both the current model and the incumbent when its score reaches the gating win rate.
+/// Every generation is kept in a directory under the dir of the settings. (gen0001, gen0002, ...)
+pub struct ReinforcementLoop<NN,C>
+	where NN: Network,
+		  C: Fn(String,Ensemble,bool) -> Result<Intelligence<NN>,ApplicationError> + Copy + Send + 'static {
+	dir:String,
+	games:u32,
+	gating_games:u32,
+	gating_win_rate:f64,
+	opening_moves:u32,
+	create:C,
+	ensemble:Ensemble,
+	options:Vec<(String,SysEventOption)>,
+	time_limit:UsiGoTimeLimit,
+	silent:bool,
+	quit:Arc<AtomicBool>,
+}
+impl<NN,C> ReinforcementLoop<NN,C>
+	where NN: Network,
+		  C: Fn(String,Ensemble,bool) -> Result<Intelligence<NN>,ApplicationError> + Copy + Send + 'static {
+	pub fn new(settings:Option<&ReinforcementSettings>,
+			   create:C,
+			   ensemble:Ensemble,
+			   options:Vec<(String,SysEventOption)>,
+			   time_limit:UsiGoTimeLimit,
+			   silent:bool) -> Result<ReinforcementLoop<NN,C>,ApplicationError> {
+		let gating_win_rate = settings.and_then(|s| s.gating_win_rate).unwrap_or(GATING_WIN_RATE);
+
+		if gating_win_rate < 0. || gating_win_rate > 1. {
+			return Err(ApplicationError::StartupError(String::from(
+				"gating_win_rateには0から1の値を指定してください。"
+			)));
+		}
+
+		Ok(ReinforcementLoop {
+			dir:settings.and_then(|s| s.dir.clone()).unwrap_or(String::from(REINFORCEMENT_DIR)),
+			games:settings.and_then(|s| s.games).unwrap_or(SELFPLAY_GAMES),
+			gating_games:settings.and_then(|s| s.gating_games).unwrap_or(GATING_GAMES),
+			gating_win_rate:gating_win_rate,
+			opening_moves:settings.and_then(|s| s.opening_moves).unwrap_or(OPENING_MOVES),
+			create:create,
+			ensemble:ensemble,
+			options:options,
+			time_limit:time_limit,
+			silent:silent,
+			quit:Arc::new(AtomicBool::new(false)),
+		})
+	}
+
+	/// Runs the generations following the last generation in the directory.
+	///
+	/// train is called with the directory of the candidate model and the directory of the teacher phases of the generation.
+	pub fn run<T>(&mut self,generations:u32,mut train:T) -> Result<(),ApplicationError>
+		where T: FnMut(String,String) -> Result<(),ApplicationError> {
+		fs::create_dir_all(&self.dir)?;
+
+		let files = self.ensemble.filenames.clone();
+		let opponent_files = self.ensemble.with_suffix("_opponent").filenames;
+
+		for (f,o) in files.iter().zip(opponent_files.iter()) {
+			let src = Path::new(EVAL_DIR).join(f);
+			let dst = Path::new(EVAL_DIR).join(o);
+
+			if src.exists() && !dst.exists() {
+				fs::copy(&src,&dst)?;
+			}
+		}
+
+		let first = self.last_generation()? + 1;
+
+		for generation in first..first + generations {
+			let dir = format!("{}/gen{:04}",self.dir,generation);
+			let kifudir = format!("{}/kifu",dir);
+			let candidate = format!("{}/candidate",dir);
+
+			fs::create_dir_all(Path::new(&kifudir).join("training"))?;
+			fs::create_dir_all(&candidate)?;
+
+			print!("第{}世代: 自己対局を開始します。\n",generation);
+
+			let games = self.play(self.games,(EVAL_DIR,&files),(EVAL_DIR,&files),&format!("{}/kifu.txt",dir))?;
+
+			let mut writer = RecordWriter::new(Path::new(&kifudir).join("training").join("selfplay.hcpe"),RecordFormat::Hcpe)?;
+
+			for game in games.iter() {
+				for p in game.positions()? {
+					writer.write(&p)?;
+				}
+			}
+
+			writer.flush()?;
+
+			print!("第{}世代: 自己対局{}局から{}局面を書き出しました。\n",generation,games.len(),writer.count());
+
+			if self.quit.load(Ordering::Acquire) {
+				break;
+			}
+
+			for f in files.iter() {
+				let src = Path::new(EVAL_DIR).join(f);
+
+				if src.exists() {
+					fs::copy(&src,Path::new(&candidate).join(f))?;
+				}
+			}
+
+			train(candidate.clone(),kifudir.clone())?;
+
+			print!("第{}世代: 候補のモデルと現在のモデルを対局させます。\n",generation);
+
+			let gating = self.play(self.gating_games,(&candidate,&files),(EVAL_DIR,&opponent_files),&format!("{}/gating.txt",dir))?;
+
+			let wins = gating.iter().filter(|g| g.winner == Some(g.player1)).count();
+			let draws = gating.iter().filter(|g| g.winner.is_none()).count();
+			let losses = gating.len() - wins - draws;
+
+			let win_rate = if gating.len() > 0 {
+				(wins as f64 + draws as f64 * 0.5) / gating.len() as f64
+			} else {
+				0.
+			};
+
+			let promoted = gating.len() > 0 && win_rate >= self.gating_win_rate;
+
+			print!("第{}世代: {}勝{}敗{}分 勝率{:.1}%\n",generation,wins,losses,draws,win_rate * 100.);
+
+			if promoted {
+				for (f,o) in files.iter().zip(opponent_files.iter()) {
+					let src = Path::new(&candidate).join(f);
+
+					if src.exists() {
+						fs::copy(&src,Path::new(EVAL_DIR).join(f))?;
+						fs::copy(&src,Path::new(EVAL_DIR).join(o))?;
+					}
+				}
+
+				print!("第{}世代: 候補のモデルを採用しました。\n",generation);
+			} else {
+				print!("第{}世代: 候補のモデルは採用されませんでした。\n",generation);
+			}
+
+			let result = GenerationResult {
+				games:games.len(),
+				positions:writer.count(),
+				wins:wins,
+				losses:losses,
+				draws:draws,
+				win_rate:win_rate,
+				promoted:promoted,
+			};
+
+			fs::write(format!("{}/result.toml",dir),toml::to_string(&result)?)?;
+
+			if self.quit.load(Ordering::Acquire) {
+				break;
+			}
+		}
+
+		Ok(())
+	}
+
+	fn last_generation(&self) -> Result<u32,ApplicationError> {
+		let mut last = 0;
+
+		for entry in fs::read_dir(&self.dir)? {
+			let name = entry?.file_name().to_string_lossy().to_string();
+
+			if name.starts_with("gen") {
+				if let Ok(n) = name[3..].parse::<u32>() {
+					last = last.max(n);
+				}
+			}
+		}
+
+		Ok(last)
+	}
+
+	fn player(&self) -> NNShogiPlayer<NN> {
+		let create = self.create;
+		let ensemble = self.ensemble.clone();
+
+		NNShogiPlayer::new(move |dir,files,material| create(dir.to_string(),ensemble.with_filenames(files)?,material))
+	}
+
+	fn player_options(&self,dir:&str,files:&[String]) -> Vec<(String,SysEventOption)> {
+		let mut options = self.options.clone();
+
+		options.push((String::from("EvalDir"),SysEventOption::Str(dir.to_string())));
+		options.push((String::from("EvalFiles"),SysEventOption::Str(files.join(","))));
+
+		options
+	}
+
+	/// Random legal moves from the initial position, so that the games do not repeat themselves.
+	fn opening(opening_moves:u32,rnd:&mut XorShiftRng) -> Result<String,ApplicationError> {
+		let (_,_,_,mvs) = Perft::new(true).random_opening(opening_moves,rnd);
+
+		if mvs.is_empty() {
+			Ok(String::from("startpos"))
+		} else {
+			Ok(format!("startpos moves {}",mvs.to_sfen()?))
+		}
+	}
+
+	fn play(&self,number_of_games:u32,player1:(&str,&[String]),player2:(&str,&[String]),kifu_file:&str)
+		-> Result<Vec<GameRecord>,ApplicationError> {
+		if number_of_games == 0 {
+			return Ok(Vec::new());
+		}
+
+		let kifus = Arc::new(Mutex::new(Vec::new()));
+		let starts = Arc::new(Mutex::new(Vec::new()));
+		let results = Arc::new(Mutex::new(Vec::new()));
+
+		let mut engine = SelfMatchEngine::new();
+
+		let mut flip = true;
+
+		let flip_players = move || {
+			flip = !flip;
+			!flip
+		};
+
+		let opening_moves = self.opening_moves;
+		let mut rnd = rand::thread_rng();
+		let mut rnd = XorShiftRng::from_seed(rnd.gen());
+
+		let initial_position_creator:Box<dyn FnMut() -> String + Send + 'static> = Box::new(move || {
+			ReinforcementLoop::<NN,C>::opening(opening_moves,&mut rnd).unwrap_or(String::from("startpos"))
+		});
+
+		let mut kifuwriter = FileSfenKifuWriter::new(kifu_file.to_string())?;
+
+		let kifu_writer = {
+			let kifus = kifus.clone();
+
+			move |sfen:&String,mvs:&Vec<Move>| {
+				kifus.lock().map(|mut kifus| kifus.push((sfen.clone(),mvs.clone()))).map_err(|_| {
+					KifuWriteError::InvalidState(String::from("Failed to secure exclusive lock of the game records."))
+				})?;
+
+				kifuwriter.write(sfen,mvs)
+			}
+		};
+
+		let system_event_queue = engine.system_event_queue.clone();
+		let quit = self.quit.clone();
+
+		let input_read_handler = move |input| {
+			if input == "quit" {
+				quit.store(true,Ordering::Release);
+
+				return match system_event_queue.lock()  {
+					Ok(mut system_event_queue) => {
+						system_event_queue.push(SystemEvent::Quit);
+						Ok(false)
+					},
+					Err(_) => {
+						Err(SelfMatchRunningError::InvalidState(String::from(
+							"Failed to secure exclusive lock of system_event_queue."
+						)))
+					}
+				};
+			}
+			Ok(true)
+		};
+
+		let silent = self.silent;
+
+		let r = {
+			let starts = starts.clone();
+			let results = results.clone();
+
+			engine.start_default(move |self_match_event_dispatcher| {
+									 let starts = starts.clone();
+
+									 self_match_event_dispatcher
+										 .add_handler(SelfMatchEventKind::GameStart, move |_,e| {
+											 match e {
+												 &SelfMatchEvent::GameStart(n,t,_) => {
+													 let _ = starts.lock().map(|mut starts| {
+														 starts.push(if n == 1 { t } else { t.opposite() })
+													 });
+													 Ok(())
+												 },
+												 e => Err(EventHandlerError::InvalidState(e.event_kind())),
+											 }
+										 });
+
+									 let results = results.clone();
+
+									 self_match_event_dispatcher
+										 .add_handler(SelfMatchEventKind::GameEnd, move |_,e| {
+											 let winner = match *e {
+												 SelfMatchEvent::GameEnd(ref s) => GameRecord::winner(s),
+												 ref e => {
+													 return Err(EventHandlerError::InvalidState(e.event_kind()));
+												 }
+											 };
+
+											 if let Ok(mut results) = results.lock() {
+												 results.push(winner);
+
+												 if !silent {
+													 print!("{}局終了しました。\n",results.len());
+												 }
+											 }
+
+											 Ok(())
+										 });
+								 },
+								 flip_players,
+								 Some(initial_position_creator),
+								 Some(Box::new(kifu_writer)),
+								 input_read_handler,
+								 self.player(),
+								 self.player(),
+								 self.player_options(player1.0,player1.1),
+								 self.player_options(player2.0,player2.1),
+								 ConsoleInfoSender::new(silent),
+								 ConsolePeriodicallyInfo::new(silent),
+								 self.time_limit,
+								 None,
+								 Some(number_of_games),
+								 |on_error_handler,e| {
+									 match on_error_handler {
+										 Some(ref h) => {
+											 let _ = h.lock().map(|h| h.call(e));
+										 },
+										 None => (),
+									 }
+								 })
+		};
+
+		r.map_err(|_| ApplicationError::SelfMatchRunningError(
+			SelfMatchRunningError::InvalidState(String::from(
+				"自己対局の実行中にエラーが発生しました。詳細はログを参照してください..."
+			))))?;
+
+		let kifus = kifus.lock().map_err(|_| ApplicationError::LearningError(String::from(
+			"Failed to secure exclusive lock of the game records."
+		)))?;
+		let starts = starts.lock().map_err(|_| ApplicationError::LearningError(String::from(
+			"Failed to secure exclusive lock of the game records."
+		)))?;
+		let results = results.lock().map_err(|_| ApplicationError::LearningError(String::from(
+			"Failed to secure exclusive lock of the game records."
+		)))?;
+
+		Ok(kifus.iter().zip(starts.iter()).zip(results.iter()).map(|(((sfen,moves),&player1),&winner)| {
+			GameRecord {
+				sfen:sfen.clone(),
+				moves:moves.clone(),
+				player1:player1,
+				winner:winner,
+			}
+		}).collect())
+	}
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn repetition_is_a_draw() {
+		assert_eq!(GameRecord::winner(&SelfMatchGameEndState::Foul(Teban::Sente,FoulKind::Sennichite)),None);
+		assert_eq!(GameRecord::winner(&SelfMatchGameEndState::Foul(Teban::Gote,FoulKind::Sennichite)),None);
+		assert_eq!(GameRecord::winner(&SelfMatchGameEndState::Draw),None);
+	}
+
+	#[test]
+	fn perpetual_check_and_the_other_fouls_lose() {
+		for kind in vec![FoulKind::SennichiteOu,FoulKind::InvalidMove,FoulKind::PutFuAndMate,
+						 FoulKind::NotRespondedOute,FoulKind::Suicide] {
+			assert_eq!(GameRecord::winner(&SelfMatchGameEndState::Foul(Teban::Sente,kind)),Some(Teban::Gote));
+			assert_eq!(GameRecord::winner(&SelfMatchGameEndState::Foul(Teban::Gote,kind)),Some(Teban::Sente));
+		}
+	}
+
+	#[test]
+	fn winner_of_the_other_end_states() {
+		assert_eq!(GameRecord::winner(&SelfMatchGameEndState::Win(Teban::Gote)),Some(Teban::Gote));
+		assert_eq!(GameRecord::winner(&SelfMatchGameEndState::NyuGyokuWin(Teban::Sente)),Some(Teban::Sente));
+		assert_eq!(GameRecord::winner(&SelfMatchGameEndState::Resign(Teban::Sente)),Some(Teban::Gote));
+		assert_eq!(GameRecord::winner(&SelfMatchGameEndState::NyuGyokuLose(Teban::Gote)),Some(Teban::Sente));
+		assert_eq!(GameRecord::winner(&SelfMatchGameEndState::Timeover(Teban::Sente)),Some(Teban::Gote));
+	}
+}
@@ -5,8 +5,6 @@ use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::atomic;
 use std::sync::atomic::{AtomicBool, AtomicU64};
-use nncombinator::arr::{Arr, DiffArr};
-use nncombinator::layer::{AskDiffInput, DiffInput, ForwardAll, ForwardDiff, PreTrain};
 
 use usiagent::shogi::*;
 use usiagent::rule::*;
@@ -15,6 +13,7 @@ use usiagent::logger::*;
 use usiagent::event::*;
 use usiagent::error::PlayerError;
 use player::Search;
+use nn::Network;
 
 #[derive(Debug)]
 pub enum MaybeMate {
@@ -28,15 +27,13 @@ pub enum MaybeMate {
 }
 pub struct Solver<E,NN>
 	where E: PlayerError,
-		  NN: ForwardAll<Input=DiffInput<DiffArr<f32,2517>,f32,2517,256>,Output=Arr<f32,1>> +
-		  PreTrain<f32> + ForwardDiff<f32> + AskDiffInput<f32,DiffInput=Arr<f32,256>> + Send + Sync + 'static {
+		  NN: Network {
 	error_type:PhantomData<E>,
 	nn_type:PhantomData<NN>,
 }
 impl<E,NN> Solver<E,NN>
 	where E: PlayerError,
-		  NN: ForwardAll<Input=DiffInput<DiffArr<f32,2517>,f32,2517,256>,Output=Arr<f32,1>> +
-		  	  PreTrain<f32> + ForwardDiff<f32> + AskDiffInput<f32,DiffInput=Arr<f32,256>> + Send + Sync + 'static {
+		  NN: Network {
 	pub fn new() -> Solver<E,NN> {
 		Solver {
 			error_type:PhantomData::<E>,
@@ -171,8 +168,7 @@ mod checkmate {
 	pub struct CheckmateStrategy<E,O,R,NN> where E: PlayerError,
 					O: Comparator<(LegalMove,usize)>,
 					R: Comparator<(LegalMove,usize)>,
-					NN: ForwardAll<Input=DiffInput<DiffArr<f32,2517>,f32,2517,256>,Output=Arr<f32,1>> +
-						PreTrain<f32> + ForwardDiff<f32> + AskDiffInput<f32,DiffInput=Arr<f32,256>> {
+					NN: Network {
 		error_type:PhantomData<E>,
 		oute_comparator:O,
 		response_oute_comparator:R,
@@ -189,8 +185,7 @@ mod checkmate {
 		where E: PlayerError,
 			  O: Comparator<(LegalMove,usize)>,
 			  R: Comparator<(LegalMove,usize)>,
-			  NN: ForwardAll<Input=DiffInput<DiffArr<f32,2517>,f32,2517,256>,Output=Arr<f32,1>> +
-			  	  PreTrain<f32> + ForwardDiff<f32> + AskDiffInput<f32,DiffInput=Arr<f32,256>> + Send + Sync + 'static {
+			  NN: Network {
 		fn new(current_frame:CheckmateStackFrame,
 				oute_comparator:O,
 				response_oute_comparator:R,
@@ -0,0 +1,262 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use usiagent::shogi::*;
+use usiagent::rule::AppliedMove;
+use usiagent::protocol::*;
+
+use error::ApplicationError;
+use solver::MaybeMate;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TraceReason {
+	ObtainedOu,
+	OuCapturable,
+	Timeout,
+	HashHit,
+	SolverMate,
+	Evalute,
+	NoMoves,
+	Error,
+	Exact,
+	BetaCutoff,
+	FailLow,
+}
+impl fmt::Display for TraceReason {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			TraceReason::ObtainedOu => write!(f, "obtained_ou"),
+			TraceReason::OuCapturable => write!(f, "ou_capturable"),
+			TraceReason::Timeout => write!(f, "timeout"),
+			TraceReason::HashHit => write!(f, "hash_hit"),
+			TraceReason::SolverMate => write!(f, "solver_mate"),
+			TraceReason::Evalute => write!(f, "evalute"),
+			TraceReason::NoMoves => write!(f, "no_moves"),
+			TraceReason::Error => write!(f, "error"),
+			TraceReason::Exact => write!(f, "exact"),
+			TraceReason::BetaCutoff => write!(f, "beta_cutoff"),
+			TraceReason::FailLow => write!(f, "fail_low"),
+		}
+	}
+}
+pub fn mate_result(m:&MaybeMate) -> &'static str {
+	match *m {
+		MaybeMate::Nomate => "nomate",
+		MaybeMate::MateMoves(_,_) => "mate",
+		MaybeMate::MaxDepth => "maxdepth",
+		MaybeMate::MaxNodes => "maxnodes",
+		MaybeMate::Timeout => "timeout",
+		MaybeMate::Continuation => "continuation",
+		MaybeMate::Unknown => "unknown",
+	}
+}
+/// Writes the nodes explored by the search to a file, one line per node.
+///
+/// Each line is tab separated:
+/// current_depth, remaining depth, alpha, beta, evaluation, reason, mate-solver result, moves from the root.
+#[derive(Clone)]
+pub struct SearchTracer {
+	writer:Arc<Mutex<BufWriter<std::fs::File>>>,
+	max_depth:Option<u32>,
+	max_nodes:Option<u64>,
+	nodes:Arc<AtomicU64>,
+}
+impl SearchTracer {
+	pub fn new(file:&str,max_depth:Option<u32>,max_nodes:Option<u64>) -> Result<SearchTracer,ApplicationError> {
+		let writer = BufWriter::new(OpenOptions::new().append(true).create(true).open(file)?);
+
+		Ok(SearchTracer {
+			writer:Arc::new(Mutex::new(writer)),
+			max_depth:max_depth,
+			max_nodes:max_nodes,
+			nodes:Arc::new(AtomicU64::new(0)),
+		})
+	}
+
+	pub fn start(&self,teban:Teban,banmen:&Banmen,mc:&MochigomaCollections) -> Result<(),ApplicationError> {
+		let sfen = (teban,banmen.clone(),mc.clone(),Vec::new()).to_sfen()?;
+
+		let mut writer = self.writer.lock().map_err(|_| ApplicationError::LogicError(String::from(
+			"トレースファイルの排他ロックの獲得に失敗しました。"
+		)))?;
+
+		writer.write_all(format!("# {}\n",sfen).as_bytes())?;
+
+		Ok(())
+	}
+
+	pub fn write<S,E>(&self,current_depth:u32,depth:u32,alpha:S,beta:S,evaluation:E,
+				  reason:TraceReason,mate:Option<&'static str>,pv:&Vec<AppliedMove>) -> Result<(),ApplicationError>
+		where S: fmt::Display, E: fmt::Display {
+		if self.max_depth.map(|d| current_depth > d).unwrap_or(false) {
+			return Ok(());
+		}
+
+		if let Some(max_nodes) = self.max_nodes {
+			if self.nodes.fetch_add(1,Ordering::AcqRel) >= max_nodes {
+				return Ok(());
+			}
+		}
+
+		let pv = pv.iter().map(|m| m.to_move().to_sfen().unwrap_or(String::from("?"))).collect::<Vec<String>>().join(" ");
+
+		let line = format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+						   current_depth,depth,alpha,beta,evaluation,reason,mate.unwrap_or("-"),pv);
+
+		let mut writer = self.writer.lock().map_err(|_| ApplicationError::LogicError(String::from(
+			"トレースファイルの排他ロックの獲得に失敗しました。"
+		)))?;
+
+		writer.write_all(line.as_bytes())?;
+
+		Ok(())
+	}
+
+	pub fn flush(&self) -> Result<(),ApplicationError> {
+		let mut writer = self.writer.lock().map_err(|_| ApplicationError::LogicError(String::from(
+			"トレースファイルの排他ロックの獲得に失敗しました。"
+		)))?;
+
+		writer.flush()?;
+
+		Ok(())
+	}
+}
+struct TraceSection {
+	header:String,
+	nodes:u64,
+	depths:BTreeMap<u32,u64>,
+	reasons:BTreeMap<String,u64>,
+	mates:BTreeMap<String,u64>,
+	root_moves:Vec<(String,String,String)>,
+}
+impl TraceSection {
+	fn new(header:&str) -> TraceSection {
+		TraceSection {
+			header:header.to_string(),
+			nodes:0,
+			depths:BTreeMap::new(),
+			reasons:BTreeMap::new(),
+			mates:BTreeMap::new(),
+			root_moves:Vec::new(),
+		}
+	}
+
+	fn add(&mut self,line:&str) -> Result<(),ApplicationError> {
+		let f = line.split('\t').collect::<Vec<&str>>();
+
+		if f.len() < 8 {
+			return Err(ApplicationError::LogicError(format!(
+				"トレースファイルの形式が不正です。 ({})",line
+			)));
+		}
+
+		let current_depth = f[0].parse::<u32>()?;
+
+		self.nodes += 1;
+		*self.depths.entry(current_depth).or_insert(0) += 1;
+		*self.reasons.entry(f[5].to_string()).or_insert(0) += 1;
+
+		if f[6] != "-" {
+			*self.mates.entry(f[6].to_string()).or_insert(0) += 1;
+		}
+
+		if current_depth == 2 {
+			let m = f[7].to_string();
+			let score = f[4].split(' ').nth(1).map(|s| TraceSection::negate(s)).unwrap_or(String::from("-"));
+
+			// Null window searches are followed by a re-search, the later one is the result.
+			match self.root_moves.iter_mut().find(|r| r.0 == m) {
+				Some(r) => {
+					r.1 = score;
+					r.2 = f[5].to_string();
+				},
+				None => {
+					self.root_moves.push((m,score,f[5].to_string()));
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	fn negate(s:&str) -> String {
+		match s {
+			"inf" => String::from("-inf"),
+			"-inf" => String::from("inf"),
+			"-" => String::from("-"),
+			s => s.parse::<i64>().map(|s| (-s).to_string()).unwrap_or(s.to_string())
+		}
+	}
+
+	fn print(&self) {
+		print!("{}\n",self.header);
+		print!("ノード数: {}\n",self.nodes);
+
+		for (d,c) in &self.depths {
+			print!("  深さ {}: {}\n",d,c);
+		}
+
+		print!("終了理由:\n");
+
+		for (r,c) in &self.reasons {
+			print!("  {}: {}\n",r,c);
+		}
+
+		if self.mates.len() > 0 {
+			print!("詰み探索:\n");
+
+			for (r,c) in &self.mates {
+				print!("  {}: {}\n",r,c);
+			}
+		}
+
+		if self.root_moves.len() > 0 {
+			print!("ルートの指し手:\n");
+
+			for (m,s,r) in &self.root_moves {
+				print!("  {}: {} ({})\n",m,s,r);
+			}
+		}
+	}
+}
+pub struct TraceSummarizer;
+impl TraceSummarizer {
+	pub fn run(file:&str) -> Result<(),ApplicationError> {
+		if !Path::new(file).exists() {
+			return Err(ApplicationError::StartupError(String::from(
+				"トレースファイルが見つかりません。"
+			)));
+		}
+
+		let reader = BufReader::new(OpenOptions::new().read(true).create(false).open(file)?);
+
+		let mut section:Option<TraceSection> = None;
+
+		for line in reader.lines() {
+			let line = line?;
+
+			if line.starts_with("#") {
+				if let Some(ref section) = section {
+					section.print();
+					print!("\n");
+				}
+
+				section = Some(TraceSection::new(&line));
+			} else if !line.is_empty() {
+				section.get_or_insert_with(|| TraceSection::new("#")).add(&line)?;
+			}
+		}
+
+		if let Some(ref section) = section {
+			section.print();
+		}
+
+		Ok(())
+	}
+}
@@ -0,0 +1,222 @@
+use usiagent::event::GameEndState;
+use usiagent::shogi::{Banmen, KomaKind};
+
+use nn::{centipawn, DRAW_VALUE};
+
+pub const VALIDATION_SIZE:usize = 10000;
+pub const VALIDATION_INTERVAL:usize = 0;
+const CALIBRATION_BUCKETS:usize = 10;
+
+/// Progress of the game estimated by the pieces (except the pawns and the kings) still in the camp of their owner.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Phase {
+	Opening,
+	Middlegame,
+	Endgame,
+}
+impl Phase {
+	const OPENING_PIECES:usize = 16;
+	const ENDGAME_PIECES:usize = 9;
+
+	pub fn of(b:&Banmen) -> Phase {
+		let &Banmen(ref kinds) = b;
+
+		let mut count = 0;
+
+		for y in 0..9 {
+			for x in 0..9 {
+				let k = kinds[y][x];
+
+				if y >= 6 && k > KomaKind::SFu && k < KomaKind::SOu {
+					count += 1;
+				} else if y <= 2 && k > KomaKind::GFu && k < KomaKind::GOu {
+					count += 1;
+				}
+			}
+		}
+
+		if count >= Phase::OPENING_PIECES {
+			Phase::Opening
+		} else if count > Phase::ENDGAME_PIECES {
+			Phase::Middlegame
+		} else {
+			Phase::Endgame
+		}
+	}
+
+	fn index(&self) -> usize {
+		match self {
+			&Phase::Opening => 0,
+			&Phase::Middlegame => 1,
+			&Phase::Endgame => 2,
+		}
+	}
+
+	fn name(index:usize) -> &'static str {
+		match index {
+			0 => "序盤",
+			1 => "中盤",
+			_ => "終盤",
+		}
+	}
+}
+/// Evaluation of a position of the held-out set.
+pub struct ValidationSample {
+	/// Game result from the side to move.
+	pub result:GameEndState,
+	/// Output of the ensemble.
+	pub output:f32,
+	/// Target of the training for the position.
+	pub target:f32,
+	pub phase:Phase,
+}
+/// Metrics over the held-out set.
+///
+/// The loss is the squared error of the output to the target, the same as the training.
+/// The prediction is correct when the sign of the evaluation agrees with the game result (within DRAW_VALUE for a draw),
+/// and the calibration compares the estimated winning probability with the actual score for each range of the probability.
+pub struct Validation {
+	eval_scale:f32,
+	count:usize,
+	loss:f64,
+	successed:usize,
+	phases:[(usize,usize); 3],
+	buckets:[(usize,f64,f64); CALIBRATION_BUCKETS],
+}
+impl Validation {
+	pub fn new(eval_scale:f32) -> Validation {
+		Validation {
+			eval_scale:eval_scale,
+			count:0,
+			loss:0.,
+			successed:0,
+			phases:[(0,0); 3],
+			buckets:[(0,0.,0.); CALIBRATION_BUCKETS],
+		}
+	}
+
+	pub fn add(&mut self,sample:ValidationSample) {
+		let cp = centipawn(sample.output,self.eval_scale);
+
+		let (success,score) = match sample.result {
+			GameEndState::Win => (cp >= 0, 1.),
+			GameEndState::Lose => (cp < 0, 0.),
+			_ => (cp.abs() <= DRAW_VALUE, 0.5),
+		};
+
+		self.count += 1;
+		self.loss += ((sample.output - sample.target) as f64).powi(2);
+
+		let phase = &mut self.phases[sample.phase.index()];
+
+		phase.0 += 1;
+
+		if success {
+			self.successed += 1;
+			phase.1 += 1;
+		}
+
+		let p = ((sample.output.max(-1.).min(1.) + 1.) / 2.) as f64;
+		let bucket = &mut self.buckets[((p * CALIBRATION_BUCKETS as f64) as usize).min(CALIBRATION_BUCKETS - 1)];
+
+		bucket.0 += 1;
+		bucket.1 += p;
+		bucket.2 += score;
+	}
+
+	pub fn loss(&self) -> f64 {
+		if self.count == 0 {
+			0.
+		} else {
+			self.loss / self.count as f64
+		}
+	}
+
+	pub fn accuracy(&self) -> f64 {
+		if self.count == 0 {
+			0.
+		} else {
+			self.successed as f64 / self.count as f64
+		}
+	}
+
+	pub fn print(&self) {
+		if self.count == 0 {
+			print!("検証用の局面がありません。\n");
+			return;
+		}
+
+		print!("検証局面数: {}\n",self.count);
+		print!("平均損失: {}\n",self.loss());
+		print!("正解率 {:.2}%\n",self.accuracy() * 100.);
+
+		for (i,&(count,successed)) in self.phases.iter().enumerate() {
+			if count > 0 {
+				print!("{}: 正解率 {:.2}% ({}局面)\n",Phase::name(i),successed as f64 / count as f64 * 100.,count);
+			}
+		}
+
+		for (i,&(count,p,score)) in self.buckets.iter().enumerate() {
+			if count > 0 {
+				print!("予測勝率 {:>3}-{:>3}%: 平均予測 {:.1}% 実際 {:.1}% ({}局面)\n",
+					   i * 100 / CALIBRATION_BUCKETS,(i + 1) * 100 / CALIBRATION_BUCKETS,
+					   p / count as f64 * 100.,score / count as f64 * 100.,count);
+			}
+		}
+	}
+}
+#[cfg(test)]
+mod tests {
+	use usiagent::rule::BANMEN_START_POS;
+	use usiagent::shogi::*;
+
+	use super::*;
+
+	#[test]
+	fn phase_of_the_start_position_is_opening() {
+		assert_eq!(Phase::of(&BANMEN_START_POS),Phase::Opening);
+	}
+
+	#[test]
+	fn phase_counts_the_pieces_left_in_the_camps() {
+		let Banmen(mut kinds) = BANMEN_START_POS.clone();
+
+		// Removes the lances, knights and silvers of sente, 14 pieces remain in the camps.
+		for &x in [0,1,2,6,7,8].iter() {
+			kinds[8][x] = KomaKind::Blank;
+		}
+
+		assert_eq!(Phase::of(&Banmen(kinds)),Phase::Middlegame);
+
+		// Removes the golds, the bishop and the rook of sente, 10 pieces remain.
+		for &x in [3,5].iter() {
+			kinds[8][x] = KomaKind::Blank;
+		}
+
+		kinds[7][1] = KomaKind::Blank;
+		kinds[7][7] = KomaKind::Blank;
+
+		assert_eq!(Phase::of(&Banmen(kinds)),Phase::Middlegame);
+
+		kinds[0][0] = KomaKind::Blank;
+
+		assert_eq!(Phase::of(&Banmen(kinds)),Phase::Endgame);
+	}
+
+	#[test]
+	fn phase_ignores_the_pieces_in_the_camp_of_the_opponent() {
+		let mut kinds = [[KomaKind::Blank; 9]; 9];
+
+		kinds[0][4] = KomaKind::GOu;
+		kinds[8][4] = KomaKind::SOu;
+
+		for x in 0..9 {
+			kinds[1][x] = KomaKind::SKin;
+			kinds[2][x] = KomaKind::SGin;
+			kinds[6][x] = KomaKind::GKin;
+			kinds[7][x] = KomaKind::GGin;
+		}
+
+		assert_eq!(Phase::of(&Banmen(kinds)),Phase::Endgame);
+	}
+}